@@ -0,0 +1,74 @@
+/// A line typed by the user
+#[derive(Debug, PartialEq, Eq)]
+pub enum Input {
+    /// A chat message for the current room
+    Message(String),
+    Help,
+    Join(String),
+    Leave(String),
+    Room(String),
+    Rooms,
+    Online,
+    DirectMessage {
+        recipient: String,
+        message: String,
+    },
+    Quit,
+    /// A command was used with missing arguments, contains the expected usage
+    Usage(&'static str),
+    Unknown(String),
+}
+
+impl Input {
+    /// Parse an input line. Lines starting with `/` are commands,
+    /// `//` sends a message starting with a single `/`
+    pub fn parse(line: &str) -> Option<Self> {
+        let line = line.trim_end();
+
+        if line.trim().is_empty() {
+            return None;
+        }
+
+        if let Some(message) = line.strip_prefix("//") {
+            return Some(Self::Message(format!("/{message}")));
+        }
+
+        let Some(command) = line.strip_prefix('/') else {
+            return Some(Self::Message(line.to_string()));
+        };
+
+        let (name, args) = command
+            .split_once(char::is_whitespace)
+            .map_or((command, ""), |(name, args)| (name, args.trim()));
+
+        let input = match name {
+            "help" | "?" => Self::Help,
+            "join" => room_argument(args, Self::Join, "/join <room>"),
+            "leave" => room_argument(args, Self::Leave, "/leave <room>"),
+            "room" => room_argument(args, Self::Room, "/room <room>"),
+            "rooms" => Self::Rooms,
+            "online" => Self::Online,
+            "msg" | "dm" => match args.split_once(char::is_whitespace) {
+                Some((recipient, message)) if !message.trim().is_empty() => Self::DirectMessage {
+                    recipient: recipient.to_string(),
+                    message: message.trim().to_string(),
+                },
+                _ => Self::Usage("/msg <user> <message>"),
+            },
+            "quit" | "exit" => Self::Quit,
+            _ => Self::Unknown(name.to_string()),
+        };
+
+        Some(input)
+    }
+}
+
+fn room_argument(args: &str, input: fn(String) -> Input, usage: &'static str) -> Input {
+    let room = args.trim_start_matches('#');
+
+    if room.is_empty() || room.contains(char::is_whitespace) {
+        Input::Usage(usage)
+    } else {
+        input(room.to_string())
+    }
+}
@@ -0,0 +1,339 @@
+#![warn(clippy::all, clippy::nursery, clippy::pedantic)]
+
+//! Terminal client for Strawberry Chat
+
+mod commands;
+mod render;
+mod terminal;
+
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::process::ExitCode;
+
+use libstrawberry::id::credentials::StrawberryIdCredentials;
+use libstrawberry::localization::Localization;
+use libstrawberry::stbchat::client::StbchatClient;
+use libstrawberry::stbchat::error::CommunicationError;
+use libstrawberry::stbchat::object::DEFAULT_ROOM;
+use libstrawberry::stbchat::packet::ClientPacket;
+use tokio::io::{AsyncBufReadExt, BufReader, Lines, Stdin};
+
+use crate::commands::Input;
+use crate::terminal::HiddenInput;
+
+const LANGUAGE_FILE: &str = include_str!("lang.yml");
+const LANGUAGES: &[&str] = &["en", "de"];
+const DEFAULT_ADDRESS: &str = "127.0.0.1:52800";
+
+type InputLines = Lines<BufReader<Stdin>>;
+
+/// Command line options
+struct Options {
+    address: String,
+    username: Option<String>,
+    register: bool,
+    strawberry_id: bool,
+    tls: bool,
+    language: String,
+}
+
+impl Options {
+    /// Parse the command line arguments. Returns `Ok(None)` if the help was requested,
+    /// or the localization key of the error together with the faulty argument
+    fn parse(
+        mut args: impl Iterator<Item = String>,
+    ) -> Result<Option<Self>, (&'static str, String)> {
+        let mut options = Self {
+            address: DEFAULT_ADDRESS.to_string(),
+            username: None,
+            register: false,
+            strawberry_id: false,
+            tls: false,
+            language: system_language(),
+        };
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(None),
+                "-u" | "--username" => {
+                    options.username = Some(args.next().ok_or(("missing_value", arg))?);
+                }
+                "-r" | "--register" => options.register = true,
+                "-i" | "--strawberry-id" => options.strawberry_id = true,
+                "-t" | "--tls" => options.tls = true,
+                "-l" | "--lang" => {
+                    let language = args.next().ok_or(("missing_value", arg))?;
+
+                    if !LANGUAGES.contains(&language.as_str()) {
+                        return Err(("invalid_argument", language));
+                    }
+
+                    options.language = language;
+                }
+                _ if arg.starts_with('-') => return Err(("invalid_argument", arg)),
+                _ => options.address = arg,
+            }
+        }
+
+        Ok(Some(options))
+    }
+}
+
+/// Language of the system if the client is translated into it, English otherwise
+fn system_language() -> String {
+    std::env::var("LANG")
+        .ok()
+        .and_then(|lang| {
+            LANGUAGES
+                .iter()
+                .find(|language| lang.starts_with(*language))
+                .map(ToString::to_string)
+        })
+        .unwrap_or_else(|| LANGUAGES[0].to_string())
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!(
+                "{}",
+                Localization::new(&system_language(), LANGUAGE_FILE, true).get("usage")
+            );
+            return ExitCode::SUCCESS;
+        }
+        Err((key, arg)) => {
+            let lang = Localization::new(&system_language(), LANGUAGE_FILE, true);
+            eprintln!("{}", lang.get_with_params(key, &[&arg]));
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let lang = Localization::new(&options.language, LANGUAGE_FILE, true);
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+
+    println!(
+        "{}",
+        lang.get_with_params("connecting", &[&options.address])
+    );
+
+    let mut client = match connect(&options, &lang).await {
+        Ok(client) => client,
+        Err(err) => {
+            eprintln!(
+                "{}",
+                lang.get_with_params("connect_failed", &[&options.address, &err])
+            );
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if !login(&client, &options, &lang, &mut lines).await {
+        return ExitCode::FAILURE;
+    }
+
+    let code = run(&mut client, &lang, &mut lines).await;
+    let _ = client.close().await;
+
+    code
+}
+
+#[cfg_attr(feature = "stbchat-tls", allow(unused_variables))]
+async fn connect(options: &Options, lang: &Localization) -> eyre::Result<StbchatClient> {
+    if !options.tls {
+        return StbchatClient::connect(options.address.as_str()).await;
+    }
+
+    #[cfg(feature = "stbchat-tls")]
+    {
+        use libstrawberry::stbchat::tls::TlsClientConfig;
+
+        let server_name = options
+            .address
+            .rsplit_once(':')
+            .map_or(options.address.as_str(), |(host, _)| host);
+        let connector = TlsClientConfig::new().build()?;
+
+        StbchatClient::connect_tls(options.address.as_str(), server_name, &connector).await
+    }
+
+    #[cfg(not(feature = "stbchat-tls"))]
+    Err(eyre::eyre!(lang.get("tls_unsupported")))
+}
+
+/// Send the credentials of the user. Returns `false` if the login could not be started
+async fn login(
+    client: &StbchatClient,
+    options: &Options,
+    lang: &Localization,
+    lines: &mut InputLines,
+) -> bool {
+    if options.strawberry_id {
+        let credentials = match StrawberryIdCredentials::fetch() {
+            Ok(credentials) => credentials,
+            Err(err) => {
+                eprintln!("{}", lang.get_with_params("no_credentials", &[&err]));
+                return false;
+            }
+        };
+
+        return client.login_strawberry_id(&credentials).await.is_ok();
+    }
+
+    let username = match &options.username {
+        Some(username) => username.clone(),
+        None => match prompt(lines, &lang.get("username_prompt")).await {
+            Some(username) => username,
+            None => return false,
+        },
+    };
+
+    let hidden = HiddenInput::new();
+    let password = prompt(lines, &lang.get("password_prompt")).await;
+    drop(hidden);
+
+    let Some(password) = password else {
+        return false;
+    };
+
+    let result = if options.register {
+        let Some(role_color) = prompt(lines, &lang.get("role_color_prompt")).await else {
+            return false;
+        };
+
+        client.register(username, password, role_color).await
+    } else {
+        client.login(username, password).await
+    };
+
+    result.is_ok()
+}
+
+/// Ask the user for a line of input. Returns `None` if stdin was closed
+async fn prompt(lines: &mut InputLines, prompt: &str) -> Option<String> {
+    print!("{prompt}");
+    let _ = std::io::stdout().flush();
+
+    lines
+        .next_line()
+        .await
+        .ok()
+        .flatten()
+        .map(|line| line.trim().to_string())
+}
+
+/// Show received packets and handle input lines until the user quits or the connection is closed
+async fn run(client: &mut StbchatClient, lang: &Localization, lines: &mut InputLines) -> ExitCode {
+    let mut room = DEFAULT_ROOM.to_string();
+    let mut joined = BTreeSet::from([DEFAULT_ROOM.to_string()]);
+
+    loop {
+        tokio::select! {
+            packet = client.recv() => {
+                let Some(packet) = packet else {
+                    println!("{}", lang.get("connection_closed"));
+                    return ExitCode::FAILURE;
+                };
+
+                if let Some(line) = render::packet(lang, &packet) {
+                    println!("{line}");
+                }
+
+                if matches!(packet, ClientPacket::AuthFailed { .. }) {
+                    return ExitCode::FAILURE;
+                }
+            }
+            line = lines.next_line() => {
+                let Ok(Some(line)) = line else {
+                    return ExitCode::SUCCESS;
+                };
+
+                let Some(input) = Input::parse(&line) else {
+                    continue;
+                };
+
+                if input == Input::Quit {
+                    return ExitCode::SUCCESS;
+                }
+
+                if handle_input(client, lang, input, &mut room, &mut joined).await.is_err() {
+                    println!("{}", lang.get("connection_closed"));
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+    }
+}
+
+/// Run a command or send a message to the current room
+async fn handle_input(
+    client: &StbchatClient,
+    lang: &Localization,
+    input: Input,
+    room: &mut String,
+    joined: &mut BTreeSet<String>,
+) -> Result<(), CommunicationError> {
+    match input {
+        Input::Message(message) => client.send_room_message(room.as_str(), message).await?,
+        Input::Help => println!("{}", lang.get("help")),
+        Input::Join(name) => {
+            client.join_room(&name).await?;
+            println!(
+                "{}",
+                render::info(lang.get_with_params("current_room", &[&name]))
+            );
+            joined.insert(name.clone());
+            *room = name;
+        }
+        Input::Leave(name) => {
+            client.leave_room(&name).await?;
+            joined.remove(&name);
+
+            if *room == name {
+                *room = joined
+                    .first()
+                    .cloned()
+                    .unwrap_or_else(|| DEFAULT_ROOM.to_string());
+                println!(
+                    "{}",
+                    render::info(lang.get_with_params("current_room", &[room]))
+                );
+            }
+        }
+        Input::Room(name) => {
+            if joined.contains(&name) {
+                println!(
+                    "{}",
+                    render::info(lang.get_with_params("current_room", &[&name]))
+                );
+                *room = name;
+            } else {
+                println!(
+                    "{}",
+                    render::info(lang.get_with_params("not_joined", &[&name, &name]))
+                );
+            }
+        }
+        Input::Rooms => client.list_rooms().await?,
+        Input::Online => client.list_presence().await?,
+        Input::DirectMessage { recipient, message } => {
+            client.send_direct_message(recipient, message).await?;
+        }
+        Input::Usage(usage) => {
+            println!(
+                "{}",
+                render::info(lang.get_with_params("command_usage", &[&usage]))
+            );
+        }
+        Input::Unknown(command) => {
+            println!(
+                "{}",
+                render::info(lang.get_with_params("unknown_command", &[&command]))
+            );
+        }
+        Input::Quit => {}
+    }
+
+    Ok(())
+}
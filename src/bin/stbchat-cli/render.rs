@@ -0,0 +1,111 @@
+use libstrawberry::colors::{BOLD, C_RESET, GRAY, MAGENTA, YELLOW};
+use libstrawberry::localization::Localization;
+use libstrawberry::stbchat::object::{DeliveryStatus, StbchatApiResponse, User};
+use libstrawberry::stbchat::packet::ClientPacket;
+use libstrawberry::time::current_time;
+
+/// Render a received packet as a line of the chat.
+/// Returns `None` for packets that are not shown
+pub fn packet(lang: &Localization, packet: &ClientPacket) -> Option<String> {
+    let line = match packet {
+        ClientPacket::SystemMessage { message } => format!(
+            "{} {YELLOW}{BOLD}{}{C_RESET}: {}",
+            timestamp(),
+            lang.get("system"),
+            sanitize(message)
+        ),
+        ClientPacket::UserMessage {
+            author,
+            message,
+            room,
+            ..
+        } => format!(
+            "{} {GRAY}#{}{C_RESET} {}: {}",
+            timestamp(),
+            sanitize(room),
+            author.display_name(),
+            sanitize(message)
+        ),
+        ClientPacket::Attachment {
+            author,
+            room,
+            attachment,
+        } => format!(
+            "{} {GRAY}#{}{C_RESET} {}: [{}, {} bytes]",
+            timestamp(),
+            sanitize(room),
+            author.display_name(),
+            sanitize(&attachment.name),
+            attachment.size
+        ),
+        ClientPacket::DirectMessage { author, message } => format!(
+            "{} {} {GRAY}-> {}{C_RESET}: {}",
+            timestamp(),
+            author.display_name(),
+            lang.get("you"),
+            sanitize(message)
+        ),
+        ClientPacket::DirectMessageStatus {
+            recipient,
+            status: DeliveryStatus::UserOffline,
+        } => info(lang.get_with_params("user_offline", &[&sanitize(recipient)])),
+        ClientPacket::Notification {
+            title,
+            content,
+            bell,
+            ..
+        } => format!(
+            "{}{} {MAGENTA}{BOLD}{}{C_RESET}: {}",
+            if *bell { "\x07" } else { "" },
+            timestamp(),
+            sanitize(title),
+            sanitize(content)
+        ),
+        ClientPacket::Backend { user_meta } => {
+            lang.get_with_params("logged_in", &[&sanitize(&user_meta.username)])
+        }
+        ClientPacket::AuthFailed { message, .. } => {
+            lang.get_with_params("auth_failed", &[&sanitize(message)])
+        }
+        ClientPacket::ApiResponse {
+            response: StbchatApiResponse::UserJoined { username, room, .. },
+            ..
+        } => info(lang.get_with_params("user_joined", &[&sanitize(username), &sanitize(room)])),
+        ClientPacket::ApiResponse {
+            response: StbchatApiResponse::UserLeft { username, room },
+            ..
+        } => info(lang.get_with_params("user_left", &[&sanitize(username), &sanitize(room)])),
+        ClientPacket::RoomList { rooms } => {
+            let rooms: Vec<String> = rooms
+                .iter()
+                .map(|room| format!("#{} ({})", sanitize(&room.name), room.members.len()))
+                .collect();
+
+            info(lang.get_with_params("rooms", &[&rooms.join(", ")]))
+        }
+        ClientPacket::PresenceList { users } => {
+            let users: Vec<String> = users.iter().map(User::display_name).collect();
+
+            info(lang.get_with_params("online", &[&users.join(", ")]))
+        }
+        _ => return None,
+    };
+
+    Some(line)
+}
+
+/// A line of the client itself, e.g. the answer to a command
+pub fn info(message: impl AsRef<str>) -> String {
+    format!("{} {GRAY}{}{C_RESET}", timestamp(), message.as_ref())
+}
+
+fn timestamp() -> String {
+    format!("{GRAY}[{}]{C_RESET}", current_time("%H:%M"))
+}
+
+/// Remove control characters, so other users can't send escape sequences to the terminal
+fn sanitize(text: &str) -> String {
+    text.chars()
+        .filter(|c| !c.is_control() || matches!(c, '\n' | '\t'))
+        .collect()
+}
@@ -0,0 +1,61 @@
+//! Terminal settings for reading passwords
+
+#[cfg(unix)]
+use std::io::IsTerminal;
+
+/// Stops the terminal from echoing typed characters until dropped, only the final newline is shown.
+/// Does nothing if stdin is not a terminal, e.g. when the password is piped in,
+/// or if the platform is not supported
+pub struct HiddenInput {
+    #[cfg(unix)]
+    original: Option<libc::termios>,
+}
+
+impl HiddenInput {
+    #[cfg(unix)]
+    pub fn new() -> Self {
+        if !std::io::stdin().is_terminal() {
+            return Self { original: None };
+        }
+
+        let mut original = std::mem::MaybeUninit::<libc::termios>::uninit();
+
+        // SAFETY: `tcgetattr` initializes the struct if it succeeds
+        let original = unsafe {
+            if libc::tcgetattr(libc::STDIN_FILENO, original.as_mut_ptr()) != 0 {
+                return Self { original: None };
+            }
+
+            original.assume_init()
+        };
+
+        let mut hidden = original;
+        hidden.c_lflag = (hidden.c_lflag & !libc::ECHO) | libc::ECHONL;
+
+        // SAFETY: `hidden` is a valid copy of the current settings
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw const hidden) } != 0 {
+            return Self { original: None };
+        }
+
+        Self {
+            original: Some(original),
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub const fn new() -> Self {
+        Self {}
+    }
+}
+
+impl Drop for HiddenInput {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Some(original) = &self.original {
+            // SAFETY: `original` holds the settings read by `tcgetattr`
+            unsafe {
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, original);
+            }
+        }
+    }
+}
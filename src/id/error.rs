@@ -21,6 +21,8 @@ pub enum CredentialsError {
 pub enum VerifierError {
     #[error("invalid user credentials")]
     InvalidCredentials,
+    #[error("invalid response from Strawberry ID")]
+    InvalidResponse,
 }
 
 #[derive(Error, Debug)]
@@ -1,3 +1,4 @@
+use reqwest::Url;
 use serde_json::Value;
 
 use crate::STRAWBERRY_ID_API;
@@ -11,40 +12,51 @@ pub struct StrawberryIdVerifier {
 }
 
 impl StrawberryIdVerifier {
-    /// # Panics
-    /// Will panic if results are not valid
-    ///
     /// # Errors
-    /// Will return `Err` if the user credentials are not valid
+    /// - Will return `Err` if the user credentials are not valid
+    /// - Will return `Err` if the response of the API is incomplete
     pub fn verify(credentials: &StrawberryIdCredentials) -> eyre::Result<Self, eyre::Error> {
-        let api_response = reqwest::blocking::get(format!(
-            "{STRAWBERRY_ID_API}api/auth?username={}&token={}",
-            credentials.username, credentials.token
-        ))?
-        .text()?;
+        Self::verify_with_endpoint(credentials, STRAWBERRY_ID_API)
+    }
+
+    /// Verify the credentials against a custom Strawberry ID API endpoint, e.g. a self-hosted instance.
+    /// The endpoint has to end with a slash, like `STRAWBERRY_ID_API`
+    /// # Errors
+    /// - Will return `Err` if the user credentials are not valid
+    /// - Will return `Err` if the response of the API is incomplete
+    pub fn verify_with_endpoint(
+        credentials: &StrawberryIdCredentials,
+        endpoint: &str,
+    ) -> eyre::Result<Self, eyre::Error> {
+        // Credentials may come from untrusted peers, so they are encoded instead of formatted into the URL
+        let url = Url::parse_with_params(
+            &format!("{endpoint}api/auth"),
+            &[
+                ("username", credentials.username.as_str()),
+                ("token", credentials.token.as_str()),
+            ],
+        )?;
+        let api_response = reqwest::blocking::get(url)?.text()?;
 
         let mut verifier = Self::default();
 
-        if let Ok(data) = serde_json::from_str::<Value>(&api_response) {
-            if data["data"]["status"] == "Ok" {
-                verifier.strawberry_id.full_name = data["data"]["user"]["full_name"]
+        if let Ok(data) = serde_json::from_str::<Value>(&api_response)
+            && data["data"]["status"] == "Ok"
+        {
+            let user = &data["data"]["user"];
+            let field = |name: &str| {
+                user[name]
                     .as_str()
-                    .unwrap()
-                    .to_string();
-                verifier.strawberry_id.email =
-                    data["data"]["user"]["email"].as_str().unwrap().to_string();
-                verifier.strawberry_id.profile_picture =
-                    data["data"]["user"]["profile_picture_url"]
-                        .as_str()
-                        .unwrap()
-                        .to_string();
-                verifier.strawberry_id.username = data["data"]["user"]["username"]
-                    .as_str()
-                    .unwrap()
-                    .to_string();
+                    .map(ToString::to_string)
+                    .ok_or(VerifierError::InvalidResponse)
+            };
+
+            verifier.strawberry_id.full_name = field("full_name")?;
+            verifier.strawberry_id.email = field("email")?;
+            verifier.strawberry_id.profile_picture = field("profile_picture_url")?;
+            verifier.strawberry_id.username = field("username")?;
 
-                return Ok(verifier);
-            }
+            return Ok(verifier);
         }
 
         Err(VerifierError::InvalidCredentials.into())
@@ -1,8 +1 @@
-#[must_use]
-pub fn badge_handler(badge: &str) -> Option<String> {
-    if badge.is_empty() {
-        None
-    } else {
-        Some(format!(" [{badge}]"))
-    }
-}
+pub use crate::string::badge_handler;
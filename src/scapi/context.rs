@@ -1,7 +1,8 @@
-use crate::stbchat::net::OutgoingPacketStream;
+use crate::stbchat::error::ExtensionError;
+use crate::stbchat::extension::{self, Extension};
+use crate::stbchat::object::ExtensionTarget;
 use crate::stbchat::packet::ServerPacket;
-use tokio::io::WriteHalf;
-use tokio::net::TcpStream;
+use crate::stbchat::queue::PacketQueue;
 
 pub struct Context {
     /// The user who executed the command
@@ -14,19 +15,69 @@ pub struct Context {
     pub channel: Channel,
 }
 
+impl Context {
+    /// Reply privately to the executor of the command
+    /// # Panics
+    /// - Will panic if the message could not be sent
+    pub async fn dm(&mut self, message: impl ToString) {
+        self.channel
+            .w_server
+            .send(ServerPacket::DirectMessage {
+                recipient: self.executor.clone(),
+                message: message.to_string(),
+            })
+            .await
+            .expect("Err");
+    }
+}
+
 pub struct Channel {
-    pub w_server: OutgoingPacketStream<WriteHalf<TcpStream>>,
+    /// Connection to the server, independent of the transport
+    pub w_server: PacketQueue,
+
+    /// Room the command was executed in
+    pub room: String,
 }
 
 impl Channel {
+    /// Create a channel for a room, e.g. using `StbchatClient::writer`
+    pub fn new(w_server: PacketQueue, room: impl Into<String>) -> Self {
+        Self {
+            w_server,
+            room: room.into(),
+        }
+    }
+
     /// # Panics
     ///
     pub async fn send(&mut self, message: impl ToString) {
         self.w_server
-            .write(ServerPacket::Message {
+            .send(ServerPacket::Message {
                 message: message.to_string(),
+                room: self.room.clone(),
+                reply_to: None,
             })
             .await
             .expect("Err");
     }
+
+    /// Send an extension packet to all members of the room
+    /// # Errors
+    /// - Will return `Err` if the type of the extension is invalid
+    /// - Will return `Err` if the payload could not be encoded
+    /// - Will return `Err` if the packet could not be sent
+    pub async fn send_extension<E: Extension + Sync>(
+        &mut self,
+        extension: &E,
+    ) -> Result<(), ExtensionError> {
+        let target = ExtensionTarget::Room {
+            room: self.room.clone(),
+        };
+
+        self.w_server
+            .send(extension::packet(target, extension)?)
+            .await?;
+
+        Ok(())
+    }
 }
@@ -1,13 +1,10 @@
 #![cfg(feature = "stbchat")]
 #![allow(clippy::future_not_send)]
-use tokio::io::{ReadHalf, WriteHalf, split};
 /// TODO: Use built-in logging from libstrawberry
-use tokio::net::TcpStream;
-
 use num_traits::ToPrimitive;
 use std::fmt::{Display, Formatter};
+use std::future::Future;
 use std::string::ToString;
-use std::time::Duration;
 
 pub mod addons;
 pub mod command;
@@ -16,12 +13,17 @@ pub mod flags;
 pub mod permissions;
 
 use crate::colors::{BLUE, BOLD, C_RESET, CYAN, GREEN, RED, YELLOW};
+#[cfg(feature = "strawberryid")]
+use crate::id::credentials::StrawberryIdCredentials;
 use crate::scapi::command::Command;
 use crate::scapi::context::{Channel, Context};
 use crate::scapi::flags::BotFlags;
 use crate::scapi::permissions::PermissionList;
-use crate::stbchat::net::{IncomingPacketStream, OutgoingPacketStream};
-use crate::stbchat::packet::{ClientPacket, ServerPacket};
+use crate::stbchat::client::StbchatClient;
+use crate::stbchat::extension::{Extension, ExtensionMessage, Extensions};
+use crate::stbchat::packet::ClientPacket;
+use crate::stbchat::queue::PacketQueue;
+use crate::string::strip_control_characters;
 use crate::time::current_time;
 
 const VERSION: &str = "1.0.0";
@@ -37,8 +39,13 @@ pub struct Bot {
     pub port: u16,
     pub prefix: String,
 
-    pub r_server: Option<IncomingPacketStream<ReadHalf<TcpStream>>>,
-    pub w_server: Option<OutgoingPacketStream<WriteHalf<TcpStream>>>,
+    /// Log in with the token as a Strawberry ID token instead of a password
+    pub token_login: bool,
+
+    pub client: Option<StbchatClient>,
+
+    /// Handlers of extension packets sent by other users or bots
+    pub extensions: Extensions,
 }
 
 impl Bot {
@@ -55,30 +62,162 @@ impl Bot {
             address: address.to_string(),
             port: port.to_u16().unwrap(),
             prefix: prefix.to_string(),
-            r_server: None,
-            w_server: None,
+            token_login: false,
+            client: None,
+            extensions: Extensions::new(),
         };
 
         bot
     }
 
-    pub async fn run(mut self) {
-        let host = format!("{}:{}", self.address, self.port);
-        let stream = TcpStream::connect(host).await.unwrap();
-        let sock_ref = socket2::SockRef::from(&stream);
+    /// Create a bot logging in with the username and token of `StrawberryIdCredentials`
+    /// ```no_run
+    /// use libstrawberry::id::credentials::StrawberryIdCredentials;
+    /// use libstrawberry::scapi::Bot;
+    ///
+    /// # async fn example() -> eyre::Result<()> {
+    /// let credentials = StrawberryIdCredentials::fetch()?;
+    ///
+    /// Bot::from_strawberry_id(credentials, "127.0.0.1", 52800, "!")
+    ///     .run()
+    ///     .await
+    /// # }
+    /// ```
+    #[cfg(feature = "strawberryid")]
+    #[must_use]
+    pub fn from_strawberry_id(
+        credentials: StrawberryIdCredentials,
+        address: impl Into<String>,
+        port: u16,
+        prefix: impl Into<String>,
+    ) -> Self {
+        Self {
+            username: credentials.username,
+            token: credentials.token,
+            address: address.into(),
+            port,
+            prefix: prefix.into(),
+            token_login: true,
+            client: None,
+            extensions: Extensions::new(),
+        }
+    }
 
-        let mut ka = socket2::TcpKeepalive::new();
-        ka = ka.with_time(Duration::from_secs(20));
-        ka = ka.with_interval(Duration::from_secs(20));
+    /// Handle extension packets of type `E`, e.g. votes of a poll.
+    /// The handler gets the connection of the bot to answer
+    /// ```no_run
+    /// use serde::{Deserialize, Serialize};
+    ///
+    /// use libstrawberry::scapi::Bot;
+    /// use libstrawberry::scapi::context::Channel;
+    /// use libstrawberry::stbchat::extension::{Extension, ExtensionMessage};
+    /// use libstrawberry::stbchat::object::ExtensionTarget;
+    ///
+    /// #[derive(Serialize, Deserialize)]
+    /// struct Roll {
+    ///     sides: u32,
+    /// }
+    ///
+    /// impl Extension for Roll {
+    ///     const TYPE: &'static str = "dice:roll";
+    /// }
+    ///
+    /// # async fn example() -> eyre::Result<()> {
+    /// Bot::new("dicebot", "password", "127.0.0.1", 52800, "!")
+    ///     .on_extension(|roll: ExtensionMessage<Roll>, writer| async move {
+    ///         if let ExtensionTarget::Room { room } = roll.target {
+    ///             let mut channel = Channel::new(writer, room);
+    ///             channel.send(format!("{} rolled a d{}", roll.sender, roll.payload.sides)).await;
+    ///         }
+    ///     })
+    ///     .run()
+    ///     .await
+    /// # }
+    /// ```
+    /// # Panics
+    /// - Will panic if the type of the extension is invalid
+    #[must_use]
+    pub fn on_extension<E, F, Fut>(mut self, handler: F) -> Self
+    where
+        E: Extension + Send + 'static,
+        F: Fn(ExtensionMessage<E>, PacketQueue) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.extensions = self.extensions.register(handler);
+
+        self
+    }
+
+    /// Connect to the server, log in and print incoming messages until the connection is closed
+    /// # Errors
+    /// - Will return `Err` if the connection could not be established
+    /// - Will return `Err` if the login packet could not be sent
+    /// - Will return `Err` if the server rejected the login
+    pub async fn run(self) -> eyre::Result<()> {
+        let client = StbchatClient::connect((self.address.as_str(), self.port)).await?;
+        self.run_with_client(client).await
+    }
 
-        sock_ref.set_tcp_keepalive(&ka).unwrap();
+    /// Log in using an already connected client and print incoming messages until the connection is closed.
+    /// Allows running the bot over any transport, e.g. `StbchatClient::connect_unix`
+    /// # Errors
+    /// - Will return `Err` if the login packet could not be sent
+    /// - Will return `Err` if the server rejected the login
+    pub async fn run_with_client(mut self, client: StbchatClient) -> eyre::Result<()> {
+        let client = self.client.insert(client);
 
-        let (r_server, w_server) = split(stream);
+        if self.token_login {
+            client.login_token(&self.username, &self.token).await?;
+        } else {
+            client.login(&self.username, &self.token).await?;
+        }
 
-        let r_server = IncomingPacketStream::wrap(r_server);
-        let w_server = OutgoingPacketStream::wrap(w_server);
+        while let Some(packet) = client.recv().await {
+            match packet {
+                ClientPacket::AuthFailed { message, .. } => {
+                    eyre::bail!("Login failed: {message}");
+                }
+                ClientPacket::SystemMessage { message } => {
+                    println!(
+                        "{BOLD}[{}] {YELLOW}System{C_RESET}: {}",
+                        current_time("%H:%M"),
+                        strip_control_characters(&message)
+                    );
+                }
+                ClientPacket::UserMessage {
+                    author,
+                    message,
+                    room,
+                    ..
+                } => {
+                    println!(
+                        "{BOLD}[{}] #{} {CYAN}{}{C_RESET}: {}",
+                        current_time("%H:%M"),
+                        strip_control_characters(&room),
+                        author.display_name(),
+                        strip_control_characters(&message)
+                    );
+                }
+                ClientPacket::DirectMessage { author, message } => {
+                    println!(
+                        "{BOLD}[{}] {CYAN}{}{C_RESET} -> you: {}",
+                        current_time("%H:%M"),
+                        author.display_name(),
+                        strip_control_characters(&message)
+                    );
+                }
+                packet @ ClientPacket::Extension { .. } => {
+                    if let Err(err) = self.extensions.handle(packet, &client.writer()).await {
+                        println!(
+                            "{BOLD}[{}] {RED}Extension{C_RESET}: {err}",
+                            current_time("%H:%M")
+                        );
+                    }
+                }
+                _ => {}
+            }
+        }
 
-        self.r_server = self.r_server;
-        self.w_server = self.w_server;
+        Ok(())
     }
 }
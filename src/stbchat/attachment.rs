@@ -0,0 +1,65 @@
+use std::fmt::Write;
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+
+/// Maximum amount of bytes sent in a single `AttachmentChunk` packet.
+/// Leaves enough room for the packet envelope within the 65535 byte frame limit
+pub const CHUNK_SIZE: usize = 32 * 1024;
+
+/// Default upper limit for the size of an attachment
+pub const DEFAULT_MAX_ATTACHMENT_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Compute the hex encoded SHA-256 hash of a file's content
+#[must_use]
+pub fn hash(data: &[u8]) -> String {
+    to_hex(&Sha256::digest(data))
+}
+
+/// Hex encode a hash, e.g. of a `Sha256` hasher fed chunk by chunk
+pub(crate) fn to_hex(digest: &[u8]) -> String {
+    let mut hex = String::with_capacity(digest.len() * 2);
+
+    for byte in digest {
+        let _ = write!(hex, "{byte:02x}");
+    }
+
+    hex
+}
+
+/// Check whether a string is a hex encoded SHA-256 hash
+#[must_use]
+pub fn is_valid_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Guess the MIME type of a file by its extension.
+/// Returns `application/octet-stream` for unknown extensions
+#[must_use]
+pub fn guess_mime_type(path: impl AsRef<Path>) -> &'static str {
+    let extension = path
+        .as_ref()
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase)
+        .unwrap_or_default();
+
+    match extension.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "txt" | "log" => "text/plain",
+        "md" => "text/markdown",
+        "html" | "htm" => "text/html",
+        "json" => "application/json",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
@@ -0,0 +1,428 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Cursor, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, Utc};
+use serde_json::json;
+
+use crate::stbchat::format;
+use crate::stbchat::packet::{ClientPacket, ServerPacket};
+
+/// Magic bytes at the start of every capture file
+const MAGIC: &[u8; 6] = b"STBCAP";
+
+/// Version of the capture file format
+const FORMAT_VERSION: u8 = 1;
+
+/// Direction of a captured frame, seen from the peer that recorded it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+impl Direction {
+    const fn to_byte(self) -> u8 {
+        match self {
+            Self::Incoming => 0,
+            Self::Outgoing => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> io::Result<Self> {
+        match byte {
+            0 => Ok(Self::Incoming),
+            1 => Ok(Self::Outgoing),
+            _ => Err(invalid_data(format!("Unknown direction {byte}"))),
+        }
+    }
+
+    /// Arrow used in human-readable dumps
+    const fn arrow(self) -> &'static str {
+        match self {
+            Self::Incoming => "<-",
+            Self::Outgoing => "->",
+        }
+    }
+}
+
+/// The peer that recorded a capture. Decides which packet type a frame is decoded as
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Client,
+    Server,
+}
+
+impl Side {
+    const fn to_byte(self) -> u8 {
+        match self {
+            Self::Client => 0,
+            Self::Server => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> io::Result<Self> {
+        match byte {
+            0 => Ok(Self::Client),
+            1 => Ok(Self::Server),
+            _ => Err(invalid_data(format!("Unknown side {byte}"))),
+        }
+    }
+}
+
+/// # Packet capture tap
+/// Records every frame read from or written to a packet stream into a capture file.
+/// Frames are recorded uncompressed, so captures stay readable when compression is negotiated.
+/// A tap can be cloned and shared between the incoming and outgoing stream of a connection
+/// ```no_run
+/// use libstrawberry::stbchat::capture::{CaptureTap, Side};
+/// use libstrawberry::stbchat::client::StbchatClient;
+///
+/// # async fn example() -> eyre::Result<()> {
+/// let tap = CaptureTap::create("session.stbcap", Side::Client)?;
+/// let stream = tokio::net::TcpStream::connect("127.0.0.1:52800").await?;
+/// let client = StbchatClient::from_stream_with_capture(stream, Some(tap));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct CaptureTap {
+    file: Arc<Mutex<BufWriter<File>>>,
+}
+
+impl CaptureTap {
+    /// Create a new capture file, overwriting an existing one
+    /// # Errors
+    /// - Will return `Err` if the file could not be created
+    pub fn create(path: impl AsRef<Path>, side: Side) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+
+        file.write_all(MAGIC)?;
+        file.write_all(&[FORMAT_VERSION, side.to_byte()])?;
+        file.flush()?;
+
+        Ok(Self {
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    /// Record a frame. Errors are ignored, a broken capture must never break the connection
+    pub fn record(&self, direction: Direction, frame: &[u8]) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| {
+                u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX)
+            });
+
+        let Ok(len) = u32::try_from(frame.len()) else {
+            return;
+        };
+
+        let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        let _ = file
+            .write_all(&timestamp.to_be_bytes())
+            .and_then(|()| file.write_all(&[direction.to_byte()]))
+            .and_then(|()| file.write_all(&len.to_be_bytes()))
+            .and_then(|()| file.write_all(frame))
+            .and_then(|()| file.flush());
+    }
+}
+
+/// A packet decoded from a captured frame
+#[derive(Debug)]
+pub enum CapturedPacket {
+    Client(ClientPacket),
+    Server(ServerPacket),
+    /// The frame couldn't be decoded, contains the reason
+    Invalid(String),
+}
+
+/// A single recorded frame
+#[derive(Debug, Clone)]
+pub struct CaptureRecord {
+    /// Microseconds since the Unix epoch
+    pub timestamp: u64,
+    pub direction: Direction,
+    /// Uncompressed `MessagePack` body of the frame
+    pub frame: Vec<u8>,
+}
+
+impl CaptureRecord {
+    #[must_use]
+    pub fn time(&self) -> DateTime<Utc> {
+        i64::try_from(self.timestamp)
+            .ok()
+            .and_then(DateTime::from_timestamp_micros)
+            .unwrap_or_default()
+    }
+
+    /// Decode the frame as the packet type sent in its direction
+    #[must_use]
+    pub fn decode(&self, side: Side) -> CapturedPacket {
+        let client_packet = matches!(
+            (side, self.direction),
+            (Side::Client, Direction::Incoming) | (Side::Server, Direction::Outgoing)
+        );
+
+        if client_packet {
+            format::decode(&self.frame).map_or_else(
+                |err| CapturedPacket::Invalid(err.to_string()),
+                CapturedPacket::Client,
+            )
+        } else {
+            format::decode(&self.frame).map_or_else(
+                |err| CapturedPacket::Invalid(err.to_string()),
+                CapturedPacket::Server,
+            )
+        }
+    }
+
+    /// Human-readable dump, e.g. `2026-01-01T12:00:00.000000Z <- UserMessage { .. }`
+    #[must_use]
+    pub fn dump(&self, side: Side) -> String {
+        let packet = match self.decode(side) {
+            CapturedPacket::Client(packet) => format!("{packet:?}"),
+            CapturedPacket::Server(packet) => format!("{packet:?}"),
+            CapturedPacket::Invalid(reason) => {
+                format!("<invalid frame of {} bytes: {reason}>", self.frame.len())
+            }
+        };
+
+        format!(
+            "{} {} {packet}",
+            self.time().format("%Y-%m-%dT%H:%M:%S%.6fZ"),
+            self.direction.arrow()
+        )
+    }
+
+    /// JSON dump containing the timestamp, direction and decoded packet
+    #[must_use]
+    pub fn to_json(&self, side: Side) -> serde_json::Value {
+        let direction = match self.direction {
+            Direction::Incoming => "incoming",
+            Direction::Outgoing => "outgoing",
+        };
+
+        let packet = match self.decode(side) {
+            CapturedPacket::Client(packet) => serde_json::to_value(packet),
+            CapturedPacket::Server(packet) => serde_json::to_value(packet),
+            CapturedPacket::Invalid(reason) => Ok(json!({ "invalid": reason })),
+        }
+        .unwrap_or_else(|err| json!({ "invalid": err.to_string() }));
+
+        json!({
+            "timestamp": self.time().to_rfc3339(),
+            "direction": direction,
+            "packet": packet,
+        })
+    }
+}
+
+/// # Capture file reader
+/// ```no_run
+/// use libstrawberry::stbchat::capture::CaptureReader;
+///
+/// # fn example() -> std::io::Result<()> {
+/// let capture = CaptureReader::open("session.stbcap")?;
+/// let side = capture.side();
+///
+/// for record in capture {
+///     println!("{}", record?.dump(side));
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct CaptureReader<R: Read = BufReader<File>> {
+    reader: R,
+    side: Side,
+}
+
+impl CaptureReader {
+    /// Open a capture file
+    /// # Errors
+    /// - Will return `Err` if the file could not be opened
+    /// - Will return `Err` if the file is not a capture file
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> CaptureReader<R> {
+    /// Read a capture from any reader
+    /// # Errors
+    /// - Will return `Err` if the header could not be read or is invalid
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0u8; 8];
+        reader.read_exact(&mut header)?;
+
+        if &header[..6] != MAGIC {
+            return Err(invalid_data("Not a stbchat capture".to_string()));
+        }
+
+        if header[6] != FORMAT_VERSION {
+            return Err(invalid_data(format!(
+                "Unsupported capture version {}",
+                header[6]
+            )));
+        }
+
+        Ok(Self {
+            reader,
+            side: Side::from_byte(header[7])?,
+        })
+    }
+
+    /// The peer that recorded the capture
+    #[must_use]
+    pub const fn side(&self) -> Side {
+        self.side
+    }
+
+    fn read_record(&mut self) -> io::Result<Option<CaptureRecord>> {
+        let mut timestamp = [0u8; 8];
+
+        match self.reader.read_exact(&mut timestamp) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+
+        let mut direction = [0u8; 1];
+        let mut len = [0u8; 4];
+        self.reader.read_exact(&mut direction)?;
+        self.reader.read_exact(&mut len)?;
+
+        let mut frame = vec![0; u32::from_be_bytes(len) as usize];
+        self.reader.read_exact(&mut frame)?;
+
+        Ok(Some(CaptureRecord {
+            timestamp: u64::from_be_bytes(timestamp),
+            direction: Direction::from_byte(direction[0])?,
+            frame,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = io::Result<CaptureRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+/// # Capture replayer
+/// Feeds the frames of a capture into a packet stream, e.g. to replay a recorded session in a regression test.
+/// The replayed frames are uncompressed
+/// ```no_run
+/// use libstrawberry::stbchat::capture::{CaptureReader, Direction, Replayer};
+/// use libstrawberry::stbchat::net::IncomingPacketStream;
+/// use libstrawberry::stbchat::packet::ClientPacket;
+///
+/// # async fn example() -> eyre::Result<()> {
+/// // Replay what a client received from the server
+/// let replayer = Replayer::new(CaptureReader::open("session.stbcap")?)?;
+/// let mut stream = IncomingPacketStream::wrap(replayer.stream(Direction::Incoming)?);
+///
+/// while let Ok(packet) = stream.read::<ClientPacket>().await {
+///     println!("{packet:?}");
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct Replayer {
+    records: Vec<CaptureRecord>,
+}
+
+impl Replayer {
+    /// Load all records of a capture
+    /// # Errors
+    /// - Will return `Err` if a record could not be read
+    pub fn new<R: Read>(capture: CaptureReader<R>) -> io::Result<Self> {
+        Ok(Self {
+            records: capture.collect::<io::Result<_>>()?,
+        })
+    }
+
+    #[must_use]
+    pub fn records(&self) -> &[CaptureRecord] {
+        &self.records
+    }
+
+    /// Frames of one direction, length-prefixed like on the wire
+    /// # Errors
+    /// - Will return `Err` if a frame is too large to be sent uncompressed
+    pub fn frames(&self, direction: Direction) -> io::Result<Vec<u8>> {
+        let mut frames = Vec::new();
+
+        for record in self.by_direction(direction) {
+            frames.extend(frame(record)?);
+        }
+
+        Ok(frames)
+    }
+
+    /// An in-memory stream containing the frames of one direction.
+    /// Can be wrapped into an `IncomingPacketStream` (sync and async)
+    /// # Errors
+    /// - Will return `Err` if a frame is too large to be sent uncompressed
+    pub fn stream(&self, direction: Direction) -> io::Result<Cursor<Vec<u8>>> {
+        Ok(Cursor::new(self.frames(direction)?))
+    }
+
+    /// Write the frames of one direction to a stream.
+    /// With `realtime`, the original delays between the frames are kept
+    /// # Errors
+    /// - Will return `Err` if a frame is too large to be sent uncompressed
+    /// - Will return `Err` if writing to the stream fails
+    #[cfg(not(feature = "stbchat-sync"))]
+    pub async fn replay<W: tokio::io::AsyncWrite + Unpin>(
+        &self,
+        direction: Direction,
+        writer: &mut W,
+        realtime: bool,
+    ) -> io::Result<()> {
+        use tokio::io::AsyncWriteExt;
+
+        let mut previous = None;
+
+        for record in self.by_direction(direction) {
+            if realtime && let Some(previous) = previous {
+                let delay = record.timestamp.saturating_sub(previous);
+                tokio::time::sleep(std::time::Duration::from_micros(delay)).await;
+            }
+            previous = Some(record.timestamp);
+
+            writer.write_all(&frame(record)?).await?;
+        }
+
+        writer.flush().await
+    }
+
+    fn by_direction(&self, direction: Direction) -> impl Iterator<Item = &CaptureRecord> {
+        self.records
+            .iter()
+            .filter(move |record| record.direction == direction)
+    }
+}
+
+fn frame(record: &CaptureRecord) -> io::Result<Vec<u8>> {
+    let len = u16::try_from(record.frame.len()).map_err(|_| {
+        invalid_data(format!(
+            "Frame of {} bytes is too large",
+            record.frame.len()
+        ))
+    })?;
+
+    let mut frame = Vec::with_capacity(record.frame.len() + 2);
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(&record.frame);
+
+    Ok(frame)
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,1159 @@
+#![cfg(not(feature = "stbchat-sync"))]
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, PoisonError};
+use std::time::{Duration, Instant};
+
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, split};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::sync::mpsc;
+#[cfg(feature = "stbchat-e2e")]
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+#[cfg(feature = "strawberryid")]
+use crate::id::credentials::StrawberryIdCredentials;
+use crate::stbchat::attachment::{self, CHUNK_SIZE, DEFAULT_MAX_ATTACHMENT_SIZE};
+use crate::stbchat::capture::CaptureTap;
+#[cfg(feature = "stbchat-compression")]
+use crate::stbchat::compression::{Compression, CompressionAlgorithm};
+#[cfg(feature = "stbchat-e2e")]
+use crate::stbchat::e2e::{self, KeyPair};
+#[cfg(feature = "stbchat-e2e")]
+use crate::stbchat::error::E2eError;
+use crate::stbchat::error::{AttachmentError, CommunicationError, ExtensionError};
+use crate::stbchat::extension::{self, Extension};
+use crate::stbchat::format::WireFormat;
+use crate::stbchat::metrics::{ConnectionGuard, Metrics};
+use crate::stbchat::net::{IncomingPacketStream, OutgoingPacketStream};
+use crate::stbchat::object::{
+    Attachment, AttachmentId, DEFAULT_ROOM, ExtensionTarget, HistoryQuery, MessageId, Presence,
+    UploadStatus,
+};
+#[cfg(feature = "stbchat-e2e")]
+use crate::stbchat::object::{StbchatApiRequest, StbchatApiResponse};
+use crate::stbchat::packet::{ClientPacket, ServerPacket};
+use crate::stbchat::queue::{OverflowPolicy, PacketQueue, QueueConfig};
+#[cfg(feature = "stbchat-tls")]
+use crate::stbchat::tls::{self, TlsConnector};
+use crate::stbchat::transport::{BoxedReader, BoxedWriter};
+#[cfg(feature = "stbchat-websocket")]
+use crate::stbchat::websocket;
+
+/// Interval in which the client sends `KeepAlive` packets to the server
+pub const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(20);
+
+/// Minimum interval between two `TypingStart` packets for the same room.
+/// Clients should consider a user as no longer typing if no `TypingStart` was received for a while
+pub const TYPING_INTERVAL: Duration = Duration::from_secs(3);
+
+/// Maximum time to wait for the server while transferring an attachment
+pub const TRANSFER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Maximum time to wait for the key directory of the server
+#[cfg(feature = "stbchat-e2e")]
+pub const KEY_LOOKUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Amount of received packets that are buffered until `recv` is called
+const EVENT_BUFFER_SIZE: usize = 64;
+
+type SharedTransfers = Arc<std::sync::Mutex<Transfers>>;
+
+type SharedNegotiation = Arc<std::sync::Mutex<Negotiation>>;
+
+/// Capabilities announced by the server and the features selected by the client
+#[derive(Default)]
+struct Negotiation {
+    /// Format requested by the user
+    preferred_format: WireFormat,
+    /// Formats announced by the server, `None` until its capabilities were received
+    formats: Option<Vec<WireFormat>>,
+    #[cfg(feature = "stbchat-compression")]
+    compression: Option<Compression>,
+}
+
+impl Negotiation {
+    /// The packet selecting the preferred format and the current compression
+    fn select(&self) -> (ServerPacket, WireFormat) {
+        let format = self
+            .formats
+            .iter()
+            .flatten()
+            .copied()
+            .find(|format| *format == self.preferred_format)
+            .unwrap_or_default();
+
+        #[cfg(feature = "stbchat-compression")]
+        let compression = self
+            .compression
+            .map(|compression| compression.algorithm.name().to_string());
+        #[cfg(not(feature = "stbchat-compression"))]
+        let compression = None;
+
+        let packet = ServerPacket::SelectCapabilities {
+            compression,
+            format: Some(format.name().to_string()),
+        };
+
+        (packet, format)
+    }
+}
+
+/// Attachment transfers and key lookups waiting for packets of the server.
+/// Matching packets are passed to the transfer instead of the event stream
+#[derive(Default)]
+struct Transfers {
+    uploads: HashMap<String, mpsc::UnboundedSender<UploadStatus>>,
+    downloads: HashMap<AttachmentId, mpsc::UnboundedSender<ClientPacket>>,
+    /// Lookups of public keys by username, `None` if the user didn't publish a key
+    #[cfg(feature = "stbchat-e2e")]
+    key_lookups: HashMap<String, Vec<oneshot::Sender<Option<Vec<u8>>>>>,
+}
+
+/// # High-level async Strawberry Chat client
+/// Connects to a server, reads incoming packets in the background
+/// and keeps the connection alive by periodically sending `KeepAlive` packets.
+/// ```no_run
+/// use libstrawberry::stbchat::client::StbchatClient;
+/// use libstrawberry::stbchat::packet::ClientPacket;
+///
+/// # async fn example() -> eyre::Result<()> {
+/// let mut client = StbchatClient::connect("127.0.0.1:52800").await?;
+/// client.login("username", "password").await?;
+/// client.send_message("Hello World!").await?;
+///
+/// while let Some(packet) = client.recv().await {
+///     if let ClientPacket::UserMessage { author, message, .. } = packet {
+///         println!("{}: {message}", author.username);
+///     }
+/// }
+///
+/// client.close().await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct StbchatClient {
+    writer: PacketQueue,
+    events: mpsc::Receiver<ClientPacket>,
+    reader_task: JoinHandle<()>,
+    keep_alive_task: JoinHandle<()>,
+
+    /// Rooms the client is typing in, with the time the last `TypingStart` was sent
+    typing: std::sync::Mutex<HashMap<String, Instant>>,
+    transfers: SharedTransfers,
+    negotiation: SharedNegotiation,
+    metrics: Arc<Metrics>,
+    max_attachment_size: u64,
+}
+
+impl StbchatClient {
+    /// Connect to a Strawberry Chat server
+    /// # Errors
+    /// - Will return `Err` if the connection could not be established
+    /// - Will return `Err` if TCP keepalive could not be configured
+    pub async fn connect(addr: impl ToSocketAddrs) -> eyre::Result<Self> {
+        let stream = connect_tcp(addr).await?;
+        Ok(Self::from_stream(stream))
+    }
+
+    /// Connect to a Strawberry Chat server using TLS
+    /// # Errors
+    /// - Will return `Err` if the connection could not be established
+    /// - Will return `Err` if TCP keepalive could not be configured
+    /// - Will return `Err` if the TLS handshake fails
+    #[cfg(feature = "stbchat-tls")]
+    pub async fn connect_tls(
+        addr: impl ToSocketAddrs,
+        server_name: &str,
+        connector: &TlsConnector,
+    ) -> eyre::Result<Self> {
+        let stream = connect_tcp(addr).await?;
+        let stream = tls::connect(connector, server_name, stream).await?;
+
+        Ok(Self::from_stream(stream))
+    }
+
+    /// Connect to a Strawberry Chat server over WebSocket, e.g. `ws://chat.example.com:52810/`
+    /// # Errors
+    /// - Will return `Err` if the URL is invalid
+    /// - Will return `Err` if the connection could not be established
+    /// - Will return `Err` if TCP keepalive could not be configured
+    /// - Will return `Err` if the WebSocket handshake fails
+    #[cfg(feature = "stbchat-websocket")]
+    pub async fn connect_websocket(url: &str) -> eyre::Result<Self> {
+        let (host, port) = websocket::host_and_port(url)?;
+        let stream = connect_tcp((host.as_str(), port)).await?;
+        let stream = websocket::connect(url, stream).await?;
+
+        Ok(Self::from_stream(stream))
+    }
+
+    /// Connect to a Strawberry Chat server over WebSocket using TLS, e.g. `wss://chat.example.com/`.
+    /// The host of the URL is used as the TLS server name
+    /// # Errors
+    /// - Will return `Err` if the URL is invalid
+    /// - Will return `Err` if the connection could not be established
+    /// - Will return `Err` if TCP keepalive could not be configured
+    /// - Will return `Err` if the TLS or WebSocket handshake fails
+    #[cfg(all(feature = "stbchat-websocket", feature = "stbchat-tls"))]
+    pub async fn connect_websocket_tls(url: &str, connector: &TlsConnector) -> eyre::Result<Self> {
+        let (host, port) = websocket::host_and_port(url)?;
+        let stream = connect_tcp((host.as_str(), port)).await?;
+        let stream = tls::connect(connector, &host, stream).await?;
+        let stream = websocket::connect(url, stream).await?;
+
+        Ok(Self::from_stream(stream))
+    }
+
+    /// Connect to a local Strawberry Chat server over a Unix domain socket
+    /// # Errors
+    /// - Will return `Err` if the connection could not be established
+    #[cfg(unix)]
+    pub async fn connect_unix(path: impl AsRef<Path>) -> eyre::Result<Self> {
+        let stream = UnixStream::connect(path).await?;
+        Ok(Self::from_stream(stream))
+    }
+
+    /// Create a client from an already established connection, e.g. a `DuplexStream` of
+    /// `transport::memory` or a `WebSocketTransport`.
+    /// Must be called from within a tokio runtime
+    pub fn from_stream<S: AsyncRead + AsyncWrite + Send + 'static>(stream: S) -> Self {
+        Self::from_stream_with_capture(stream, None)
+    }
+
+    /// Create a client from an already established connection, recording all packets into a capture.
+    /// Must be called from within a tokio runtime
+    pub fn from_stream_with_capture<S: AsyncRead + AsyncWrite + Send + 'static>(
+        stream: S,
+        tap: Option<CaptureTap>,
+    ) -> Self {
+        let (r_server, w_server) = split(stream);
+
+        let mut r_server = IncomingPacketStream::wrap(Box::new(r_server) as BoxedReader);
+        let mut w_server = OutgoingPacketStream::wrap(Box::new(w_server) as BoxedWriter);
+        r_server.set_tap(tap.clone());
+        w_server.set_tap(tap);
+
+        let metrics = Arc::new(Metrics::new());
+        r_server.set_metrics(Some(metrics.clone()));
+        w_server.set_metrics(Some(metrics.clone()));
+        // Senders wait for a slow server instead of losing packets
+        let writer =
+            PacketQueue::spawn(w_server, QueueConfig::new().overflow(OverflowPolicy::Block));
+
+        let (tx, events) = mpsc::channel(EVENT_BUFFER_SIZE);
+
+        let transfers = SharedTransfers::default();
+        let negotiation = SharedNegotiation::default();
+
+        let reader_task = tokio::spawn(read_packets(
+            r_server,
+            writer.clone(),
+            transfers.clone(),
+            negotiation.clone(),
+            tx,
+            metrics.track_connection(),
+        ));
+        let keep_alive_task = tokio::spawn(keep_alive(writer.clone()));
+
+        Self {
+            writer,
+            events,
+            reader_task,
+            keep_alive_task,
+            typing: std::sync::Mutex::new(HashMap::new()),
+            transfers,
+            negotiation,
+            metrics,
+            max_attachment_size: DEFAULT_MAX_ATTACHMENT_SIZE,
+        }
+    }
+
+    /// Limit the size of files uploaded with `send_file`, should match the limit of the server.
+    /// Defaults to `DEFAULT_MAX_ATTACHMENT_SIZE`
+    pub const fn set_max_attachment_size(&mut self, max_attachment_size: u64) {
+        self.max_attachment_size = max_attachment_size;
+    }
+
+    /// Log in with an existing account
+    /// # Errors
+    /// - Will return `Err` if the packet could not be sent
+    pub async fn login(
+        &self,
+        username: impl ToString,
+        password: impl ToString,
+    ) -> Result<(), CommunicationError> {
+        self.send(ServerPacket::Login {
+            username: username.to_string(),
+            password: password.to_string(),
+        })
+        .await
+    }
+
+    /// Log in with a Strawberry ID token instead of a password
+    /// # Errors
+    /// - Will return `Err` if the packet could not be sent
+    pub async fn login_token(
+        &self,
+        username: impl ToString,
+        token: impl ToString,
+    ) -> Result<(), CommunicationError> {
+        self.send(ServerPacket::TokenLogin {
+            username: username.to_string(),
+            token: token.to_string(),
+        })
+        .await
+    }
+
+    /// Log in with Strawberry ID credentials
+    /// ```no_run
+    /// use libstrawberry::id::credentials::StrawberryIdCredentials;
+    /// use libstrawberry::stbchat::client::StbchatClient;
+    ///
+    /// # async fn example() -> eyre::Result<()> {
+    /// let client = StbchatClient::connect("127.0.0.1:52800").await?;
+    /// client.login_strawberry_id(&StrawberryIdCredentials::fetch()?).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    /// # Errors
+    /// - Will return `Err` if the packet could not be sent
+    #[cfg(feature = "strawberryid")]
+    pub async fn login_strawberry_id(
+        &self,
+        credentials: &StrawberryIdCredentials,
+    ) -> Result<(), CommunicationError> {
+        self.login_token(&credentials.username, &credentials.token)
+            .await
+    }
+
+    /// Register a new account
+    /// # Errors
+    /// - Will return `Err` if the packet could not be sent
+    pub async fn register(
+        &self,
+        username: impl ToString,
+        password: impl ToString,
+        role_color: impl ToString,
+    ) -> Result<(), CommunicationError> {
+        self.send(ServerPacket::Register {
+            username: username.to_string(),
+            password: password.to_string(),
+            role_color: role_color.to_string(),
+        })
+        .await
+    }
+
+    /// Send a chat message to the default room
+    /// # Errors
+    /// - Will return `Err` if the packet could not be sent
+    pub async fn send_message(&self, message: impl ToString) -> Result<(), CommunicationError> {
+        self.send_room_message(DEFAULT_ROOM, message).await
+    }
+
+    /// Send a chat message to a room the client has joined
+    /// # Errors
+    /// - Will return `Err` if the packet could not be sent
+    pub async fn send_room_message(
+        &self,
+        room: impl ToString,
+        message: impl ToString,
+    ) -> Result<(), CommunicationError> {
+        self.clear_typing(&room.to_string());
+        self.send(ServerPacket::Message {
+            message: message.to_string(),
+            room: room.to_string(),
+            reply_to: None,
+        })
+        .await
+    }
+
+    /// Reply to a message. The reply is sent to the given room, which has to be the room of the message
+    /// # Errors
+    /// - Will return `Err` if the packet could not be sent
+    pub async fn send_reply(
+        &self,
+        room: impl ToString,
+        reply_to: MessageId,
+        message: impl ToString,
+    ) -> Result<(), CommunicationError> {
+        self.clear_typing(&room.to_string());
+        self.send(ServerPacket::Message {
+            message: message.to_string(),
+            room: room.to_string(),
+            reply_to: Some(reply_to),
+        })
+        .await
+    }
+
+    /// Request up to `limit` messages of a room the client has joined.
+    /// The server answers with a `History` packet
+    /// # Errors
+    /// - Will return `Err` if the packet could not be sent
+    pub async fn request_history(
+        &self,
+        room: impl ToString,
+        query: HistoryQuery,
+        limit: usize,
+    ) -> Result<(), CommunicationError> {
+        self.send(ServerPacket::History {
+            room: room.to_string(),
+            query,
+            limit,
+        })
+        .await
+    }
+
+    /// Upload a file and share it in a room the client has joined.
+    /// Interrupted uploads of the same file are resumed, e.g. after reconnecting.
+    /// Returns the id of the attachment once the server verified its hash
+    /// # Errors
+    /// - Will return `Err` if the file could not be read
+    /// - Will return `TooLarge` if the file is larger than the maximum attachment size
+    /// - Will return `Err` if the server rejected the upload
+    /// - Will return `Err` if a packet could not be sent
+    /// - Will return `Err` if the server didn't answer within `TRANSFER_TIMEOUT`
+    pub async fn send_file(
+        &self,
+        room: impl ToString,
+        path: impl AsRef<Path>,
+    ) -> Result<AttachmentId, AttachmentError> {
+        let path = path.as_ref();
+        let size = tokio::fs::metadata(path).await?.len();
+
+        if size > self.max_attachment_size {
+            return Err(AttachmentError::TooLarge {
+                size,
+                max: self.max_attachment_size,
+            });
+        }
+
+        let data = tokio::fs::read(path).await?;
+
+        let attachment = Attachment {
+            id: 0,
+            name: path.file_name().map_or_else(
+                || "file".to_string(),
+                |name| name.to_string_lossy().into_owned(),
+            ),
+            size: data.len() as u64,
+            mime_type: attachment::guess_mime_type(path).to_string(),
+            hash: attachment::hash(&data),
+        };
+
+        let (tx, mut status) = mpsc::unbounded_channel();
+        self.lock_transfers()
+            .uploads
+            .insert(attachment.hash.clone(), tx);
+
+        let hash = attachment.hash.clone();
+        let result = self
+            .upload(room.to_string(), attachment, &data, &mut status)
+            .await;
+        self.lock_transfers().uploads.remove(&hash);
+
+        result
+    }
+
+    /// Download an attachment and save it to a path.
+    /// Data is written to `<path>.part` first, so interrupted downloads are resumed on the next call.
+    /// The file is moved to `path` once its hash was verified
+    /// # Errors
+    /// - Will return `Err` if the file could not be written
+    /// - Will return `Err` if the attachment is not available
+    /// - Will return `Err` if the received data doesn't match the hash of the attachment
+    /// - Will return `Err` if a packet could not be sent
+    /// - Will return `Err` if the server didn't answer within `TRANSFER_TIMEOUT`
+    pub async fn save_attachment(
+        &self,
+        attachment: &Attachment,
+        path: impl AsRef<Path>,
+    ) -> Result<(), AttachmentError> {
+        let path = path.as_ref();
+        let part = part_path(path);
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&part)
+            .await?;
+
+        let mut offset = file.metadata().await?.len();
+
+        if offset > attachment.size {
+            file.set_len(0).await?;
+            offset = 0;
+        }
+
+        if offset < attachment.size {
+            let (tx, mut chunks) = mpsc::unbounded_channel();
+            self.lock_transfers().downloads.insert(attachment.id, tx);
+
+            let result = self
+                .download(attachment, offset, &mut file, &mut chunks)
+                .await;
+            self.lock_transfers().downloads.remove(&attachment.id);
+
+            result?;
+        }
+
+        file.flush().await?;
+        drop(file);
+
+        if attachment::hash(&tokio::fs::read(&part).await?) != attachment.hash {
+            tokio::fs::remove_file(&part).await?;
+            return Err(AttachmentError::HashMismatch);
+        }
+
+        tokio::fs::rename(&part, path).await?;
+
+        Ok(())
+    }
+
+    async fn upload(
+        &self,
+        room: String,
+        attachment: Attachment,
+        data: &[u8],
+        status: &mut mpsc::UnboundedReceiver<UploadStatus>,
+    ) -> Result<AttachmentId, AttachmentError> {
+        self.send(ServerPacket::OfferAttachment {
+            room,
+            name: attachment.name,
+            size: attachment.size,
+            mime_type: attachment.mime_type,
+            hash: attachment.hash,
+        })
+        .await?;
+
+        let (id, offset) = match next_transfer_event(status).await? {
+            UploadStatus::Ready { id, offset } => (id, offset),
+            UploadStatus::Complete { id } => return Ok(id),
+            UploadStatus::Failed { reason } => return Err(AttachmentError::Rejected(reason)),
+        };
+
+        let start = usize::try_from(offset).map_or(data.len(), |start| start.min(data.len()));
+        let mut offset = start as u64;
+
+        for chunk in data[start..].chunks(CHUNK_SIZE) {
+            self.send(ServerPacket::AttachmentChunk {
+                id,
+                offset,
+                data: chunk.to_vec(),
+            })
+            .await?;
+
+            offset += chunk.len() as u64;
+        }
+
+        loop {
+            match next_transfer_event(status).await? {
+                UploadStatus::Complete { id: completed } if completed == id => return Ok(id),
+                UploadStatus::Failed { reason } => return Err(AttachmentError::Rejected(reason)),
+                _ => {}
+            }
+        }
+    }
+
+    async fn download(
+        &self,
+        attachment: &Attachment,
+        mut offset: u64,
+        file: &mut tokio::fs::File,
+        chunks: &mut mpsc::UnboundedReceiver<ClientPacket>,
+    ) -> Result<(), AttachmentError> {
+        self.send(ServerPacket::DownloadAttachment {
+            id: attachment.id,
+            offset,
+        })
+        .await?;
+
+        while offset < attachment.size {
+            match next_transfer_event(chunks).await? {
+                ClientPacket::AttachmentChunk {
+                    offset: received,
+                    data,
+                    ..
+                } => {
+                    if received != offset {
+                        return Err(AttachmentError::UnexpectedOffset {
+                            expected: offset,
+                            got: received,
+                        });
+                    }
+
+                    file.write_all(&data).await?;
+                    offset += data.len() as u64;
+                }
+                _ => return Err(AttachmentError::Unavailable(attachment.id)),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Wait for the next public key of a user received from the key directory
+    #[cfg(feature = "stbchat-e2e")]
+    fn register_key_lookup(&self, username: &str) -> oneshot::Receiver<Option<Vec<u8>>> {
+        let (tx, rx) = oneshot::channel();
+
+        let mut transfers = self.lock_transfers();
+        let lookups = transfers
+            .key_lookups
+            .entry(username.to_string())
+            .or_default();
+        lookups.retain(|lookup| !lookup.is_closed());
+        lookups.push(tx);
+        drop(transfers);
+
+        rx
+    }
+
+    fn lock_transfers(&self) -> std::sync::MutexGuard<'_, Transfers> {
+        self.transfers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Replace the content of an own message
+    /// # Errors
+    /// - Will return `Err` if the packet could not be sent
+    pub async fn edit_message(
+        &self,
+        id: MessageId,
+        message: impl ToString,
+    ) -> Result<(), CommunicationError> {
+        self.send(ServerPacket::EditMessage {
+            id,
+            message: message.to_string(),
+        })
+        .await
+    }
+
+    /// Delete an own message
+    /// # Errors
+    /// - Will return `Err` if the packet could not be sent
+    pub async fn delete_message(&self, id: MessageId) -> Result<(), CommunicationError> {
+        self.send(ServerPacket::DeleteMessage { id }).await
+    }
+
+    /// React to a message with an emoji
+    /// # Errors
+    /// - Will return `Err` if the packet could not be sent
+    pub async fn add_reaction(
+        &self,
+        id: MessageId,
+        emoji: impl ToString,
+    ) -> Result<(), CommunicationError> {
+        self.send(ServerPacket::AddReaction {
+            id,
+            emoji: emoji.to_string(),
+        })
+        .await
+    }
+
+    /// Remove an own reaction from a message
+    /// # Errors
+    /// - Will return `Err` if the packet could not be sent
+    pub async fn remove_reaction(
+        &self,
+        id: MessageId,
+        emoji: impl ToString,
+    ) -> Result<(), CommunicationError> {
+        self.send(ServerPacket::RemoveReaction {
+            id,
+            emoji: emoji.to_string(),
+        })
+        .await
+    }
+
+    /// Send a private message to a single user.
+    /// The server answers with a `DirectMessageStatus` packet
+    /// # Errors
+    /// - Will return `Err` if the packet could not be sent
+    pub async fn send_direct_message(
+        &self,
+        recipient: impl ToString,
+        message: impl ToString,
+    ) -> Result<(), CommunicationError> {
+        self.send(ServerPacket::DirectMessage {
+            recipient: recipient.to_string(),
+            message: message.to_string(),
+        })
+        .await
+    }
+
+    /// Publish the public key of a key pair in the key directory of the server,
+    /// so other users can send end-to-end encrypted direct messages.
+    /// The server confirms with a `PublicKey` api response
+    /// # Errors
+    /// - Will return `Err` if the packet could not be sent
+    #[cfg(feature = "stbchat-e2e")]
+    pub async fn publish_key(&self, keys: &KeyPair) -> Result<(), CommunicationError> {
+        self.send(ServerPacket::ApiRequest {
+            request_type: "publish_key".to_string(),
+            request: Some(StbchatApiRequest::PublishKey {
+                public_key: keys.public_key().to_vec(),
+            }),
+        })
+        .await
+    }
+
+    /// Look up the public key of a user in the key directory of the server
+    /// # Errors
+    /// - Will return `Err` if the user didn't publish a public key
+    /// - Will return `Err` if the packet could not be sent
+    /// - Will return `Err` if the server didn't answer within `KEY_LOOKUP_TIMEOUT`
+    #[cfg(feature = "stbchat-e2e")]
+    pub async fn public_key(&self, username: impl ToString) -> Result<Vec<u8>, E2eError> {
+        let username = username.to_string();
+
+        let rx = self.register_key_lookup(&username);
+
+        self.send(ServerPacket::ApiRequest {
+            request_type: "public_key".to_string(),
+            request: Some(StbchatApiRequest::PublicKey {
+                username: username.clone(),
+            }),
+        })
+        .await?;
+
+        match tokio::time::timeout(KEY_LOOKUP_TIMEOUT, rx).await {
+            Ok(Ok(Some(public_key))) => Ok(public_key),
+            Ok(Ok(None)) => Err(E2eError::UnknownKey(username)),
+            Ok(Err(_)) => Err(CommunicationError::ConnectionClosed.into()),
+            Err(_) => Err(E2eError::Timeout(username)),
+        }
+    }
+
+    /// Encrypt a private message with the published key of the recipient and send it.
+    /// The server answers with a `DirectMessageStatus` packet.
+    ///
+    /// Returns the fingerprint of the key the message was encrypted with.
+    /// Compare it with the fingerprint of the recipient through another channel to detect a forged key
+    /// # Errors
+    /// - Will return `Err` if the recipient didn't publish a valid public key
+    /// - Will return `Err` if the message could not be encrypted
+    /// - Will return `Err` if the packet could not be sent
+    #[cfg(feature = "stbchat-e2e")]
+    pub async fn send_encrypted_direct_message(
+        &self,
+        keys: &KeyPair,
+        recipient: impl ToString,
+        message: impl ToString,
+    ) -> Result<String, E2eError> {
+        let recipient = recipient.to_string();
+        let public_key = self.public_key(&recipient).await?;
+        let payload = keys.encrypt(&public_key, &message.to_string())?;
+
+        self.send(ServerPacket::EncryptedDirectMessage { recipient, payload })
+            .await?;
+
+        Ok(e2e::fingerprint(&public_key))
+    }
+
+    /// Send an extension packet to a room or user
+    /// # Errors
+    /// - Will return `Err` if the type of the extension is invalid
+    /// - Will return `Err` if the payload could not be encoded
+    /// - Will return `Err` if the packet could not be sent
+    pub async fn send_extension<E: Extension + Sync>(
+        &self,
+        target: ExtensionTarget,
+        extension: &E,
+    ) -> Result<(), ExtensionError> {
+        self.send(extension::packet(target, extension)?).await?;
+
+        Ok(())
+    }
+
+    /// Join a room, creating it if it doesn't exist yet
+    /// # Errors
+    /// - Will return `Err` if the packet could not be sent
+    pub async fn join_room(&self, room: impl ToString) -> Result<(), CommunicationError> {
+        self.send(ServerPacket::JoinRoom {
+            room: room.to_string(),
+        })
+        .await
+    }
+
+    /// Leave a room
+    /// # Errors
+    /// - Will return `Err` if the packet could not be sent
+    pub async fn leave_room(&self, room: impl ToString) -> Result<(), CommunicationError> {
+        self.send(ServerPacket::LeaveRoom {
+            room: room.to_string(),
+        })
+        .await
+    }
+
+    /// Request a list of all rooms. The server answers with a `RoomList` packet
+    /// # Errors
+    /// - Will return `Err` if the packet could not be sent
+    pub async fn list_rooms(&self) -> Result<(), CommunicationError> {
+        self.send(ServerPacket::ListRooms).await
+    }
+
+    /// Change the own presence. The server announces it to everyone with a `PresenceUpdate` packet
+    /// # Errors
+    /// - Will return `Err` if the packet could not be sent
+    pub async fn set_presence(&self, presence: Presence) -> Result<(), CommunicationError> {
+        self.send(ServerPacket::SetPresence { presence }).await
+    }
+
+    /// Request the presence of all online users. The server answers with a `PresenceList` packet
+    /// # Errors
+    /// - Will return `Err` if the packet could not be sent
+    pub async fn list_presence(&self) -> Result<(), CommunicationError> {
+        self.send(ServerPacket::ListPresence).await
+    }
+
+    /// Tell the members of a room that the user is typing.
+    /// Can be called on every keystroke, a `TypingStart` packet is sent at most once per `TYPING_INTERVAL`
+    /// # Errors
+    /// - Will return `Err` if the packet could not be sent
+    pub async fn start_typing(&self, room: impl ToString) -> Result<(), CommunicationError> {
+        let room = room.to_string();
+
+        {
+            let mut typing = self.typing.lock().unwrap_or_else(PoisonError::into_inner);
+
+            if typing
+                .get(&room)
+                .is_some_and(|sent| sent.elapsed() < TYPING_INTERVAL)
+            {
+                return Ok(());
+            }
+
+            typing.insert(room.clone(), Instant::now());
+        }
+
+        self.send(ServerPacket::TypingStart { room }).await
+    }
+
+    /// Tell the members of a room that the user stopped typing.
+    /// Nothing is sent if the user isn't typing in the room.
+    /// Sending a message to the room stops typing implicitly
+    /// # Errors
+    /// - Will return `Err` if the packet could not be sent
+    pub async fn stop_typing(&self, room: impl ToString) -> Result<(), CommunicationError> {
+        let room = room.to_string();
+
+        if !self.clear_typing(&room) {
+            return Ok(());
+        }
+
+        self.send(ServerPacket::TypingStop { room }).await
+    }
+
+    /// Forget that the user is typing in a room. Returns `true` if the user was typing
+    fn clear_typing(&self, room: &str) -> bool {
+        self.typing
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(room)
+            .is_some()
+    }
+
+    /// Send a raw packet to the server
+    /// # Errors
+    /// - Will return `Err` if the packet could not be sent
+    pub async fn send(&self, packet: ServerPacket) -> Result<(), CommunicationError> {
+        self.writer.send(packet).await
+    }
+
+    /// Wait for the next packet sent by the server.
+    /// Returns `None` once the connection has been closed
+    pub async fn recv(&mut self) -> Option<ClientPacket> {
+        self.events.recv().await
+    }
+
+    /// Returns the receiving end of the event stream
+    pub const fn events(&mut self) -> &mut mpsc::Receiver<ClientPacket> {
+        &mut self.events
+    }
+
+    /// Serialize packets in another format, e.g. JSON to inspect the traffic.
+    /// The format is selected once the server announced its capabilities, or right away if it already did.
+    /// Returns `false` if the server announced its capabilities without the format
+    /// # Errors
+    /// - Will return `Err` if the packet could not be sent
+    pub async fn select_format(&self, format: WireFormat) -> Result<bool, CommunicationError> {
+        let selected = {
+            let mut negotiation = self
+                .negotiation
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            negotiation.preferred_format = format;
+
+            match &negotiation.formats {
+                None => None,
+                Some(formats) if !formats.contains(&format) => return Ok(false),
+                Some(_) => Some(negotiation.select()),
+            }
+        };
+
+        if let Some((packet, format)) = selected {
+            self.send(packet).await?;
+            self.writer.set_format(format);
+        }
+
+        Ok(true)
+    }
+
+    /// Returns the format packets are sent in
+    #[must_use]
+    pub fn format(&self) -> WireFormat {
+        self.writer.format()
+    }
+
+    /// Returns the outgoing packet queue of the connection, shared with the client.
+    /// Allows sending packets from other tasks, e.g. by scapi channels
+    #[must_use]
+    pub fn writer(&self) -> PacketQueue {
+        self.writer.clone()
+    }
+
+    /// Returns the packet and connection metrics of the client
+    #[must_use]
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// Returns `true` as long as packets are being read from the server
+    #[must_use]
+    pub fn is_connected(&self) -> bool {
+        !self.reader_task.is_finished()
+    }
+
+    /// Stop sending `KeepAlive` packets and gracefully shut down the connection
+    /// # Errors
+    /// - Will return `Err` if the connection could not be shut down
+    pub async fn close(self) -> Result<(), CommunicationError> {
+        self.keep_alive_task.abort();
+        self.writer.shutdown().await?;
+        self.reader_task.abort();
+
+        Ok(())
+    }
+}
+
+impl Drop for StbchatClient {
+    fn drop(&mut self) {
+        self.keep_alive_task.abort();
+        self.reader_task.abort();
+    }
+}
+
+/// Wait for the next packet of an attachment transfer
+async fn next_transfer_event<T>(
+    receiver: &mut mpsc::UnboundedReceiver<T>,
+) -> Result<T, AttachmentError> {
+    match tokio::time::timeout(TRANSFER_TIMEOUT, receiver.recv()).await {
+        Ok(Some(event)) => Ok(event),
+        Ok(None) => Err(CommunicationError::ConnectionClosed.into()),
+        Err(_) => Err(AttachmentError::Timeout),
+    }
+}
+
+/// Path of the partially downloaded file, `<path>.part`
+fn part_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+    PathBuf::from(part)
+}
+
+/// Pass a packet to the attachment transfer waiting for it.
+/// Returns the packet if no transfer is interested in it
+fn route_transfer(transfers: &SharedTransfers, packet: ClientPacket) -> Option<ClientPacket> {
+    #[cfg_attr(not(feature = "stbchat-e2e"), allow(unused_mut))]
+    let mut transfers = transfers.lock().unwrap_or_else(PoisonError::into_inner);
+
+    match packet {
+        ClientPacket::AttachmentUpload { hash, status } => match transfers.uploads.get(&hash) {
+            Some(upload) => {
+                let _ = upload.send(status);
+                None
+            }
+            None => Some(ClientPacket::AttachmentUpload { hash, status }),
+        },
+        ClientPacket::AttachmentChunk { id, .. } | ClientPacket::AttachmentUnavailable { id } => {
+            match transfers.downloads.get(&id) {
+                Some(download) => {
+                    let _ = download.send(packet);
+                    None
+                }
+                None => Some(packet),
+            }
+        }
+        #[cfg(feature = "stbchat-e2e")]
+        ClientPacket::ApiResponse {
+            response_type,
+            response,
+        } => route_public_key(&mut transfers, response_type, response),
+        packet => Some(packet),
+    }
+}
+
+/// Pass a public key to the lookups waiting for it
+#[cfg(feature = "stbchat-e2e")]
+fn route_public_key(
+    transfers: &mut Transfers,
+    response_type: String,
+    response: StbchatApiResponse,
+) -> Option<ClientPacket> {
+    let (username, public_key) = match &response {
+        StbchatApiResponse::PublicKey {
+            username,
+            public_key,
+        } => (username, Some(public_key)),
+        StbchatApiResponse::NoPublicKey { username } => (username, None),
+        _ => {
+            return Some(ClientPacket::ApiResponse {
+                response_type,
+                response,
+            });
+        }
+    };
+
+    let Some(lookups) = transfers.key_lookups.remove(username) else {
+        return Some(ClientPacket::ApiResponse {
+            response_type,
+            response,
+        });
+    };
+
+    for lookup in lookups {
+        let _ = lookup.send(public_key.cloned());
+    }
+
+    None
+}
+
+/// Open a TCP connection with keepalive enabled
+async fn connect_tcp(addr: impl ToSocketAddrs) -> eyre::Result<TcpStream> {
+    let stream = TcpStream::connect(addr).await?;
+    let sock_ref = socket2::SockRef::from(&stream);
+
+    let ka = socket2::TcpKeepalive::new()
+        .with_time(KEEP_ALIVE_INTERVAL)
+        .with_interval(KEEP_ALIVE_INTERVAL);
+
+    sock_ref.set_tcp_keepalive(&ka)?;
+
+    Ok(stream)
+}
+
+/// Forward every packet from the server into the event stream.
+/// Capability negotiation packets are handled here and not forwarded
+async fn read_packets(
+    mut r_server: IncomingPacketStream<BoxedReader>,
+    writer: PacketQueue,
+    transfers: SharedTransfers,
+    negotiation: SharedNegotiation,
+    events: mpsc::Sender<ClientPacket>,
+    _connection: ConnectionGuard,
+) {
+    loop {
+        let packet = match r_server.read::<ClientPacket>().await {
+            Ok(packet) => packet,
+            Err(err) if err.is_decode() => continue,
+            Err(_) => break,
+        };
+
+        match packet {
+            ClientPacket::Capabilities {
+                compression,
+                formats,
+            } => {
+                if select_capabilities(&writer, &negotiation, &compression, &formats)
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+            #[cfg(feature = "stbchat-compression")]
+            ClientPacket::CapabilitiesSelected { compression, .. } => {
+                let selected = compression
+                    .as_deref()
+                    .and_then(CompressionAlgorithm::from_name);
+
+                r_server.set_compression(selected.map(Compression::new));
+            }
+            #[cfg(not(feature = "stbchat-compression"))]
+            ClientPacket::CapabilitiesSelected { .. } => {}
+            packet => {
+                let Some(packet) = route_transfer(&transfers, packet) else {
+                    continue;
+                };
+
+                if events.send(packet).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    // Dropping the waiting transfers tells them that the connection was closed
+    *transfers.lock().unwrap_or_else(PoisonError::into_inner) = Transfers::default();
+}
+
+/// Answer the capabilities of the server with the preferred format and compression.
+/// Switches the compression right after the answer, the format of packets sent afterwards
+#[cfg_attr(not(feature = "stbchat-compression"), allow(unused_variables))]
+async fn select_capabilities(
+    writer: &PacketQueue,
+    negotiation: &SharedNegotiation,
+    compression: &[String],
+    formats: &[String],
+) -> Result<(), CommunicationError> {
+    #[cfg(feature = "stbchat-compression")]
+    let selected = compression
+        .iter()
+        .find_map(|name| CompressionAlgorithm::from_name(name))
+        .map(Compression::new);
+
+    let (packet, format) = {
+        let mut negotiation = negotiation.lock().unwrap_or_else(PoisonError::into_inner);
+        negotiation.formats = Some(
+            formats
+                .iter()
+                .filter_map(|name| WireFormat::from_name(name))
+                .collect(),
+        );
+        #[cfg(feature = "stbchat-compression")]
+        {
+            negotiation.compression = selected;
+        }
+
+        negotiation.select()
+    };
+
+    #[cfg(feature = "stbchat-compression")]
+    writer.send_switching_compression(packet, selected).await?;
+    #[cfg(not(feature = "stbchat-compression"))]
+    writer.send(packet).await?;
+
+    writer.set_format(format);
+
+    Ok(())
+}
+
+/// Periodically send `KeepAlive` packets until writing fails
+async fn keep_alive(writer: PacketQueue) {
+    let mut interval = tokio::time::interval(KEEP_ALIVE_INTERVAL);
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        if writer.send(ServerPacket::KeepAlive).await.is_err() {
+            break;
+        }
+    }
+}
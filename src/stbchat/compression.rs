@@ -0,0 +1,265 @@
+#![cfg(feature = "stbchat-compression")]
+
+use std::io::{Read, Write};
+
+use flate2::Compression as DeflateLevel;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+
+use crate::stbchat::error::CompressionError;
+
+/// Frames smaller than this amount of bytes are sent uncompressed by default
+pub const DEFAULT_THRESHOLD: usize = 256;
+
+/// Upper limit for the size of a decompressed frame
+pub const MAX_DECOMPRESSED_SIZE: usize = 1024 * 1024;
+
+/// Flag of frames that are sent uncompressed
+const FLAG_RAW: u8 = 0;
+
+/// Compression algorithms for stbchat frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionAlgorithm {
+    Zstd,
+    Deflate,
+}
+
+impl CompressionAlgorithm {
+    /// All algorithms in order of preference
+    pub const ALL: [Self; 2] = [Self::Zstd, Self::Deflate];
+
+    /// Name of the algorithm used during capability negotiation
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Zstd => "zstd",
+            Self::Deflate => "deflate",
+        }
+    }
+
+    /// Parse the name of an algorithm
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|algorithm| algorithm.name() == name)
+    }
+
+    /// Flag byte that marks frames compressed with this algorithm
+    const fn flag(self) -> u8 {
+        match self {
+            Self::Zstd => 1,
+            Self::Deflate => 2,
+        }
+    }
+
+    const fn from_flag(flag: u8) -> Option<Self> {
+        match flag {
+            1 => Some(Self::Zstd),
+            2 => Some(Self::Deflate),
+            _ => None,
+        }
+    }
+
+    fn compress(self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Self::Zstd => zstd::bulk::compress(data, 0),
+            Self::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), DeflateLevel::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+
+    fn decompress(self, data: &[u8]) -> Result<Vec<u8>, CompressionError> {
+        let limit = MAX_DECOMPRESSED_SIZE as u64 + 1;
+        let mut decompressed = Vec::new();
+
+        match self {
+            Self::Zstd => {
+                zstd::Decoder::new(data)?
+                    .take(limit)
+                    .read_to_end(&mut decompressed)?;
+            }
+            Self::Deflate => {
+                DeflateDecoder::new(data)
+                    .take(limit)
+                    .read_to_end(&mut decompressed)?;
+            }
+        }
+
+        if decompressed.len() > MAX_DECOMPRESSED_SIZE {
+            return Err(CompressionError::TooLarge(MAX_DECOMPRESSED_SIZE));
+        }
+
+        Ok(decompressed)
+    }
+}
+
+/// # Per-frame compression settings
+/// Once enabled on a packet stream, every frame is prefixed with a flag byte telling
+/// whether (and how) the frame is compressed. Frames below `threshold` are left raw.
+/// Both peers have to enable compression at the same frame, which is why it is negotiated
+/// with `Capabilities` packets instead of being turned on directly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    pub algorithm: CompressionAlgorithm,
+    pub threshold: usize,
+}
+
+impl Compression {
+    #[must_use]
+    pub const fn new(algorithm: CompressionAlgorithm) -> Self {
+        Self {
+            algorithm,
+            threshold: DEFAULT_THRESHOLD,
+        }
+    }
+
+    #[must_use]
+    pub const fn threshold(mut self, threshold: usize) -> Self {
+        self.threshold = threshold;
+
+        self
+    }
+}
+
+/// Add the flag byte to a serialized packet, compressing it if worthwhile
+pub(crate) fn encode(
+    bytes: Vec<u8>,
+    compression: Option<&Compression>,
+) -> Result<Vec<u8>, CompressionError> {
+    let Some(compression) = compression else {
+        return Ok(bytes);
+    };
+
+    if bytes.len() >= compression.threshold {
+        let compressed = compression.algorithm.compress(&bytes)?;
+
+        if compressed.len() < bytes.len() {
+            let mut frame = Vec::with_capacity(compressed.len() + 1);
+            frame.push(compression.algorithm.flag());
+            frame.extend(compressed);
+            return Ok(frame);
+        }
+    }
+
+    let mut frame = Vec::with_capacity(bytes.len() + 1);
+    frame.push(FLAG_RAW);
+    frame.extend(bytes);
+    Ok(frame)
+}
+
+/// Strip the flag byte of a frame and decompress it if needed
+pub(crate) fn decode(
+    frame: Vec<u8>,
+    compression: Option<&Compression>,
+) -> Result<Vec<u8>, CompressionError> {
+    if compression.is_none() {
+        return Ok(frame);
+    }
+
+    let Some((&flag, body)) = frame.split_first() else {
+        return Err(CompressionError::MissingFlag);
+    };
+
+    if flag == FLAG_RAW {
+        return Ok(body.to_vec());
+    }
+
+    CompressionAlgorithm::from_flag(flag)
+        .ok_or(CompressionError::UnknownFlag(flag))?
+        .decompress(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn repetitive(len: usize) -> Vec<u8> {
+        b"strawberry ".iter().copied().cycle().take(len).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = repetitive(4096);
+
+        for algorithm in CompressionAlgorithm::ALL {
+            let compression = Compression::new(algorithm);
+            let frame = encode(data.clone(), Some(&compression)).unwrap();
+
+            assert_eq!(frame[0], algorithm.flag());
+            assert!(frame.len() < data.len());
+            assert_eq!(decode(frame, Some(&compression)).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn small_frames_stay_raw() {
+        let compression = Compression::new(CompressionAlgorithm::Zstd);
+        let data = repetitive(DEFAULT_THRESHOLD - 1);
+        let frame = encode(data.clone(), Some(&compression)).unwrap();
+
+        assert_eq!(frame[0], FLAG_RAW);
+        assert_eq!(frame[1..], data);
+        assert_eq!(decode(frame, Some(&compression)).unwrap(), data);
+    }
+
+    #[test]
+    fn incompressible_frames_stay_raw() {
+        let compression = Compression::new(CompressionAlgorithm::Deflate).threshold(0);
+        let data = vec![42];
+
+        assert_eq!(encode(data, Some(&compression)).unwrap(), [FLAG_RAW, 42]);
+    }
+
+    #[test]
+    fn frames_are_untouched_without_compression() {
+        let data = repetitive(4096);
+
+        assert_eq!(encode(data.clone(), None).unwrap(), data);
+        assert_eq!(decode(data.clone(), None).unwrap(), data);
+    }
+
+    #[test]
+    fn decoding_checks_the_flag() {
+        let compression = Compression::new(CompressionAlgorithm::Zstd);
+
+        assert!(matches!(
+            decode(Vec::new(), Some(&compression)),
+            Err(CompressionError::MissingFlag)
+        ));
+        assert!(matches!(
+            decode(vec![7, 1, 2, 3], Some(&compression)),
+            Err(CompressionError::UnknownFlag(7))
+        ));
+        // Frames of the other algorithm are accepted, the flag tells how to decompress them
+        let data = repetitive(4096);
+        let deflate = Compression::new(CompressionAlgorithm::Deflate);
+        let frame = encode(data.clone(), Some(&deflate)).unwrap();
+        assert_eq!(decode(frame, Some(&compression)).unwrap(), data);
+    }
+
+    #[test]
+    fn decompressed_size_is_limited() {
+        let compression = Compression::new(CompressionAlgorithm::Zstd);
+        let frame = encode(vec![0; MAX_DECOMPRESSED_SIZE + 1], Some(&compression)).unwrap();
+
+        assert!(matches!(
+            decode(frame, Some(&compression)),
+            Err(CompressionError::TooLarge(MAX_DECOMPRESSED_SIZE))
+        ));
+    }
+
+    #[test]
+    fn algorithms_are_parsed_by_name() {
+        for algorithm in CompressionAlgorithm::ALL {
+            assert_eq!(
+                CompressionAlgorithm::from_name(algorithm.name()),
+                Some(algorithm)
+            );
+        }
+        assert_eq!(CompressionAlgorithm::from_name("gzip"), None);
+    }
+}
@@ -0,0 +1,348 @@
+#![cfg(feature = "stbchat-e2e")]
+
+use std::fmt::{self, Debug, Formatter, Write as _};
+use std::fs::{self, OpenOptions};
+use std::io::Write as _;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
+use rand_core::OsRng;
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::stbchat::error::E2eError;
+use crate::stbchat::object::{EncryptedPayload, PUBLIC_KEY_LENGTH};
+
+/// Context of the key derivation, changes whenever the encryption scheme changes
+const KDF_INFO: &[u8] = b"stbchat-e2e-v1";
+
+/// Length of a secret key in bytes
+pub const SECRET_KEY_LENGTH: usize = 32;
+
+/// # Key pair for end-to-end encrypted direct messages
+/// Messages are encrypted with `XChaCha20-Poly1305` using a key derived from
+/// the X25519 key agreement of the sender and the recipient.
+/// The public key is published in the key directory of the server, the secret key never leaves the client.
+///
+/// The server could hand out its own keys, so users should compare the fingerprints
+/// of their keys through another channel before trusting a conversation
+/// ```no_run
+/// use libstrawberry::stbchat::e2e::KeyPair;
+///
+/// # fn example() -> Result<(), libstrawberry::stbchat::error::E2eError> {
+/// let alice = KeyPair::load_or_generate("alice.key")?;
+/// let bob = KeyPair::generate();
+///
+/// let payload = alice.encrypt(&bob.public_key(), "Hello Bob!")?;
+/// assert_eq!(bob.decrypt(&payload)?, "Hello Bob!");
+///
+/// println!("Alice's fingerprint: {}", alice.fingerprint());
+/// # Ok(())
+/// # }
+/// ```
+pub struct KeyPair {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl KeyPair {
+    /// Generate a new random key pair
+    #[must_use]
+    pub fn generate() -> Self {
+        let secret = StaticSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+
+        Self { secret, public }
+    }
+
+    /// Restore a key pair from its secret key
+    #[must_use]
+    pub fn from_secret(secret: [u8; SECRET_KEY_LENGTH]) -> Self {
+        let secret = StaticSecret::from(secret);
+        let public = PublicKey::from(&secret);
+
+        Self { secret, public }
+    }
+
+    /// Load the key pair stored at `path`, generating and storing a new one if the file doesn't exist.
+    /// On Unix the file is only readable by the current user
+    /// # Errors
+    /// - Will return `Err` if the file could not be read or written
+    /// - Will return `Err` if the file doesn't contain a secret key
+    pub fn load_or_generate(path: impl AsRef<Path>) -> Result<Self, E2eError> {
+        let path = path.as_ref();
+
+        if path.exists() {
+            let secret = fs::read(path)?
+                .try_into()
+                .map_err(|_| E2eError::InvalidKey)?;
+
+            return Ok(Self::from_secret(secret));
+        }
+
+        let keys = Self::generate();
+
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+
+        options.open(path)?.write_all(&keys.secret_key())?;
+
+        Ok(keys)
+    }
+
+    /// Secret key for storing the key pair. Keep it private
+    #[must_use]
+    pub fn secret_key(&self) -> [u8; SECRET_KEY_LENGTH] {
+        self.secret.to_bytes()
+    }
+
+    #[must_use]
+    pub fn public_key(&self) -> [u8; PUBLIC_KEY_LENGTH] {
+        self.public.to_bytes()
+    }
+
+    /// Fingerprint of the public key, see [`fingerprint`]
+    #[must_use]
+    pub fn fingerprint(&self) -> String {
+        fingerprint(self.public.as_bytes())
+    }
+
+    /// Encrypt a direct message for the owner of `recipient_key`
+    /// # Errors
+    /// - Will return `Err` if the recipient key is invalid
+    /// - Will return `Err` if the message could not be encrypted
+    pub fn encrypt(
+        &self,
+        recipient_key: &[u8],
+        message: &str,
+    ) -> Result<EncryptedPayload, E2eError> {
+        let recipient = parse_public_key(recipient_key)?;
+        let cipher = self.cipher(&recipient, &self.public, &recipient)?;
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+        let ciphertext = cipher
+            .encrypt(&nonce, message.as_bytes())
+            .map_err(|_| E2eError::Encrypt)?;
+
+        Ok(EncryptedPayload {
+            sender_key: self.public_key().to_vec(),
+            recipient_key: recipient.to_bytes().to_vec(),
+            nonce: nonce.to_vec(),
+            ciphertext,
+        })
+    }
+
+    /// Decrypt a direct message sent to this key pair.
+    /// Compare the fingerprint of the sender key with the known fingerprint of the author
+    /// to make sure the message wasn't sent by someone else
+    /// # Errors
+    /// - Will return `Err` if the message was encrypted for another key
+    /// - Will return `Err` if the message was tampered with
+    pub fn decrypt(&self, payload: &EncryptedPayload) -> Result<String, E2eError> {
+        if payload.recipient_key != self.public.as_bytes() {
+            return Err(E2eError::WrongRecipient);
+        }
+
+        let sender = parse_public_key(&payload.sender_key)?;
+        let cipher = self.cipher(&sender, &sender, &self.public)?;
+
+        if payload.nonce.len() != XNonce::default().len() {
+            return Err(E2eError::Decrypt);
+        }
+
+        let message = cipher
+            .decrypt(
+                XNonce::from_slice(&payload.nonce),
+                Payload::from(payload.ciphertext.as_slice()),
+            )
+            .map_err(|_| E2eError::Decrypt)?;
+
+        String::from_utf8(message).map_err(|_| E2eError::Decrypt)
+    }
+
+    /// Derive the cipher shared with `peer`. Binding the direction of the message
+    /// keeps messages from being reflected back to their sender
+    fn cipher(
+        &self,
+        peer: &PublicKey,
+        sender: &PublicKey,
+        recipient: &PublicKey,
+    ) -> Result<XChaCha20Poly1305, E2eError> {
+        let shared = self.secret.diffie_hellman(peer);
+
+        // Low order keys would result in a known shared secret
+        if !shared.was_contributory() {
+            return Err(E2eError::InvalidKey);
+        }
+
+        let mut info = KDF_INFO.to_vec();
+        info.extend_from_slice(sender.as_bytes());
+        info.extend_from_slice(recipient.as_bytes());
+
+        let mut key = [0; 32];
+        Hkdf::<Sha256>::new(None, shared.as_bytes())
+            .expand(&info, &mut key)
+            .map_err(|_| E2eError::Encrypt)?;
+
+        Ok(XChaCha20Poly1305::new(&key.into()))
+    }
+}
+
+impl Debug for KeyPair {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyPair")
+            .field("fingerprint", &self.fingerprint())
+            .finish_non_exhaustive()
+    }
+}
+
+impl EncryptedPayload {
+    /// Fingerprint of the key the message was sent with
+    #[must_use]
+    pub fn sender_fingerprint(&self) -> String {
+        fingerprint(&self.sender_key)
+    }
+}
+
+/// Human readable fingerprint of a public key for comparing keys through another channel
+///
+/// Consists of the first 16 bytes of its SHA-256 hash in groups of four hex digits,
+/// e.g. `3f2a 9c01 77be 0d45 e1a8 52c3 6b90 fe14`
+#[must_use]
+pub fn fingerprint(public_key: &[u8]) -> String {
+    let hash = Sha256::digest(public_key);
+    let mut fingerprint = String::with_capacity(39);
+
+    for (i, pair) in hash[..16].chunks(2).enumerate() {
+        if i > 0 {
+            fingerprint.push(' ');
+        }
+
+        let _ = write!(fingerprint, "{:02x}{:02x}", pair[0], pair[1]);
+    }
+
+    fingerprint
+}
+
+fn parse_public_key(bytes: &[u8]) -> Result<PublicKey, E2eError> {
+    let bytes: [u8; PUBLIC_KEY_LENGTH] = bytes.try_into().map_err(|_| E2eError::InvalidKey)?;
+
+    Ok(PublicKey::from(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let alice = KeyPair::generate();
+        let bob = KeyPair::generate();
+
+        let payload = alice.encrypt(&bob.public_key(), "Hello Bob!").unwrap();
+
+        assert_ne!(payload.ciphertext, b"Hello Bob!");
+        assert_eq!(payload.sender_fingerprint(), alice.fingerprint());
+        assert_eq!(bob.decrypt(&payload).unwrap(), "Hello Bob!");
+    }
+
+    #[test]
+    fn nonces_are_random() {
+        let alice = KeyPair::generate();
+        let bob = KeyPair::generate();
+
+        let first = alice.encrypt(&bob.public_key(), "Hello").unwrap();
+        let second = alice.encrypt(&bob.public_key(), "Hello").unwrap();
+
+        assert_ne!(first.nonce, second.nonce);
+        assert_ne!(first.ciphertext, second.ciphertext);
+    }
+
+    #[test]
+    fn other_keys_cant_decrypt() {
+        let alice = KeyPair::generate();
+        let bob = KeyPair::generate();
+        let mallory = KeyPair::generate();
+
+        let mut payload = alice.encrypt(&bob.public_key(), "Hello Bob!").unwrap();
+
+        assert!(matches!(
+            mallory.decrypt(&payload),
+            Err(E2eError::WrongRecipient)
+        ));
+        // Messages can't be reflected back to their sender
+        assert!(matches!(
+            alice.decrypt(&payload),
+            Err(E2eError::WrongRecipient)
+        ));
+
+        payload.recipient_key = mallory.public_key().to_vec();
+        assert!(matches!(mallory.decrypt(&payload), Err(E2eError::Decrypt)));
+    }
+
+    #[test]
+    fn tampered_messages_are_rejected() {
+        let alice = KeyPair::generate();
+        let bob = KeyPair::generate();
+        let payload = alice.encrypt(&bob.public_key(), "Hello Bob!").unwrap();
+
+        let mut nonce = payload.clone();
+        nonce.nonce[0] ^= 1;
+        assert!(matches!(bob.decrypt(&nonce), Err(E2eError::Decrypt)));
+
+        let mut short_nonce = payload.clone();
+        short_nonce.nonce.pop();
+        assert!(matches!(bob.decrypt(&short_nonce), Err(E2eError::Decrypt)));
+
+        let mut ciphertext = payload.clone();
+        ciphertext.ciphertext[0] ^= 1;
+        assert!(matches!(bob.decrypt(&ciphertext), Err(E2eError::Decrypt)));
+
+        let mut sender = payload;
+        sender.sender_key = KeyPair::generate().public_key().to_vec();
+        assert!(matches!(bob.decrypt(&sender), Err(E2eError::Decrypt)));
+    }
+
+    #[test]
+    fn invalid_keys_are_rejected() {
+        let alice = KeyPair::generate();
+
+        assert!(matches!(
+            alice.encrypt(&[1; 16], "Hello"),
+            Err(E2eError::InvalidKey)
+        ));
+        // Low order point with a known shared secret
+        assert!(matches!(
+            alice.encrypt(&[0; PUBLIC_KEY_LENGTH], "Hello"),
+            Err(E2eError::InvalidKey)
+        ));
+    }
+
+    #[test]
+    fn key_pairs_are_restored_from_their_secret() {
+        let keys = KeyPair::generate();
+        let restored = KeyPair::from_secret(keys.secret_key());
+
+        assert_eq!(restored.public_key(), keys.public_key());
+    }
+
+    #[test]
+    fn fingerprints_are_grouped_hex() {
+        let fingerprint = fingerprint(&[0; PUBLIC_KEY_LENGTH]);
+        let groups: Vec<&str> = fingerprint.split(' ').collect();
+
+        assert_eq!(fingerprint.len(), 39);
+        assert_eq!(groups.len(), 8);
+        assert!(
+            groups
+                .iter()
+                .all(|group| group.len() == 4 && group.chars().all(|c| c.is_ascii_hexdigit()))
+        );
+    }
+}
@@ -1,8 +1,205 @@
 use thiserror::Error;
 
-/// Error in case if the package size is too large
+use crate::stbchat::object::{AttachmentId, AuthFailure};
+
+/// Errors while sending or receiving packets
 #[derive(Error, Debug)]
 pub enum CommunicationError {
     #[error("Packet size too large, expected <=65535, got {0}")]
     PacketTooLarge(usize),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Connection closed by remote")]
+    ConnectionClosed,
+    #[error("Timed out while reading packet")]
+    Timeout,
+    #[error("Couldn't decode packet of {len} bytes: {source}")]
+    Decode {
+        len: usize,
+        #[source]
+        source: rmp_serde::decode::Error,
+    },
+    #[error("Couldn't encode packet: {0}")]
+    Encode(#[from] rmp_serde::encode::Error),
+    #[cfg(feature = "stbchat-json")]
+    #[error("Couldn't decode JSON packet of {len} bytes: {source}")]
+    DecodeJson {
+        len: usize,
+        #[source]
+        source: serde_json::Error,
+    },
+    #[cfg(feature = "stbchat-json")]
+    #[error("Couldn't encode JSON packet: {0}")]
+    EncodeJson(#[source] serde_json::Error),
+    #[cfg(feature = "stbchat-compression")]
+    #[error(transparent)]
+    Compression(#[from] CompressionError),
+}
+
+impl CommunicationError {
+    /// Returns `true` if a single packet could not be decoded. The stream stays usable in this case
+    #[must_use]
+    pub const fn is_decode(&self) -> bool {
+        match self {
+            Self::Decode { .. } => true,
+            #[cfg(feature = "stbchat-json")]
+            Self::DecodeJson { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+/// Errors while setting up a TLS connection
+#[cfg(feature = "stbchat-tls")]
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("no valid certificate found")]
+    MissingCertificate,
+    #[error("no valid private key found")]
+    MissingPrivateKey,
+    #[error("invalid certificate fingerprint '{0}', expected 32 hex encoded bytes")]
+    InvalidFingerprint(String),
+    #[error("invalid server name '{0}'")]
+    InvalidServerName(String),
+}
+
+/// Errors while compressing or decompressing frames
+#[cfg(feature = "stbchat-compression")]
+#[derive(Error, Debug)]
+pub enum CompressionError {
+    #[error("unknown compression flag {0}")]
+    UnknownFlag(u8),
+    #[error("frame is missing the compression flag")]
+    MissingFlag,
+    #[error("decompressed frame too large, expected <={0}")]
+    TooLarge(usize),
+    #[error("couldn't (de)compress frame: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Errors while uploading or downloading attachments
+#[derive(Error, Debug)]
+pub enum AttachmentError {
+    #[error("File too large, expected <={max}, got {size}")]
+    TooLarge { size: u64, max: u64 },
+    #[error("Upload rejected by server: {0}")]
+    Rejected(String),
+    #[error("Attachment {0} is not available")]
+    Unavailable(AttachmentId),
+    #[error("Received data doesn't match the hash of the attachment")]
+    HashMismatch,
+    #[error("Received chunk at offset {got}, expected {expected}")]
+    UnexpectedOffset { expected: u64, got: u64 },
+    #[error("Timed out while waiting for the server")]
+    Timeout,
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Communication(#[from] CommunicationError),
+}
+
+/// Errors while sending or handling extension packets
+#[derive(Error, Debug)]
+pub enum ExtensionError {
+    #[error("Invalid extension type '{0}', expected 'namespace:name'")]
+    InvalidType(String),
+    #[error("Couldn't encode payload of extension '{extension_type}': {source}")]
+    Encode {
+        extension_type: String,
+        #[source]
+        source: rmp_serde::encode::Error,
+    },
+    #[error("Couldn't decode payload of extension '{extension_type}': {source}")]
+    Decode {
+        extension_type: String,
+        #[source]
+        source: rmp_serde::decode::Error,
+    },
+    #[error(transparent)]
+    Communication(#[from] CommunicationError),
+}
+
+/// Errors while encrypting, decrypting or exchanging end-to-end encrypted direct messages
+#[cfg(feature = "stbchat-e2e")]
+#[derive(Error, Debug)]
+pub enum E2eError {
+    #[error(
+        "Invalid public key, expected {} bytes",
+        crate::stbchat::object::PUBLIC_KEY_LENGTH
+    )]
+    InvalidKey,
+    #[error("User '{0}' didn't publish a public key")]
+    UnknownKey(String),
+    #[error("Message was encrypted for another key")]
+    WrongRecipient,
+    #[error("Couldn't encrypt message")]
+    Encrypt,
+    #[error("Couldn't decrypt message, it was tampered with or encrypted with another key")]
+    Decrypt,
+    #[error("Timed out while waiting for the public key of '{0}'")]
+    Timeout(String),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Communication(#[from] CommunicationError),
+}
+
+/// Reasons why a username is not accepted by the server
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum UsernameError {
+    #[error("Username must be at least {0} characters long")]
+    TooShort(usize),
+    #[error("Username must be at most {0} characters long")]
+    TooLong(usize),
+    #[error("Username must start with a letter or digit")]
+    InvalidStart,
+    #[error("Username contains the invalid character '{0}'")]
+    InvalidCharacter(char),
+    #[error("This username is reserved")]
+    Reserved,
+}
+
+/// Errors while registering, authenticating or changing accounts
+#[derive(Error, Debug)]
+pub enum AccountError {
+    #[error("Invalid username or password")]
+    InvalidCredentials,
+    #[error("Invalid Strawberry ID credentials")]
+    InvalidToken,
+    #[error("This server doesn't support logging in with Strawberry ID")]
+    TokenLoginUnsupported,
+    #[error("This username is already taken")]
+    UsernameTaken,
+    #[error("User '{0}' doesn't exist")]
+    UnknownUser(String),
+    #[error(transparent)]
+    InvalidUsername(#[from] UsernameError),
+    #[error("Password must be at least {0} characters long")]
+    WeakPassword(usize),
+    #[error("Invalid role color '{0}'")]
+    InvalidRoleColor(String),
+    #[error("Invalid badge '{0}'")]
+    InvalidBadge(String),
+    #[error("Couldn't hash password: {0}")]
+    Hash(String),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+impl AccountError {
+    /// Returns the failure reported to the client
+    #[must_use]
+    pub const fn failure(&self) -> AuthFailure {
+        match self {
+            Self::InvalidCredentials | Self::InvalidToken | Self::UnknownUser(_) => {
+                AuthFailure::InvalidCredentials
+            }
+            Self::TokenLoginUnsupported => AuthFailure::Unsupported,
+            Self::UsernameTaken => AuthFailure::UsernameTaken,
+            Self::InvalidUsername(_) => AuthFailure::InvalidUsername,
+            Self::WeakPassword(_) => AuthFailure::WeakPassword,
+            Self::InvalidRoleColor(_) => AuthFailure::InvalidRoleColor,
+            Self::InvalidBadge(_) | Self::Hash(_) | Self::Io(_) => AuthFailure::ServerError,
+        }
+    }
 }
@@ -0,0 +1,265 @@
+#[cfg(not(feature = "stbchat-sync"))]
+use std::collections::HashMap;
+#[cfg(not(feature = "stbchat-sync"))]
+use std::future::Future;
+#[cfg(not(feature = "stbchat-sync"))]
+use std::pin::Pin;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::stbchat::error::ExtensionError;
+use crate::stbchat::object::ExtensionTarget;
+use crate::stbchat::packet::{ClientPacket, ServerPacket};
+#[cfg(not(feature = "stbchat-sync"))]
+use crate::stbchat::queue::PacketQueue;
+
+/// Maximum length of an extension type in bytes
+pub const MAX_EXTENSION_TYPE_LENGTH: usize = 64;
+
+/// # Application-defined extension packet
+/// Custom features like polls or games define their own packets without changing the protocol.
+/// The server relays extension packets to a room or user without looking at their payload,
+/// which is encoded using `MessagePack`.
+///
+/// Types are namespaced as `namespace:name`, e.g. `polls:vote`, so extensions of
+/// different applications don't collide
+/// ```
+/// use serde::{Deserialize, Serialize};
+///
+/// use libstrawberry::stbchat::extension::Extension;
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct Vote {
+///     poll: u64,
+///     option: usize,
+/// }
+///
+/// impl Extension for Vote {
+///     const TYPE: &'static str = "polls:vote";
+/// }
+/// ```
+pub trait Extension: Serialize + DeserializeOwned {
+    /// Namespaced type of the extension
+    const TYPE: &'static str;
+}
+
+/// Check whether an extension type consists of a namespace and a name separated by a colon.
+/// Both may only contain ASCII letters, digits, `-`, `_` and `.`
+#[must_use]
+pub fn is_valid_type(extension_type: &str) -> bool {
+    let is_valid_part = |part: &str| {
+        !part.is_empty()
+            && part
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    };
+
+    extension_type.len() <= MAX_EXTENSION_TYPE_LENGTH
+        && extension_type
+            .split_once(':')
+            .is_some_and(|(namespace, name)| is_valid_part(namespace) && is_valid_part(name))
+}
+
+/// Build a `ServerPacket` sending an extension to a room or user
+/// # Errors
+/// - Will return `Err` if the type of the extension is invalid
+/// - Will return `Err` if the payload could not be encoded
+pub fn packet<E: Extension>(
+    target: ExtensionTarget,
+    extension: &E,
+) -> Result<ServerPacket, ExtensionError> {
+    if !is_valid_type(E::TYPE) {
+        return Err(ExtensionError::InvalidType(E::TYPE.to_string()));
+    }
+
+    let payload = rmp_serde::to_vec_named(extension).map_err(|source| ExtensionError::Encode {
+        extension_type: E::TYPE.to_string(),
+        source,
+    })?;
+
+    Ok(ServerPacket::Extension {
+        extension_type: E::TYPE.to_string(),
+        target,
+        payload,
+    })
+}
+
+/// A received extension packet with its decoded payload
+#[derive(Debug, Clone)]
+pub struct ExtensionMessage<E> {
+    /// Username of the sender
+    pub sender: String,
+    pub target: ExtensionTarget,
+    pub payload: E,
+}
+
+impl<E: Extension> ExtensionMessage<E> {
+    /// Decode a received packet. Returns `Ok(None)` if the packet is no extension of type `E`
+    /// # Errors
+    /// - Will return `Err` if the payload doesn't match `E`
+    pub fn decode(packet: &ClientPacket) -> Result<Option<Self>, ExtensionError> {
+        let ClientPacket::Extension {
+            extension_type,
+            sender,
+            target,
+            payload,
+        } = packet
+        else {
+            return Ok(None);
+        };
+
+        if extension_type != E::TYPE {
+            return Ok(None);
+        }
+
+        let payload = decode_payload(extension_type, payload)?;
+
+        Ok(Some(Self {
+            sender: sender.clone(),
+            target: target.clone(),
+            payload,
+        }))
+    }
+}
+
+fn decode_payload<E: Extension>(extension_type: &str, payload: &[u8]) -> Result<E, ExtensionError> {
+    rmp_serde::from_slice(payload).map_err(|source| ExtensionError::Decode {
+        extension_type: extension_type.to_string(),
+        source,
+    })
+}
+
+#[cfg(not(feature = "stbchat-sync"))]
+type HandlerFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+#[cfg(not(feature = "stbchat-sync"))]
+type Handler = Box<
+    dyn Fn(String, ExtensionTarget, &[u8], PacketQueue) -> Result<HandlerFuture, ExtensionError>
+        + Send
+        + Sync,
+>;
+
+/// # Registry of typed extension handlers
+/// Decodes the payload of received extension packets into the registered type and runs its handler.
+/// Handlers get the packet queue of the connection the packet was received on, so they can answer
+/// ```no_run
+/// use serde::{Deserialize, Serialize};
+///
+/// use libstrawberry::stbchat::client::StbchatClient;
+/// use libstrawberry::stbchat::extension::{self, Extension, Extensions};
+/// use libstrawberry::stbchat::object::ExtensionTarget;
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct Vote {
+///     poll: u64,
+///     option: usize,
+/// }
+///
+/// impl Extension for Vote {
+///     const TYPE: &'static str = "polls:vote";
+/// }
+///
+/// # async fn example() -> eyre::Result<()> {
+/// let extensions = Extensions::new().register(|vote: extension::ExtensionMessage<Vote>, _writer| async move {
+///     println!("{} voted for option {}", vote.sender, vote.payload.option);
+/// });
+///
+/// let mut client = StbchatClient::connect("127.0.0.1:52800").await?;
+/// client.login("alice", "password").await?;
+///
+/// let target = ExtensionTarget::Room { room: "general".to_string() };
+/// client.send_extension(target, &Vote { poll: 1, option: 0 }).await?;
+///
+/// while let Some(packet) = client.recv().await {
+///     if let Some(packet) = extensions.handle(packet, &client.writer()).await? {
+///         println!("{packet:?}");
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(not(feature = "stbchat-sync"))]
+#[derive(Default)]
+pub struct Extensions {
+    handlers: HashMap<String, Handler>,
+}
+
+#[cfg(not(feature = "stbchat-sync"))]
+impl Extensions {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handle extensions of type `E`, replacing any handler registered for the same type
+    /// # Panics
+    /// - Will panic if the type of the extension is invalid
+    #[must_use]
+    pub fn register<E, F, Fut>(mut self, handler: F) -> Self
+    where
+        E: Extension + Send + 'static,
+        F: Fn(ExtensionMessage<E>, PacketQueue) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        assert!(
+            is_valid_type(E::TYPE),
+            "invalid extension type '{}', expected 'namespace:name'",
+            E::TYPE
+        );
+
+        let handler: Handler = Box::new(move |sender, target, payload, writer| {
+            let payload = decode_payload(E::TYPE, payload)?;
+            let message = ExtensionMessage {
+                sender,
+                target,
+                payload,
+            };
+
+            Ok(Box::pin(handler(message, writer)) as HandlerFuture)
+        });
+
+        self.handlers.insert(E::TYPE.to_string(), handler);
+
+        self
+    }
+
+    /// Check whether a handler is registered for an extension type
+    #[must_use]
+    pub fn is_registered(&self, extension_type: &str) -> bool {
+        self.handlers.contains_key(extension_type)
+    }
+
+    /// Run the handler of a received extension packet.
+    /// Returns the packet if it is no extension or no handler is registered for its type
+    /// # Errors
+    /// - Will return `Err` if the payload doesn't match the registered type
+    pub async fn handle(
+        &self,
+        packet: ClientPacket,
+        writer: &PacketQueue,
+    ) -> Result<Option<ClientPacket>, ExtensionError> {
+        let ClientPacket::Extension {
+            extension_type,
+            sender,
+            target,
+            payload,
+        } = packet
+        else {
+            return Ok(Some(packet));
+        };
+
+        let Some(handler) = self.handlers.get(&extension_type) else {
+            return Ok(Some(ClientPacket::Extension {
+                extension_type,
+                sender,
+                target,
+                payload,
+            }));
+        };
+
+        handler(sender, target, &payload, writer.clone())?.await;
+
+        Ok(None)
+    }
+}
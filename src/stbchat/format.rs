@@ -0,0 +1,251 @@
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::stbchat::error::CommunicationError;
+
+/// # Serialization formats of stbchat packets
+/// The outgoing format is chosen per connection, incoming frames are decoded
+/// in the format they were serialized in. Peers switch to another format by selecting
+/// one of the formats announced in the `Capabilities` packet of the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum WireFormat {
+    /// Compact binary format understood by every peer
+    #[default]
+    MessagePack,
+    /// Human readable format, e.g. for inspecting traffic or integrations without a `MessagePack` library.
+    /// Binary fields like attachment chunks are base64 encoded
+    #[cfg(feature = "stbchat-json")]
+    Json,
+}
+
+impl WireFormat {
+    /// All supported formats in order of preference
+    pub const ALL: &[Self] = &[
+        Self::MessagePack,
+        #[cfg(feature = "stbchat-json")]
+        Self::Json,
+    ];
+
+    /// Name of the format used during capability negotiation
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::MessagePack => "msgpack",
+            #[cfg(feature = "stbchat-json")]
+            Self::Json => "json",
+        }
+    }
+
+    /// Parse the name of a format
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|format| format.name() == name)
+    }
+
+    /// Detect the format of a serialized packet.
+    /// `MessagePack` packets are arrays, so only JSON packets start with `{`
+    #[must_use]
+    #[cfg_attr(
+        not(feature = "stbchat-json"),
+        allow(unused_variables, clippy::missing_const_for_fn)
+    )]
+    pub fn detect(bytes: &[u8]) -> Self {
+        #[cfg(feature = "stbchat-json")]
+        if bytes.iter().find(|byte| !byte.is_ascii_whitespace()) == Some(&b'{') {
+            return Self::Json;
+        }
+
+        Self::MessagePack
+    }
+
+    /// Serialize a packet
+    /// # Errors
+    /// - Will return `Err` if the packet could not be serialized
+    pub fn serialize<P: Serialize + ?Sized>(
+        self,
+        packet: &P,
+    ) -> Result<Vec<u8>, CommunicationError> {
+        match self {
+            Self::MessagePack => Ok(rmp_serde::to_vec(packet)?),
+            #[cfg(feature = "stbchat-json")]
+            Self::Json => serde_json::to_vec(packet).map_err(CommunicationError::EncodeJson),
+        }
+    }
+
+    /// Deserialize a packet
+    /// # Errors
+    /// - Will return `Decode` if the packet could not be deserialized
+    pub fn deserialize<P: DeserializeOwned>(self, bytes: &[u8]) -> Result<P, CommunicationError> {
+        match self {
+            Self::MessagePack => {
+                rmp_serde::from_slice(bytes).map_err(|source| CommunicationError::Decode {
+                    len: bytes.len(),
+                    source,
+                })
+            }
+            #[cfg(feature = "stbchat-json")]
+            Self::Json => {
+                serde_json::from_slice(bytes).map_err(|source| CommunicationError::DecodeJson {
+                    len: bytes.len(),
+                    source,
+                })
+            }
+        }
+    }
+}
+
+/// Deserialize a packet in the format it was serialized in
+/// # Errors
+/// - Will return `Decode` if the packet could not be deserialized
+pub fn decode<P: DeserializeOwned>(bytes: &[u8]) -> Result<P, CommunicationError> {
+    WireFormat::detect(bytes).deserialize(bytes)
+}
+
+/// Serde helpers for binary packet fields.
+/// Uses native bytes in `MessagePack` and base64 strings in human readable formats
+pub(crate) mod bytes {
+    use std::fmt;
+
+    use serde::de::{SeqAccess, Visitor};
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        #[cfg(feature = "stbchat-json")]
+        if serializer.is_human_readable() {
+            use base64::Engine;
+
+            let encoded = base64::engine::general_purpose::STANDARD.encode(bytes);
+            return serializer.serialize_str(&encoded);
+        }
+
+        serde_bytes::serialize(bytes, serializer)
+    }
+
+    /// Accepts both representations, since fields of internally tagged packets are buffered
+    /// before they are deserialized and the buffer always claims to be human readable
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        deserializer.deserialize_any(BytesVisitor)
+    }
+
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("bytes or a base64 encoded string")
+        }
+
+        fn visit_bytes<E: serde::de::Error>(self, bytes: &[u8]) -> Result<Self::Value, E> {
+            Ok(bytes.to_vec())
+        }
+
+        fn visit_byte_buf<E: serde::de::Error>(self, bytes: Vec<u8>) -> Result<Self::Value, E> {
+            Ok(bytes)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or_default());
+
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+
+            Ok(bytes)
+        }
+
+        #[cfg(feature = "stbchat-json")]
+        fn visit_str<E: serde::de::Error>(self, encoded: &str) -> Result<Self::Value, E> {
+            use base64::Engine;
+
+            base64::engine::general_purpose::STANDARD
+                .decode(encoded)
+                .map_err(E::custom)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stbchat::packet::ServerPacket;
+
+    fn chunk() -> ServerPacket {
+        ServerPacket::AttachmentChunk {
+            id: 7,
+            offset: 1024,
+            data: b"{strawberry}".to_vec(),
+        }
+    }
+
+    fn is_chunk(packet: &ServerPacket) -> bool {
+        matches!(
+            packet,
+            ServerPacket::AttachmentChunk { id: 7, offset: 1024, data } if data == b"{strawberry}"
+        )
+    }
+
+    #[test]
+    fn message_pack_is_detected() {
+        let bytes = WireFormat::MessagePack.serialize(&chunk()).unwrap();
+
+        assert_eq!(WireFormat::detect(&bytes), WireFormat::MessagePack);
+        assert!(is_chunk(&decode(&bytes).unwrap()));
+    }
+
+    #[test]
+    fn message_pack_keeps_bytes_native() {
+        let bytes = WireFormat::MessagePack.serialize(&chunk()).unwrap();
+
+        assert!(bytes.windows(12).any(|window| window == b"{strawberry}"));
+    }
+
+    #[cfg(feature = "stbchat-json")]
+    #[test]
+    fn json_is_detected() {
+        let bytes = WireFormat::Json.serialize(&chunk()).unwrap();
+
+        assert_eq!(WireFormat::detect(&bytes), WireFormat::Json);
+        assert!(is_chunk(&decode(&bytes).unwrap()));
+    }
+
+    #[cfg(feature = "stbchat-json")]
+    #[test]
+    fn json_may_start_with_whitespace() {
+        let bytes = b" \n{\"packet_type\": \"KeepAlive\"}";
+
+        assert_eq!(WireFormat::detect(bytes), WireFormat::Json);
+        assert!(matches!(decode(bytes), Ok(ServerPacket::KeepAlive)));
+    }
+
+    #[cfg(feature = "stbchat-json")]
+    #[test]
+    fn json_encodes_bytes_as_base64() {
+        let bytes = WireFormat::Json.serialize(&chunk()).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+
+        assert_eq!(json["data"], "e3N0cmF3YmVycnl9");
+    }
+
+    #[test]
+    fn everything_else_is_message_pack() {
+        assert_eq!(WireFormat::detect(b""), WireFormat::MessagePack);
+        assert_eq!(WireFormat::detect(b"   "), WireFormat::MessagePack);
+        assert_eq!(
+            WireFormat::detect(&[0x92, 0x01, 0x02]),
+            WireFormat::MessagePack
+        );
+        assert!(decode::<ServerPacket>(&[0xc1]).is_err());
+    }
+
+    #[test]
+    fn formats_are_parsed_by_name() {
+        for &format in WireFormat::ALL {
+            assert_eq!(WireFormat::from_name(format.name()), Some(format));
+        }
+        assert_eq!(WireFormat::from_name("xml"), None);
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+use crate::stbchat::object::{MessageId, MessageUpdate, User};
+use crate::stbchat::packet::ClientPacket;
+
+/// Amount of messages a `MessageStore` keeps by default
+pub const DEFAULT_STORE_CAPACITY: usize = 1000;
+
+/// A chat message including all updates applied to it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub id: MessageId,
+    pub author: User,
+    pub message: String,
+    pub room: String,
+    pub reply_to: Option<MessageId>,
+    pub edited: bool,
+
+    /// Usernames that reacted, grouped by emoji
+    pub reactions: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl ChatMessage {
+    /// Apply an edit or reaction to the message. Deletions have to be handled by the owner of the message.
+    /// Returns `false` if the update doesn't belong to this message or didn't change it
+    pub fn apply(&mut self, update: &MessageUpdate) -> bool {
+        if update.id() != self.id {
+            return false;
+        }
+
+        match update {
+            MessageUpdate::Edited { message, .. } => {
+                self.message.clone_from(message);
+                self.edited = true;
+                true
+            }
+            MessageUpdate::ReactionAdded {
+                emoji, username, ..
+            } => self
+                .reactions
+                .entry(emoji.clone())
+                .or_default()
+                .insert(username.clone()),
+            MessageUpdate::ReactionRemoved {
+                emoji, username, ..
+            } => {
+                let Some(users) = self.reactions.get_mut(emoji) else {
+                    return false;
+                };
+                let removed = users.remove(username);
+
+                if users.is_empty() {
+                    self.reactions.remove(emoji);
+                }
+
+                removed
+            }
+            MessageUpdate::Deleted { .. } => false,
+        }
+    }
+
+    /// Whether a user reacted to the message with the given emoji
+    #[must_use]
+    pub fn has_reaction(&self, emoji: &str, username: &str) -> bool {
+        self.reactions
+            .get(emoji)
+            .is_some_and(|users| users.contains(username))
+    }
+}
+
+/// # Local message store for clients
+/// Keeps the most recent messages and applies `MessageUpdate` events to them
+/// ```
+/// use libstrawberry::stbchat::messages::MessageStore;
+/// use libstrawberry::stbchat::object::{MessageUpdate, User};
+/// use libstrawberry::stbchat::packet::ClientPacket;
+///
+/// let mut store = MessageStore::new();
+///
+/// store.apply(&ClientPacket::UserMessage {
+///     author: User::default(),
+///     message: String::from("Hello"),
+///     room: String::from("general"),
+///     id: 1,
+///     reply_to: None,
+/// });
+///
+/// store.apply(&ClientPacket::MessageUpdate {
+///     room: String::from("general"),
+///     update: MessageUpdate::Edited { id: 1, message: String::from("Hello World") },
+/// });
+///
+/// assert_eq!(store.get(1).unwrap().message, "Hello World");
+/// ```
+pub struct MessageStore {
+    messages: BTreeMap<MessageId, ChatMessage>,
+    order: VecDeque<MessageId>,
+    capacity: usize,
+}
+
+impl Default for MessageStore {
+    fn default() -> Self {
+        Self::with_capacity(DEFAULT_STORE_CAPACITY)
+    }
+}
+
+impl MessageStore {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a store that keeps at most `capacity` messages
+    #[must_use]
+    pub const fn with_capacity(capacity: usize) -> Self {
+        Self {
+            messages: BTreeMap::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    /// Apply a packet to the store. Returns `true` if the store changed.
+    /// Only `UserMessage` packets with an id, `MessageUpdate` and `History` packets are relevant
+    pub fn apply(&mut self, packet: &ClientPacket) -> bool {
+        match packet {
+            ClientPacket::UserMessage {
+                author,
+                message,
+                room,
+                id,
+                reply_to,
+            } if *id != 0 => {
+                self.insert(ChatMessage {
+                    id: *id,
+                    author: author.clone(),
+                    message: message.clone(),
+                    room: room.clone(),
+                    reply_to: *reply_to,
+                    edited: false,
+                    reactions: BTreeMap::new(),
+                });
+                true
+            }
+            ClientPacket::MessageUpdate { update, .. } => self.apply_update(update),
+            ClientPacket::History { messages, .. } => {
+                for message in messages {
+                    self.insert(message.clone());
+                }
+                !messages.is_empty()
+            }
+            _ => false,
+        }
+    }
+
+    /// Apply a single update. Returns `false` if the message is unknown or didn't change
+    pub fn apply_update(&mut self, update: &MessageUpdate) -> bool {
+        if let MessageUpdate::Deleted { id } = update {
+            self.order.retain(|other| other != id);
+            return self.messages.remove(id).is_some();
+        }
+
+        self.messages
+            .get_mut(&update.id())
+            .is_some_and(|stored| stored.apply(update))
+    }
+
+    /// Insert a message, evicting the oldest one if the store is full
+    pub fn insert(&mut self, message: ChatMessage) {
+        let id = message.id;
+
+        if self.messages.insert(id, message).is_none() {
+            self.order.push_back(id);
+        }
+
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.messages.remove(&oldest);
+            }
+        }
+    }
+
+    #[must_use]
+    pub fn get(&self, id: MessageId) -> Option<&ChatMessage> {
+        self.messages.get(&id)
+    }
+
+    /// All stored messages of a room, oldest first
+    pub fn room<'a>(&'a self, room: &'a str) -> impl Iterator<Item = &'a ChatMessage> + 'a {
+        self.messages
+            .values()
+            .filter(move |message| message.room == room)
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+}
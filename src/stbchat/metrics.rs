@@ -0,0 +1,461 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Instant;
+
+#[cfg(not(feature = "stbchat-sync"))]
+use std::net::Ipv4Addr;
+#[cfg(not(feature = "stbchat-sync"))]
+use std::time::Duration;
+#[cfg(not(feature = "stbchat-sync"))]
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+#[cfg(not(feature = "stbchat-sync"))]
+use tokio::net::{TcpListener, TcpStream};
+
+use serde::Deserialize;
+
+use crate::stbchat::capture::Direction;
+use crate::stbchat::format;
+
+/// Upper bounds in seconds of the connection lifetime histogram
+pub const LIFETIME_BUCKETS: [f64; 8] = [1.0, 10.0, 60.0, 300.0, 1800.0, 3600.0, 21600.0, 86400.0];
+
+/// Packet type of frames which could not be decoded
+const INVALID_PACKET_TYPE: &str = "invalid";
+
+/// Maximum size of an HTTP request to the Prometheus endpoint
+#[cfg(not(feature = "stbchat-sync"))]
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+/// Time a client of the Prometheus endpoint has to send its request
+#[cfg(not(feature = "stbchat-sync"))]
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Default, Clone, Copy)]
+struct Counter {
+    packets: u64,
+    bytes: u64,
+}
+
+/// # Metrics of Strawberry Chat connections
+/// Counts packets and bytes per direction and packet type, decode errors, timeouts
+/// and the lifetime of connections. Can be attached to packet streams, servers and clients
+/// and shared between all of them.
+/// ```no_run
+/// use std::sync::Arc;
+///
+/// use libstrawberry::stbchat::metrics::Metrics;
+/// use libstrawberry::stbchat::server::StbchatServer;
+///
+/// # async fn example() -> eyre::Result<()> {
+/// let metrics = Arc::new(Metrics::new());
+///
+/// // Scrape http://127.0.0.1:9100/metrics
+/// tokio::spawn(metrics.clone().listen_prometheus(9100));
+///
+/// StbchatServer::new()
+///     .metrics(metrics)
+///     .listen("0.0.0.0:52800")
+///     .await
+/// # }
+/// ```
+#[derive(Default)]
+pub struct Metrics {
+    packets: Mutex<HashMap<(Direction, String), Counter>>,
+    decode_errors: AtomicU64,
+    timeouts: AtomicU64,
+    connections_opened: AtomicU64,
+    lifetimes: Mutex<LifetimeHistogram>,
+}
+
+impl Metrics {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count a frame sent or received with a packet body (before compression)
+    /// and its size on the wire
+    pub fn record_packet(&self, direction: Direction, body: &[u8], wire_size: usize) {
+        self.record(direction, packet_type(body), wire_size);
+    }
+
+    /// Count a received frame which could not be decoded
+    pub fn record_decode_error(&self, wire_size: usize) {
+        self.decode_errors.fetch_add(1, Ordering::Relaxed);
+        self.record(
+            Direction::Incoming,
+            INVALID_PACKET_TYPE.to_string(),
+            wire_size,
+        );
+    }
+
+    /// Count a packet body which did not arrive in time
+    pub fn record_timeout(&self) {
+        self.timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a new connection, its lifetime is recorded once the returned guard is dropped
+    #[must_use]
+    pub fn track_connection(self: &Arc<Self>) -> ConnectionGuard {
+        self.connections_opened.fetch_add(1, Ordering::Relaxed);
+
+        ConnectionGuard {
+            metrics: self.clone(),
+            opened: Instant::now(),
+        }
+    }
+
+    /// Returns a copy of all counters
+    #[must_use]
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let mut packets: Vec<PacketMetrics> = self
+            .packets
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|((direction, packet_type), counter)| PacketMetrics {
+                direction: *direction,
+                packet_type: packet_type.clone(),
+                packets: counter.packets,
+                bytes: counter.bytes,
+            })
+            .collect();
+
+        packets.sort_by(|a, b| {
+            direction_label(a.direction)
+                .cmp(direction_label(b.direction))
+                .then_with(|| a.packet_type.cmp(&b.packet_type))
+        });
+
+        MetricsSnapshot {
+            packets,
+            decode_errors: self.decode_errors.load(Ordering::Relaxed),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+            connections_opened: self.connections_opened.load(Ordering::Relaxed),
+            connection_lifetime: self
+                .lifetimes
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clone(),
+        }
+    }
+
+    /// Bind to the given port on localhost and serve the metrics in the Prometheus text format
+    /// # Errors
+    /// - Will return `Err` if the port could not be bound
+    /// - Will return `Err` if accepting a connection fails
+    #[cfg(not(feature = "stbchat-sync"))]
+    pub async fn listen_prometheus(self: Arc<Self>, port: u16) -> eyre::Result<()> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port)).await?;
+        self.serve_prometheus(listener).await
+    }
+
+    /// Serve the metrics in the Prometheus text format on an already bound listener.
+    /// Answers `GET /metrics`, every other request is rejected
+    /// # Errors
+    /// - Will return `Err` if accepting a connection fails
+    #[cfg(not(feature = "stbchat-sync"))]
+    pub async fn serve_prometheus(self: Arc<Self>, listener: TcpListener) -> eyre::Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+
+            tokio::spawn(answer_scrape(self.clone(), stream));
+        }
+    }
+
+    pub(crate) fn record(&self, direction: Direction, packet_type: String, wire_size: usize) {
+        let mut packets = self.packets.lock().unwrap_or_else(PoisonError::into_inner);
+        let counter = packets.entry((direction, packet_type)).or_default();
+
+        counter.packets += 1;
+        counter.bytes += wire_size as u64;
+        drop(packets);
+    }
+}
+
+/// Counts as an open connection until dropped
+pub struct ConnectionGuard {
+    metrics: Arc<Metrics>,
+    opened: Instant,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let lifetime = self.opened.elapsed().as_secs_f64();
+
+        self.metrics
+            .lifetimes
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .observe(lifetime);
+    }
+}
+
+/// Packets and bytes of a packet type in one direction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PacketMetrics {
+    pub direction: Direction,
+    /// Value of the `packet_type` field, `invalid` for frames which could not be decoded
+    pub packet_type: String,
+    pub packets: u64,
+    /// Size of the frames on the wire, including length prefixes
+    pub bytes: u64,
+}
+
+/// Histogram of connection lifetimes in seconds
+#[derive(Debug, Clone, PartialEq)]
+pub struct LifetimeHistogram {
+    /// Amount of connections per bucket of `LIFETIME_BUCKETS`, not cumulative
+    pub buckets: [u64; LIFETIME_BUCKETS.len()],
+    /// Amount of connections longer than the largest bucket
+    pub overflow: u64,
+    pub sum: f64,
+    pub count: u64,
+}
+
+impl Default for LifetimeHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: [0; LIFETIME_BUCKETS.len()],
+            overflow: 0,
+            sum: 0.0,
+            count: 0,
+        }
+    }
+}
+
+impl LifetimeHistogram {
+    fn observe(&mut self, seconds: f64) {
+        match LIFETIME_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            Some(bucket) => self.buckets[bucket] += 1,
+            None => self.overflow += 1,
+        }
+
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+/// Copy of all metrics at one point in time
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricsSnapshot {
+    /// Sorted by direction and packet type
+    pub packets: Vec<PacketMetrics>,
+    pub decode_errors: u64,
+    pub timeouts: u64,
+    pub connections_opened: u64,
+    /// Lifetimes of all closed connections
+    pub connection_lifetime: LifetimeHistogram,
+}
+
+impl MetricsSnapshot {
+    /// Returns the amount of currently open connections
+    #[must_use]
+    pub const fn connections_active(&self) -> u64 {
+        self.connections_opened
+            .saturating_sub(self.connection_lifetime.count)
+    }
+
+    /// Returns the total amount of packets and bytes in a direction
+    #[must_use]
+    pub fn total(&self, direction: Direction) -> (u64, u64) {
+        self.packets
+            .iter()
+            .filter(|metrics| metrics.direction == direction)
+            .fold((0, 0), |(packets, bytes), metrics| {
+                (packets + metrics.packets, bytes + metrics.bytes)
+            })
+    }
+
+    /// Render the snapshot in the Prometheus text exposition format
+    #[must_use]
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "stbchat_packets_total",
+            "counter",
+            "Packets by direction and type",
+        );
+        for metrics in &self.packets {
+            sample_by_type(&mut out, "stbchat_packets_total", metrics, metrics.packets);
+        }
+
+        header(
+            &mut out,
+            "stbchat_bytes_total",
+            "counter",
+            "Bytes on the wire by direction and packet type",
+        );
+        for metrics in &self.packets {
+            sample_by_type(&mut out, "stbchat_bytes_total", metrics, metrics.bytes);
+        }
+
+        header(
+            &mut out,
+            "stbchat_decode_errors_total",
+            "counter",
+            "Received frames which could not be decoded",
+        );
+        let _ = writeln!(out, "stbchat_decode_errors_total {}", self.decode_errors);
+
+        header(
+            &mut out,
+            "stbchat_timeouts_total",
+            "counter",
+            "Packet bodies which did not arrive in time",
+        );
+        let _ = writeln!(out, "stbchat_timeouts_total {}", self.timeouts);
+
+        header(
+            &mut out,
+            "stbchat_connections_opened_total",
+            "counter",
+            "Opened connections",
+        );
+        let _ = writeln!(
+            out,
+            "stbchat_connections_opened_total {}",
+            self.connections_opened
+        );
+
+        header(
+            &mut out,
+            "stbchat_connections_active",
+            "gauge",
+            "Currently open connections",
+        );
+        let _ = writeln!(
+            out,
+            "stbchat_connections_active {}",
+            self.connections_active()
+        );
+
+        let histogram = &self.connection_lifetime;
+        header(
+            &mut out,
+            "stbchat_connection_lifetime_seconds",
+            "histogram",
+            "Lifetime of closed connections",
+        );
+        let mut cumulative = 0;
+        for (bound, count) in LIFETIME_BUCKETS.iter().zip(histogram.buckets) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "stbchat_connection_lifetime_seconds_bucket{{le=\"{bound}\"}} {cumulative}"
+            );
+        }
+        let _ = writeln!(
+            out,
+            "stbchat_connection_lifetime_seconds_bucket{{le=\"+Inf\"}} {}",
+            histogram.count
+        );
+        let _ = writeln!(
+            out,
+            "stbchat_connection_lifetime_seconds_sum {}",
+            histogram.sum
+        );
+        let _ = writeln!(
+            out,
+            "stbchat_connection_lifetime_seconds_count {}",
+            histogram.count
+        );
+
+        out
+    }
+}
+
+#[derive(Deserialize)]
+struct PacketType {
+    packet_type: String,
+}
+
+/// Read the `packet_type` field of a packet body without decoding the whole packet
+pub(crate) fn packet_type(body: &[u8]) -> String {
+    format::decode::<PacketType>(body).map_or_else(
+        |_| INVALID_PACKET_TYPE.to_string(),
+        |packet| packet.packet_type,
+    )
+}
+
+const fn direction_label(direction: Direction) -> &'static str {
+    match direction {
+        Direction::Incoming => "incoming",
+        Direction::Outgoing => "outgoing",
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn sample_by_type(out: &mut String, name: &str, metrics: &PacketMetrics, value: u64) {
+    let _ = writeln!(
+        out,
+        "{name}{{direction=\"{}\",packet_type=\"{}\"}} {value}",
+        direction_label(metrics.direction),
+        escape_label(&metrics.packet_type)
+    );
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Answer a single HTTP request of a Prometheus scraper
+#[cfg(not(feature = "stbchat-sync"))]
+async fn answer_scrape(metrics: Arc<Metrics>, mut stream: TcpStream) {
+    let request_line = tokio::time::timeout(REQUEST_TIMEOUT, read_request_line(&mut stream)).await;
+    let Some(request_line) = request_line.ok().and_then(Result::ok).flatten() else {
+        return;
+    };
+
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = metrics.snapshot().to_prometheus();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+        }
+        (Some("GET"), _) => {
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+        }
+        _ => "HTTP/1.1 405 Method Not Allowed\r\nAllow: GET\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            .to_string(),
+    };
+
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+/// Read the request headers and return the request line
+#[cfg(not(feature = "stbchat-sync"))]
+async fn read_request_line(stream: &mut TcpStream) -> std::io::Result<Option<String>> {
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        if request.len() > MAX_REQUEST_SIZE {
+            return Ok(None);
+        }
+
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            return Ok(None);
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    Ok(request.lines().next().map(ToString::to_string))
+}
@@ -1,8 +1,30 @@
 #![cfg(feature = "stbchat")]
 
+pub mod attachment;
+pub mod capture;
+pub mod client;
+pub mod compression;
+pub mod e2e;
 pub mod error;
+pub mod extension;
+pub mod format;
+pub mod messages;
+pub mod metrics;
 pub mod net;
 pub mod object;
 pub mod packet;
+pub mod queue;
+pub mod role_color;
+pub mod server;
+pub mod tls;
+pub mod transcript;
+pub mod transport;
+pub mod websocket;
 
-pub const PROTOCOL_VERSION: &str = "3";
+/// Version of the Strawberry Chat protocol.
+///
+/// Compatibility with version 3 is one-way: packets of version 3 peers are accepted,
+/// with fields added since (e.g. `room`, `id`, `reply_to` and `User::presence`) taking their defaults.
+/// `MessagePack` packets are encoded positionally, so version 3 peers reject packets
+/// carrying these trailing fields with an "invalid length" error
+pub const PROTOCOL_VERSION: &str = "4";
@@ -1,7 +1,9 @@
 #![allow(clippy::future_not_send)]
 
+use std::io;
 #[cfg(feature = "stbchat-sync")]
 use std::io::{Read, Write};
+use std::sync::Arc;
 
 #[cfg(not(feature = "stbchat-sync"))]
 use std::time::Duration;
@@ -11,62 +13,143 @@ use tokio::{
     time::timeout,
 };
 
-use eyre::bail;
 use serde::Serialize;
 use serde::de::DeserializeOwned;
 
-use crate::stbchat::error;
+use crate::stbchat::capture::{CaptureTap, Direction};
+#[cfg(feature = "stbchat-compression")]
+use crate::stbchat::compression::{self, Compression};
+use crate::stbchat::error::CommunicationError;
+use crate::stbchat::format::{self, WireFormat};
+use crate::stbchat::metrics::{self, Metrics};
+
+/// Time the body of a frame may take to arrive on top of its transfer time
+#[cfg(not(feature = "stbchat-sync"))]
+const PAYLOAD_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// Slowest transfer rate in bytes per second accepted for the body of a frame
+#[cfg(not(feature = "stbchat-sync"))]
+const MIN_PAYLOAD_RATE: u64 = 16 * 1024;
+
+/// Time the body of a frame of `len` bytes may take to arrive, large frames take longer on slow links
+#[cfg(not(feature = "stbchat-sync"))]
+fn payload_timeout(len: u16) -> Duration {
+    PAYLOAD_TIMEOUT + Duration::from_millis(u64::from(len) * 1000 / MIN_PAYLOAD_RATE)
+}
 
 /// Async Package Stream for outgoing packages
 #[cfg(not(feature = "stbchat-sync"))]
 pub struct OutgoingPacketStream<S: AsyncWriteExt + Unpin> {
     stream: S,
+    #[cfg(feature = "stbchat-compression")]
+    compression: Option<Compression>,
+    format: WireFormat,
+    tap: Option<CaptureTap>,
+    metrics: Option<Arc<Metrics>>,
 }
 
 /// Sync Package Stream for outgoing packages
 #[cfg(feature = "stbchat-sync")]
 pub struct OutgoingPacketStream<S: Write + Unpin> {
     stream: S,
+    #[cfg(feature = "stbchat-compression")]
+    compression: Option<Compression>,
+    format: WireFormat,
+    tap: Option<CaptureTap>,
+    metrics: Option<Arc<Metrics>>,
 }
 
 #[cfg(not(feature = "stbchat-sync"))]
 impl<W: AsyncWriteExt + Unpin> OutgoingPacketStream<W> {
     /// Wrap an existing async stream into the OutgoingPacketStream
     pub const fn wrap(stream: W) -> Self {
-        Self { stream }
+        Self {
+            stream,
+            #[cfg(feature = "stbchat-compression")]
+            compression: None,
+            format: WireFormat::MessagePack,
+            tap: None,
+            metrics: None,
+        }
+    }
+
+    /// Enable or disable per-frame compression.
+    /// Has to be switched at the same frame on both peers
+    #[cfg(feature = "stbchat-compression")]
+    pub const fn set_compression(&mut self, compression: Option<Compression>) {
+        self.compression = compression;
+    }
+
+    /// Serialize packets in the given format.
+    /// The remote has to support the format, e.g. after selecting it during capability negotiation
+    pub const fn set_format(&mut self, format: WireFormat) {
+        self.format = format;
+    }
+
+    /// Returns the format packets are serialized in
+    pub const fn format(&self) -> WireFormat {
+        self.format
+    }
+
+    /// Record every frame into a capture
+    pub fn set_tap(&mut self, tap: Option<CaptureTap>) {
+        self.tap = tap;
+    }
+
+    /// Count every frame in the given metrics
+    pub fn set_metrics(&mut self, metrics: Option<Arc<Metrics>>) {
+        self.metrics = metrics;
     }
 
     /// Write a packet to the stream
     /// # Errors
+    /// - Will return `Err` if the packet could not be serialized
     /// - Will return `Err` if packet size is too large
-    ///
-    /// # Panics
-    /// - Will panic if something gone wrong
-    pub async fn write<P: Serialize>(&mut self, packet: P) -> eyre::Result<()> {
-        let bytes = rmp_serde::to_vec(&packet)?;
-        let len = bytes.len();
-
-        let Ok(len) = u16::try_from(len) else {
-            bail!(error::CommunicationError::PacketTooLarge(len))
-        };
-
-        let mut packet = vec![];
+    /// - Will return `Err` if writing to the stream fails
+    pub async fn write<P: Serialize>(&mut self, packet: P) -> Result<(), CommunicationError> {
+        let mut batch = Vec::new();
+        self.encode_frame(self.format.serialize(&packet)?, &mut batch)?;
+        self.write_batch(&batch).await?;
 
-        packet.write_u16(len).await.unwrap();
-        packet.extend(bytes);
+        Ok(())
+    }
 
-        self.stream.write_all(&packet).await?;
+    /// Compress and frame a serialized packet and append it to a batch of frames
+    pub(crate) fn encode_frame(
+        &self,
+        bytes: Vec<u8>,
+        batch: &mut Vec<u8>,
+    ) -> Result<(), CommunicationError> {
+        if let Some(tap) = &self.tap {
+            tap.record(Direction::Outgoing, &bytes);
+        }
+        let packet_type = self.metrics.as_ref().map(|_| metrics::packet_type(&bytes));
+        #[cfg(feature = "stbchat-compression")]
+        let bytes = compression::encode(bytes, self.compression.as_ref())?;
+        let frame = frame(bytes)?;
+
+        if let Some((metrics, packet_type)) = self.metrics.as_ref().zip(packet_type) {
+            metrics.record(Direction::Outgoing, packet_type, frame.len());
+        }
+        batch.extend(frame);
 
         Ok(())
     }
 
+    /// Write a batch of frames at once
+    pub(crate) async fn write_batch(&mut self, batch: &[u8]) -> io::Result<()> {
+        self.stream.write_all(batch).await?;
+        // Message based transports (e.g. WebSocket) only send complete frames on flush
+        self.stream.flush().await
+    }
+
     /// Returns the wrapped streams
     pub fn unwrap(self) -> W {
         self.stream
     }
 
     /// Returns the wrapped stream as a mutable
-    pub fn inner_mut(&mut self) -> &mut W {
+    pub const fn inner_mut(&mut self) -> &mut W {
         &mut self.stream
     }
 }
@@ -75,29 +158,64 @@ impl<W: AsyncWriteExt + Unpin> OutgoingPacketStream<W> {
 impl<W: Write + Unpin> OutgoingPacketStream<W> {
     /// Wrap an existing sync stream into the OutgoingPacketStream
     pub const fn wrap(stream: W) -> Self {
-        Self { stream }
+        Self {
+            stream,
+            #[cfg(feature = "stbchat-compression")]
+            compression: None,
+            format: WireFormat::MessagePack,
+            tap: None,
+            metrics: None,
+        }
     }
 
-    /// Write a packet to the stream
-    /// # Errors
-    /// - Will return `Err` if packet size is too large
-    ///
-    /// # Panics
-    /// - Will panic if something gone wrong
-    pub fn write<P: Serialize>(&mut self, packet: P) -> eyre::Result<()> {
-        let bytes = rmp_serde::to_vec(&packet)?;
-        let len = bytes.len();
-
-        let Ok(len) = u16::try_from(len) else {
-            bail!(error::CommunicationError::PacketTooLarge(len))
-        };
+    /// Enable or disable per-frame compression.
+    /// Has to be switched at the same frame on both peers
+    #[cfg(feature = "stbchat-compression")]
+    pub const fn set_compression(&mut self, compression: Option<Compression>) {
+        self.compression = compression;
+    }
 
-        let mut packet = vec![];
+    /// Serialize packets in the given format.
+    /// The remote has to support the format, e.g. after selecting it during capability negotiation
+    pub const fn set_format(&mut self, format: WireFormat) {
+        self.format = format;
+    }
 
-        packet.extend_from_slice(&len.to_be_bytes());
-        packet.extend(bytes);
+    /// Returns the format packets are serialized in
+    pub const fn format(&self) -> WireFormat {
+        self.format
+    }
 
-        self.stream.write_all(&packet)?;
+    /// Record every frame into a capture
+    pub fn set_tap(&mut self, tap: Option<CaptureTap>) {
+        self.tap = tap;
+    }
+
+    /// Count every frame in the given metrics
+    pub fn set_metrics(&mut self, metrics: Option<Arc<Metrics>>) {
+        self.metrics = metrics;
+    }
+
+    /// Write a packet to the stream
+    /// # Errors
+    /// - Will return `Err` if the packet could not be serialized
+    /// - Will return `Err` if packet size is too large
+    /// - Will return `Err` if writing to the stream fails
+    pub fn write<P: Serialize>(&mut self, packet: P) -> Result<(), CommunicationError> {
+        let bytes = self.format.serialize(&packet)?;
+        if let Some(tap) = &self.tap {
+            tap.record(Direction::Outgoing, &bytes);
+        }
+        let packet_type = self.metrics.as_ref().map(|_| metrics::packet_type(&bytes));
+        #[cfg(feature = "stbchat-compression")]
+        let bytes = compression::encode(bytes, self.compression.as_ref())?;
+        let frame = frame(bytes)?;
+
+        self.stream.write_all(&frame)?;
+
+        if let Some((metrics, packet_type)) = self.metrics.as_ref().zip(packet_type) {
+            metrics.record(Direction::Outgoing, packet_type, frame.len());
+        }
 
         Ok(())
     }
@@ -117,34 +235,94 @@ impl<W: Write + Unpin> OutgoingPacketStream<W> {
 #[cfg(not(feature = "stbchat-sync"))]
 pub struct IncomingPacketStream<R: AsyncReadExt + Unpin> {
     stream: R,
+    #[cfg(feature = "stbchat-compression")]
+    compression: Option<Compression>,
+    tap: Option<CaptureTap>,
+    metrics: Option<Arc<Metrics>>,
+    bytes_read: u64,
 }
 
 /// Sync Package Stream for incoming packages
 #[cfg(feature = "stbchat-sync")]
 pub struct IncomingPacketStream<R: Read + Unpin> {
     stream: R,
+    #[cfg(feature = "stbchat-compression")]
+    compression: Option<Compression>,
+    tap: Option<CaptureTap>,
+    metrics: Option<Arc<Metrics>>,
+    bytes_read: u64,
 }
 
 #[cfg(not(feature = "stbchat-sync"))]
 impl<R: AsyncReadExt + Unpin> IncomingPacketStream<R> {
     /// Wrap an existing async stream into the IncomingPacketStream
     pub const fn wrap(stream: R) -> Self {
-        Self { stream }
+        Self {
+            stream,
+            #[cfg(feature = "stbchat-compression")]
+            compression: None,
+            tap: None,
+            metrics: None,
+            bytes_read: 0,
+        }
+    }
+
+    /// Enable or disable per-frame compression.
+    /// Has to be switched at the same frame on both peers
+    #[cfg(feature = "stbchat-compression")]
+    pub const fn set_compression(&mut self, compression: Option<Compression>) {
+        self.compression = compression;
+    }
+
+    /// Record every frame into a capture
+    pub fn set_tap(&mut self, tap: Option<CaptureTap>) {
+        self.tap = tap;
+    }
+
+    /// Count every frame in the given metrics
+    pub fn set_metrics(&mut self, metrics: Option<Arc<Metrics>>) {
+        self.metrics = metrics;
+    }
+
+    /// Total amount of bytes read from the stream, including length prefixes
+    pub const fn bytes_read(&self) -> u64 {
+        self.bytes_read
     }
 
     /// # IncomingPacketStream (Async)
     /// Read packet(s) from remote clients
     /// # Errors
-    /// - Will error when timeout is reached
-    pub async fn read<P: DeserializeOwned>(&mut self) -> eyre::Result<P> {
-        let len = self.stream.read_u16().await?;
+    /// - Will return `ConnectionClosed` if the remote closed the connection
+    /// - Will return `Timeout` when the packet body does not arrive in time.
+    ///   Frames may take 50 ms plus their size at 16 KiB/s to arrive
+    /// - Will return `Decode` if the packet could not be deserialized.
+    ///   The stream stays usable in this case. Packets are decoded in the format they were serialized in
+    pub async fn read<P: DeserializeOwned>(&mut self) -> Result<P, CommunicationError> {
+        let len = self.stream.read_u16().await.map_err(closed_on_eof)?;
         let mut buffer = vec![0; len as usize];
-        timeout(
-            Duration::from_millis(50),
-            self.stream.read_exact(&mut buffer),
-        )
-        .await??;
-        Ok(rmp_serde::from_read(buffer.as_slice())?)
+        let Ok(read) = timeout(payload_timeout(len), self.stream.read_exact(&mut buffer)).await
+        else {
+            if let Some(metrics) = &self.metrics {
+                metrics.record_timeout();
+            }
+            return Err(CommunicationError::Timeout);
+        };
+        read?;
+        self.bytes_read += 2 + u64::from(len);
+        #[cfg(feature = "stbchat-compression")]
+        let buffer = compression::decode(buffer, self.compression.as_ref())?;
+        if let Some(tap) = &self.tap {
+            tap.record(Direction::Incoming, &buffer);
+        }
+        let packet = format::decode(&buffer);
+        if let Some(metrics) = &self.metrics {
+            let wire_size = 2 + usize::from(len);
+            match &packet {
+                Ok(_) => metrics.record_packet(Direction::Incoming, &buffer, wire_size),
+                Err(_) => metrics.record_decode_error(wire_size),
+            }
+        }
+        packet
     }
 
     /// Returns the wrapped streams
@@ -157,22 +335,70 @@ impl<R: AsyncReadExt + Unpin> IncomingPacketStream<R> {
 impl<R: Read + Unpin> IncomingPacketStream<R> {
     /// Wrap an existing sync stream into the IncomingPacketStream
     pub const fn wrap(stream: R) -> Self {
-        Self { stream }
+        Self {
+            stream,
+            #[cfg(feature = "stbchat-compression")]
+            compression: None,
+            tap: None,
+            metrics: None,
+            bytes_read: 0,
+        }
+    }
+
+    /// Enable or disable per-frame compression.
+    /// Has to be switched at the same frame on both peers
+    #[cfg(feature = "stbchat-compression")]
+    pub const fn set_compression(&mut self, compression: Option<Compression>) {
+        self.compression = compression;
+    }
+
+    /// Record every frame into a capture
+    pub fn set_tap(&mut self, tap: Option<CaptureTap>) {
+        self.tap = tap;
+    }
+
+    /// Count every frame in the given metrics
+    pub fn set_metrics(&mut self, metrics: Option<Arc<Metrics>>) {
+        self.metrics = metrics;
+    }
+
+    /// Total amount of bytes read from the stream, including length prefixes
+    pub const fn bytes_read(&self) -> u64 {
+        self.bytes_read
     }
 
     /// # IncomingPacketStream (Sync)
     /// Read packet(s) from remote clients
     /// # Errors
-    /// - Will error if reading from stream fails
-    pub fn read<P: DeserializeOwned>(&mut self) -> eyre::Result<P> {
+    /// - Will return `ConnectionClosed` if the remote closed the connection
+    /// - Will return `Io` if reading from stream fails
+    /// - Will return `Decode` if the packet could not be deserialized.
+    ///   The stream stays usable in this case. Packets are decoded in the format they were serialized in
+    pub fn read<P: DeserializeOwned>(&mut self) -> Result<P, CommunicationError> {
         let mut len_buf = [0u8; 2];
-        self.stream.read_exact(&mut len_buf)?;
+        self.stream
+            .read_exact(&mut len_buf)
+            .map_err(closed_on_eof)?;
 
         let len = u16::from_be_bytes(len_buf);
         let mut buffer = vec![0; len as usize];
 
         self.stream.read_exact(&mut buffer)?;
-        Ok(rmp_serde::from_read(buffer.as_slice())?)
+        self.bytes_read += 2 + u64::from(len);
+        #[cfg(feature = "stbchat-compression")]
+        let buffer = compression::decode(buffer, self.compression.as_ref())?;
+        if let Some(tap) = &self.tap {
+            tap.record(Direction::Incoming, &buffer);
+        }
+        let packet = format::decode(&buffer);
+        if let Some(metrics) = &self.metrics {
+            let wire_size = 2 + usize::from(len);
+            match &packet {
+                Ok(_) => metrics.record_packet(Direction::Incoming, &buffer, wire_size),
+                Err(_) => metrics.record_decode_error(wire_size),
+            }
+        }
+        packet
     }
 
     /// Returns the wrapped streams
@@ -180,3 +406,28 @@ impl<R: Read + Unpin> IncomingPacketStream<R> {
         self.stream
     }
 }
+
+/// Prefix a serialized packet with its length
+fn frame(bytes: Vec<u8>) -> Result<Vec<u8>, CommunicationError> {
+    let len = bytes.len();
+
+    let Ok(len) = u16::try_from(len) else {
+        return Err(CommunicationError::PacketTooLarge(len));
+    };
+
+    let mut frame = Vec::with_capacity(bytes.len() + 2);
+
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend(bytes);
+
+    Ok(frame)
+}
+
+/// A clean EOF at a frame boundary means the remote closed the connection
+fn closed_on_eof(err: io::Error) -> CommunicationError {
+    if err.kind() == io::ErrorKind::UnexpectedEof {
+        CommunicationError::ConnectionClosed
+    } else {
+        CommunicationError::Io(err)
+    }
+}
@@ -1,5 +1,16 @@
 use serde::{Deserialize, Serialize};
 
+use crate::stbchat::role_color::RoleColor;
+use crate::string::{badge_handler, strip_control_characters};
+
+/// Room every user joins after logging in. Packets of version 3 peers,
+/// which don't know about rooms, are treated as belonging to this room
+pub const DEFAULT_ROOM: &str = "general";
+
+pub(crate) fn default_room() -> String {
+    DEFAULT_ROOM.to_string()
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct User {
     pub username: String,
@@ -7,6 +18,73 @@ pub struct User {
     pub badge: String,
     pub role_color: String,
     pub avatar_url: String,
+    #[serde(default)]
+    pub presence: Presence,
+}
+
+impl User {
+    /// Parsed role color, `None` if the role color is no recognized color
+    #[must_use]
+    pub fn color(&self) -> Option<RoleColor> {
+        RoleColor::parse(&self.role_color)
+    }
+
+    /// Name of the user for terminal output: the nickname, the username in the role color and the badge,
+    /// e.g. `Julian (@julian) [dev]`. The nickname is left out if it equals the username.
+    /// Control characters are removed and unrecognized role colors are ignored
+    #[must_use]
+    pub fn display_name(&self) -> String {
+        self.format_name(str::to_string, |username| match self.color() {
+            Some(color) => color.paint(username),
+            None => username,
+        })
+    }
+
+    /// Name of the user like `display_name`, shared by all clients and transcripts.
+    /// `escape` is applied to every part after control characters were removed,
+    /// `paint` styles the escaped username
+    pub(crate) fn format_name(
+        &self,
+        escape: impl Fn(&str) -> String,
+        paint: impl Fn(String) -> String,
+    ) -> String {
+        let username = strip_control_characters(&self.username);
+        let nickname = strip_control_characters(&self.nickname);
+        let badge =
+            badge_handler(&escape(&strip_control_characters(&self.badge))).unwrap_or_default();
+
+        if nickname.is_empty() || nickname == username {
+            format!("{}{badge}", paint(escape(&username)))
+        } else {
+            format!(
+                "{} ({}){badge}",
+                escape(&nickname),
+                paint(escape(&format!("@{username}")))
+            )
+        }
+    }
+}
+
+/// Availability of a user
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum PresenceStatus {
+    #[default]
+    #[serde(rename = "online")]
+    Online,
+    #[serde(rename = "away")]
+    Away,
+    #[serde(rename = "do_not_disturb")]
+    DoNotDisturb,
+    #[serde(rename = "offline")]
+    Offline,
+}
+
+/// Presence of a user, consisting of the availability and an optional custom status text
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Presence {
+    pub status: PresenceStatus,
+    #[serde(default)]
+    pub status_text: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -14,6 +92,126 @@ pub struct UserMeta {
     pub username: String,
 }
 
+/// Unique id of a chat message, assigned by the server.
+/// Messages of version 3 peers don't carry an id and use `0`
+pub type MessageId = u64;
+
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Room {
+    pub name: String,
+    pub members: Vec<String>,
+}
+
+/// Delivery feedback for direct messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeliveryStatus {
+    #[serde(rename = "delivered")]
+    Delivered,
+    #[serde(rename = "user_offline")]
+    UserOffline,
+}
+
+/// Reason why a `Login` or `Register` packet was rejected.
+/// The connection stays open, so the client can try again
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AuthFailure {
+    #[serde(rename = "invalid_credentials")]
+    InvalidCredentials,
+    #[serde(rename = "username_taken")]
+    UsernameTaken,
+    #[serde(rename = "invalid_username")]
+    InvalidUsername,
+    #[serde(rename = "weak_password")]
+    WeakPassword,
+    #[serde(rename = "invalid_role_color")]
+    InvalidRoleColor,
+    /// The server doesn't support the requested login method
+    #[serde(rename = "unsupported")]
+    Unsupported,
+    /// The account could not be stored or checked
+    #[serde(rename = "server_error")]
+    ServerError,
+}
+
+/// Unique id of an attachment, assigned by the server once the upload has been offered
+pub type AttachmentId = u64;
+
+/// Metadata of a file shared in a room
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attachment {
+    pub id: AttachmentId,
+    pub name: String,
+    pub size: u64,
+    pub mime_type: String,
+    /// Hex encoded SHA-256 hash of the file
+    pub hash: String,
+}
+
+/// Progress of an attachment upload
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "upload_status")]
+pub enum UploadStatus {
+    /// The server accepts chunks starting at `offset`. Uploads are resumed if the same file was offered before
+    #[serde(rename = "ready")]
+    Ready { id: AttachmentId, offset: u64 },
+    /// All chunks were received and the hash matched
+    #[serde(rename = "complete")]
+    Complete { id: AttachmentId },
+    #[serde(rename = "failed")]
+    Failed { reason: String },
+}
+
+/// Selects a page of the message history of a room
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "query_type")]
+pub enum HistoryQuery {
+    /// The most recent messages
+    #[default]
+    #[serde(rename = "latest")]
+    Latest,
+    /// Messages sent before the given message
+    #[serde(rename = "before")]
+    Before { id: MessageId },
+    /// Messages sent after the given message
+    #[serde(rename = "after")]
+    After { id: MessageId },
+}
+
+/// Change of an already sent message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "update_type")]
+pub enum MessageUpdate {
+    #[serde(rename = "edited")]
+    Edited { id: MessageId, message: String },
+    #[serde(rename = "deleted")]
+    Deleted { id: MessageId },
+    #[serde(rename = "reaction_added")]
+    ReactionAdded {
+        id: MessageId,
+        emoji: String,
+        username: String,
+    },
+    #[serde(rename = "reaction_removed")]
+    ReactionRemoved {
+        id: MessageId,
+        emoji: String,
+        username: String,
+    },
+}
+
+impl MessageUpdate {
+    /// Id of the updated message
+    #[must_use]
+    pub const fn id(&self) -> MessageId {
+        match self {
+            Self::Edited { id, .. }
+            | Self::Deleted { id }
+            | Self::ReactionAdded { id, .. }
+            | Self::ReactionRemoved { id, .. } => *id,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "api_response")]
 pub enum StbchatApiResponse {
@@ -23,9 +221,72 @@ pub enum StbchatApiResponse {
         nickname: String,
         role_color: String,
         badge: String,
+        #[serde(default = "default_room")]
+        room: String,
     },
     #[serde(rename = "user_left")]
-    UserLeft { username: String },
+    UserLeft {
+        username: String,
+        #[serde(default = "default_room")]
+        room: String,
+    },
     #[serde(rename = "user_data")]
     UserData { data: User },
+    /// Public key of a user in the key directory
+    #[serde(rename = "public_key")]
+    PublicKey {
+        username: String,
+        #[serde(with = "crate::stbchat::format::bytes")]
+        public_key: Vec<u8>,
+    },
+    /// The user didn't publish a public key yet
+    #[serde(rename = "no_public_key")]
+    NoPublicKey { username: String },
+}
+
+/// Typed requests of an `ApiRequest` packet
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "api_request")]
+pub enum StbchatApiRequest {
+    /// Publish the own public key for end-to-end encrypted direct messages,
+    /// replacing a previously published key
+    #[serde(rename = "publish_key")]
+    PublishKey {
+        #[serde(with = "crate::stbchat::format::bytes")]
+        public_key: Vec<u8>,
+    },
+    /// Look up the public key of a user
+    #[serde(rename = "public_key")]
+    PublicKey { username: String },
+}
+
+/// Length of a public key used for end-to-end encryption in bytes
+pub const PUBLIC_KEY_LENGTH: usize = 32;
+
+/// # End-to-end encrypted direct message
+/// Only the sender and the recipient can decrypt the ciphertext, the server relays it as is
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EncryptedPayload {
+    /// Public key of the sender
+    #[serde(with = "crate::stbchat::format::bytes")]
+    pub sender_key: Vec<u8>,
+    /// Public key of the recipient the message was encrypted for
+    #[serde(with = "crate::stbchat::format::bytes")]
+    pub recipient_key: Vec<u8>,
+    #[serde(with = "crate::stbchat::format::bytes")]
+    pub nonce: Vec<u8>,
+    #[serde(with = "crate::stbchat::format::bytes")]
+    pub ciphertext: Vec<u8>,
+}
+
+/// Receivers of an extension packet
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "target_type")]
+pub enum ExtensionTarget {
+    /// Every member of a room, including the sender
+    #[serde(rename = "room")]
+    Room { room: String },
+    /// Every connection of a single user
+    #[serde(rename = "user")]
+    User { username: String },
 }
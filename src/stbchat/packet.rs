@@ -1,20 +1,89 @@
 #![allow(clippy::future_not_send, clippy::needless_pass_by_value)]
 
-use crate::stbchat::object::{StbchatApiResponse, User, UserMeta};
+use crate::stbchat::messages::ChatMessage;
+use crate::stbchat::object::{
+    Attachment, AttachmentId, AuthFailure, DeliveryStatus, EncryptedPayload, ExtensionTarget,
+    HistoryQuery, MessageId, MessageUpdate, Presence, Room, StbchatApiRequest, StbchatApiResponse,
+    UploadStatus, User, UserMeta, default_room,
+};
 use serde::{Deserialize, Serialize};
 
 /// # A packet sent from the server to the client (Server -> Client)
 /// - `SystemMessage`: A message sent from the system
 /// - `UserMessage`: A message sent from a user
+/// - `MessageUpdate`: An already sent message was edited, deleted or reacted to
+/// - `History`: A page of the message history of a room, oldest message first
+/// - `Attachment`: A user shared a file in a room
+/// - `AttachmentUpload`: Progress of an own attachment upload
+/// - `AttachmentChunk`: A part of a requested attachment
+/// - `AttachmentUnavailable`: A requested attachment doesn't exist or isn't accessible
+/// - `DirectMessage`: A private message sent from a user
+/// - `DirectMessageStatus`: Tells whether a direct message could be delivered
+/// - `EncryptedDirectMessage`: An end-to-end encrypted private message sent from a user
 /// - `Notification`: Tells the client to show a notification
 /// - `Backend`: Sends the username to the client
+/// - `AuthFailed`: A `Login` or `Register` packet was rejected
+/// - `RoomList`: All rooms with at least one member
+/// - `PresenceUpdate`: The presence of a user changed
+/// - `PresenceList`: All online users including their presence
+/// - `TypingStart` / `TypingStop`: A user started or stopped typing in a room
+/// - `Capabilities`: Announces optional protocol features supported by the server
+/// - `CapabilitiesSelected`: Confirms the features selected by the client
+/// - `Extension`: An application-defined packet relayed from another user
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "packet_type")]
 pub enum ClientPacket {
     #[serde(rename = "system_message")]
     SystemMessage { message: String },
     #[serde(rename = "user_message")]
-    UserMessage { author: User, message: String },
+    UserMessage {
+        author: User,
+        message: String,
+        #[serde(default = "default_room")]
+        room: String,
+        #[serde(default)]
+        id: MessageId,
+        #[serde(default)]
+        reply_to: Option<MessageId>,
+    },
+    #[serde(rename = "message_update")]
+    MessageUpdate { room: String, update: MessageUpdate },
+    #[serde(rename = "history")]
+    History {
+        room: String,
+        messages: Vec<ChatMessage>,
+        /// Whether more messages are available in the queried direction
+        has_more: bool,
+    },
+    #[serde(rename = "attachment")]
+    Attachment {
+        author: User,
+        room: String,
+        attachment: Attachment,
+    },
+    #[serde(rename = "attachment_upload")]
+    AttachmentUpload { hash: String, status: UploadStatus },
+    #[serde(rename = "attachment_chunk")]
+    AttachmentChunk {
+        id: AttachmentId,
+        offset: u64,
+        #[serde(with = "crate::stbchat::format::bytes")]
+        data: Vec<u8>,
+    },
+    #[serde(rename = "attachment_unavailable")]
+    AttachmentUnavailable { id: AttachmentId },
+    #[serde(rename = "direct_message")]
+    DirectMessage { author: User, message: String },
+    #[serde(rename = "encrypted_direct_message")]
+    EncryptedDirectMessage {
+        author: User,
+        payload: EncryptedPayload,
+    },
+    #[serde(rename = "direct_message_status")]
+    DirectMessageStatus {
+        recipient: String,
+        status: DeliveryStatus,
+    },
     #[serde(rename = "notification_backend")]
     Notification {
         title: String,
@@ -27,23 +96,84 @@ pub enum ClientPacket {
     Event { event_type: String },
     #[serde(rename = "stbchat_backend")]
     Backend { user_meta: UserMeta },
+    #[serde(rename = "auth_failed")]
+    AuthFailed {
+        reason: AuthFailure,
+        /// Human readable description of the failure
+        message: String,
+    },
     #[serde(rename = "stbchat_api")]
     ApiResponse {
         response_type: String,
         response: StbchatApiResponse,
     },
+    #[serde(rename = "room_list")]
+    RoomList { rooms: Vec<Room> },
+    #[serde(rename = "presence_update")]
+    PresenceUpdate {
+        username: String,
+        presence: Presence,
+    },
+    #[serde(rename = "presence_list")]
+    PresenceList { users: Vec<User> },
+    #[serde(rename = "typing_start")]
+    TypingStart { username: String, room: String },
+    #[serde(rename = "typing_stop")]
+    TypingStop { username: String, room: String },
+    #[serde(rename = "stbchat_capabilities")]
+    Capabilities {
+        compression: Vec<String>,
+        #[serde(default)]
+        formats: Vec<String>,
+    },
+    #[serde(rename = "stbchat_capabilities_selected")]
+    CapabilitiesSelected {
+        compression: Option<String>,
+        #[serde(default)]
+        format: Option<String>,
+    },
+    #[serde(rename = "extension")]
+    Extension {
+        /// Namespaced type of the extension, e.g. `polls:vote`
+        extension_type: String,
+        sender: String,
+        target: ExtensionTarget,
+        /// `MessagePack` encoded payload
+        #[serde(with = "crate::stbchat::format::bytes")]
+        payload: Vec<u8>,
+    },
 }
 
 /// # A packet sent from the client to the server (Client -> Server)
 /// - `Login`: A event packet for receiving the users credentials
+/// - `TokenLogin`: Log in with a Strawberry ID token instead of a password
 /// - `Message`: A message sent from client
-#[derive(Serialize, Deserialize)]
+/// - `EditMessage` / `DeleteMessage`: Change or remove an own message
+/// - `AddReaction` / `RemoveReaction`: React to a message with an emoji
+/// - `History`: Request a page of the message history of a room
+/// - `OfferAttachment`: Start or resume uploading a file to a room
+/// - `AttachmentChunk`: A part of an offered file
+/// - `DownloadAttachment`: Request the content of an attachment, starting at `offset`
+/// - `DirectMessage`: A private message to a single user
+/// - `EncryptedDirectMessage`: An end-to-end encrypted private message to a single user
+/// - `JoinRoom` / `LeaveRoom`: Join or leave a room
+/// - `ListRooms`: Request a `RoomList`
+/// - `SetPresence`: Change the own presence
+/// - `ListPresence`: Request a `PresenceList`
+/// - `TypingStart` / `TypingStop`: Tell the members of a room that the user is typing
+/// - `SelectCapabilities`: Selects optional protocol features announced by the server
+/// - `Extension`: An application-defined packet relayed to a room or user
+#[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "packet_type")]
 pub enum ServerPacket {
     Login {
         username: String,
         password: String,
     },
+    TokenLogin {
+        username: String,
+        token: String,
+    },
     Register {
         username: String,
         password: String,
@@ -51,9 +181,88 @@ pub enum ServerPacket {
     },
     Message {
         message: String,
+        #[serde(default = "default_room")]
+        room: String,
+        #[serde(default)]
+        reply_to: Option<MessageId>,
+    },
+    EditMessage {
+        id: MessageId,
+        message: String,
+    },
+    DeleteMessage {
+        id: MessageId,
+    },
+    AddReaction {
+        id: MessageId,
+        emoji: String,
+    },
+    RemoveReaction {
+        id: MessageId,
+        emoji: String,
+    },
+    History {
+        room: String,
+        query: HistoryQuery,
+        limit: usize,
+    },
+    OfferAttachment {
+        room: String,
+        name: String,
+        size: u64,
+        mime_type: String,
+        hash: String,
+    },
+    AttachmentChunk {
+        id: AttachmentId,
+        offset: u64,
+        #[serde(with = "crate::stbchat::format::bytes")]
+        data: Vec<u8>,
+    },
+    DownloadAttachment {
+        id: AttachmentId,
+        offset: u64,
+    },
+    DirectMessage {
+        recipient: String,
+        message: String,
+    },
+    EncryptedDirectMessage {
+        recipient: String,
+        payload: EncryptedPayload,
+    },
+    JoinRoom {
+        room: String,
+    },
+    LeaveRoom {
+        room: String,
+    },
+    ListRooms,
+    SetPresence {
+        presence: Presence,
+    },
+    ListPresence,
+    TypingStart {
+        room: String,
+    },
+    TypingStop {
+        room: String,
     },
     ApiRequest {
         request_type: String,
+        #[serde(default)]
+        request: Option<StbchatApiRequest>,
     },
     KeepAlive,
+    SelectCapabilities {
+        compression: Option<String>,
+        #[serde(default)]
+        format: Option<String>,
+    },
+    Extension {
+        extension_type: String,
+        target: ExtensionTarget,
+        #[serde(with = "crate::stbchat::format::bytes")]
+        payload: Vec<u8>,
+    },
 }
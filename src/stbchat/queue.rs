@@ -0,0 +1,758 @@
+#![cfg(not(feature = "stbchat-sync"))]
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use serde::Serialize;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::{Notify, oneshot, watch};
+
+#[cfg(feature = "stbchat-compression")]
+use crate::stbchat::compression::Compression;
+use crate::stbchat::error::CommunicationError;
+use crate::stbchat::format::WireFormat;
+use crate::stbchat::net::OutgoingPacketStream;
+use crate::stbchat::packet::{ClientPacket, ServerPacket};
+
+/// Default amount of packets waiting to be sent per connection
+pub const DEFAULT_QUEUE_CAPACITY: usize = 256;
+
+/// Default maximum size of frames combined into a single write
+pub const DEFAULT_MAX_BATCH_SIZE: usize = 16 * 1024;
+
+/// Order in which queued packets are sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Priority {
+    /// Sent before all normal packets, e.g. `KeepAlive` and system messages
+    High,
+    #[default]
+    Normal,
+}
+
+/// Packets which can be sent through a packet queue
+pub trait Prioritized: Serialize {
+    fn priority(&self) -> Priority {
+        Priority::Normal
+    }
+}
+
+impl Prioritized for ClientPacket {
+    fn priority(&self) -> Priority {
+        match self {
+            Self::SystemMessage { .. }
+            | Self::Notification { .. }
+            | Self::Event { .. }
+            | Self::Backend { .. }
+            | Self::AuthFailed { .. }
+            | Self::Capabilities { .. }
+            | Self::CapabilitiesSelected { .. } => Priority::High,
+            _ => Priority::Normal,
+        }
+    }
+}
+
+impl Prioritized for ServerPacket {
+    fn priority(&self) -> Priority {
+        match self {
+            Self::KeepAlive
+            | Self::Login { .. }
+            | Self::TokenLogin { .. }
+            | Self::Register { .. }
+            | Self::SelectCapabilities { .. } => Priority::High,
+            _ => Priority::Normal,
+        }
+    }
+}
+
+impl<P: Prioritized> Prioritized for &P {
+    fn priority(&self) -> Priority {
+        (*self).priority()
+    }
+}
+
+/// What happens when a packet is sent while the queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Drop the oldest normal packet, or the oldest high priority packet if there is none
+    /// and count it in `PacketQueue::dropped`
+    #[default]
+    DropOldest,
+    /// Drop all queued packets and close the connection
+    Disconnect,
+    /// Wait until the writer task made room
+    Block,
+}
+
+/// # Configuration of a packet queue
+/// By default up to 256 packets are queued, the oldest packets are dropped once the queue is full
+/// and up to 16 KiB of frames are combined into a single write
+#[derive(Debug, Clone, Copy)]
+pub struct QueueConfig {
+    capacity: usize,
+    overflow: OverflowPolicy,
+    max_batch_size: usize,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            capacity: DEFAULT_QUEUE_CAPACITY,
+            overflow: OverflowPolicy::default(),
+            max_batch_size: DEFAULT_MAX_BATCH_SIZE,
+        }
+    }
+}
+
+impl QueueConfig {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Maximum amount of packets waiting to be sent, at least one
+    #[must_use]
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+
+        self
+    }
+
+    #[must_use]
+    pub const fn overflow(mut self, overflow: OverflowPolicy) -> Self {
+        self.overflow = overflow;
+
+        self
+    }
+
+    /// Maximum size of frames combined into a single write.
+    /// Larger frames are always written on their own
+    #[must_use]
+    pub const fn max_batch_size(mut self, max_batch_size: usize) -> Self {
+        self.max_batch_size = max_batch_size;
+
+        self
+    }
+}
+
+/// Queue a packet waits in
+#[derive(Clone, Copy)]
+enum Lane {
+    High,
+    Normal,
+    /// Bulk transfers, never dropped and waiting for room regardless of the overflow policy
+    Bulk,
+}
+
+impl From<Priority> for Lane {
+    fn from(priority: Priority) -> Self {
+        match priority {
+            Priority::High => Self::High,
+            Priority::Normal => Self::Normal,
+        }
+    }
+}
+
+/// A serialized packet waiting to be sent
+struct Item {
+    bytes: Vec<u8>,
+    #[cfg(feature = "stbchat-compression")]
+    switch: Option<SwitchCompression>,
+}
+
+/// Compression to switch to right after writing a packet
+#[cfg(feature = "stbchat-compression")]
+struct SwitchCompression(Option<Compression>);
+
+#[derive(Default)]
+struct State {
+    high: VecDeque<Item>,
+    normal: VecDeque<Item>,
+    bulk: VecDeque<Item>,
+    /// Packets dropped because the queue was full
+    dropped: u64,
+    /// No more packets are accepted, the writer task drops everything still queued
+    closed: bool,
+    /// Shut down the stream once the queue is empty
+    shutdown: Option<oneshot::Sender<io::Result<()>>>,
+    /// All handles were dropped, the writer task stops once the queue is empty
+    detached: bool,
+    /// Format new packets are serialized in
+    format: WireFormat,
+}
+
+impl State {
+    fn len(&self) -> usize {
+        self.high.len() + self.normal.len() + self.bulk.len()
+    }
+}
+
+struct Shared {
+    config: QueueConfig,
+    state: Mutex<State>,
+    /// Wakes the writer task
+    pending: Notify,
+    /// Wakes senders waiting for room in the queue
+    space: Notify,
+    closed: watch::Sender<bool>,
+}
+
+impl Shared {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Queue a packet, applying the overflow policy if the queue is full.
+    /// Returns the packet if it has to wait for room
+    fn try_push(&self, lane: Lane, item: Item) -> Result<Option<Item>, CommunicationError> {
+        let mut state = self.state();
+        if state.closed {
+            return Err(CommunicationError::ConnectionClosed);
+        }
+
+        if matches!(lane, Lane::Bulk) {
+            if state.len() >= self.config.capacity {
+                return Ok(Some(item));
+            }
+        } else if state.high.len() + state.normal.len() >= self.config.capacity {
+            // Bulk packets are not counted, so they can't push out other packets
+            match self.config.overflow {
+                OverflowPolicy::DropOldest => {
+                    if state.normal.pop_front().is_none() {
+                        state.high.pop_front();
+                    }
+                    state.dropped += 1;
+                }
+                OverflowPolicy::Disconnect => {
+                    drop(state);
+                    self.close();
+                    return Err(CommunicationError::ConnectionClosed);
+                }
+                OverflowPolicy::Block => return Ok(Some(item)),
+            }
+        }
+
+        match lane {
+            Lane::High => state.high.push_back(item),
+            Lane::Normal => state.normal.push_back(item),
+            Lane::Bulk => state.bulk.push_back(item),
+        }
+        drop(state);
+
+        self.pending.notify_one();
+        Ok(None)
+    }
+
+    /// Stop accepting packets and drop all queued ones
+    fn close(&self) {
+        let mut state = self.state();
+        state.closed = true;
+        state.high.clear();
+        state.normal.clear();
+        state.bulk.clear();
+        drop(state);
+
+        self.pending.notify_one();
+        self.space.notify_waiters();
+        self.closed.send_replace(true);
+    }
+}
+
+/// Tells the writer task to finish once the last handle was dropped
+struct Handle {
+    shared: Arc<Shared>,
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        self.shared.state().detached = true;
+        self.shared.pending.notify_one();
+    }
+}
+
+/// # Outgoing packet queue of a connection
+/// Packets are serialized by the sender and written by a background task,
+/// so a slow connection never stalls the sending task.
+/// High priority packets overtake queued normal packets, consecutive frames are written at once.
+/// Handles can be cloned and shared between tasks
+/// ```no_run
+/// use libstrawberry::stbchat::net::OutgoingPacketStream;
+/// use libstrawberry::stbchat::packet::ServerPacket;
+/// use libstrawberry::stbchat::queue::{OverflowPolicy, PacketQueue, QueueConfig};
+/// use tokio::net::TcpStream;
+///
+/// # async fn example() -> eyre::Result<()> {
+/// let stream = TcpStream::connect("127.0.0.1:52800").await?;
+/// let config = QueueConfig::new().capacity(64).overflow(OverflowPolicy::Block);
+/// let queue = PacketQueue::spawn(OutgoingPacketStream::wrap(stream), config);
+///
+/// queue.send(ServerPacket::KeepAlive).await?;
+/// queue.shutdown().await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct PacketQueue {
+    handle: Arc<Handle>,
+}
+
+impl PacketQueue {
+    /// Spawn the writer task of a connection, keeping the format of the stream.
+    /// Must be called from within a tokio runtime
+    pub fn spawn<W: AsyncWrite + Send + Unpin + 'static>(
+        stream: OutgoingPacketStream<W>,
+        config: QueueConfig,
+    ) -> Self {
+        let shared = Arc::new(Shared {
+            config,
+            state: Mutex::new(State {
+                format: stream.format(),
+                ..State::default()
+            }),
+            pending: Notify::new(),
+            space: Notify::new(),
+            closed: watch::Sender::new(false),
+        });
+
+        tokio::spawn(write_packets(shared.clone(), stream));
+
+        Self {
+            handle: Arc::new(Handle { shared }),
+        }
+    }
+
+    /// Queue a packet with its own priority
+    /// # Errors
+    /// - Will return `Err` if the packet could not be serialized
+    /// - Will return `PacketTooLarge` if the packet doesn't fit into a frame, even if compression would shrink it
+    /// - Will return `ConnectionClosed` if the connection was closed
+    ///   or the queue overflowed with the `Disconnect` policy
+    pub async fn send<P: Prioritized>(&self, packet: P) -> Result<(), CommunicationError> {
+        let priority = packet.priority();
+        self.push(priority.into(), self.serialize(&packet)?).await
+    }
+
+    /// Queue a packet with the given priority
+    /// # Errors
+    /// - Will return `Err` under the same conditions as `send`
+    pub async fn send_with_priority<P: Serialize>(
+        &self,
+        packet: P,
+        priority: Priority,
+    ) -> Result<(), CommunicationError> {
+        self.push(priority.into(), self.serialize(&packet)?).await
+    }
+
+    /// Queue a packet of a bulk transfer, e.g. an attachment chunk.
+    /// Bulk packets are sent after all other packets and are never dropped,
+    /// they wait for room in the queue regardless of the overflow policy
+    /// # Errors
+    /// - Will return `Err` under the same conditions as `send`
+    pub async fn send_bulk<P: Serialize>(&self, packet: P) -> Result<(), CommunicationError> {
+        self.push(Lane::Bulk, self.serialize(&packet)?).await
+    }
+
+    /// Queue a packet and switch the compression of the stream right after writing it.
+    /// Used to confirm a compression negotiation with the last uncompressed frame
+    /// # Errors
+    /// - Will return `Err` under the same conditions as `send`
+    #[cfg(feature = "stbchat-compression")]
+    pub async fn send_switching_compression<P: Prioritized>(
+        &self,
+        packet: P,
+        compression: Option<Compression>,
+    ) -> Result<(), CommunicationError> {
+        let item = Item {
+            bytes: self.serialize(&packet)?,
+            switch: Some(SwitchCompression(compression)),
+        };
+
+        self.push_item(packet.priority().into(), item).await
+    }
+
+    /// Serialize packets sent from now on in the given format.
+    /// Packets which are already queued keep their format
+    pub fn set_format(&self, format: WireFormat) {
+        self.shared().state().format = format;
+    }
+
+    /// Returns the format new packets are serialized in
+    #[must_use]
+    pub fn format(&self) -> WireFormat {
+        self.shared().state().format
+    }
+
+    /// Send all queued packets and shut down the connection
+    /// # Errors
+    /// - Will return `ConnectionClosed` if the connection was already closed
+    /// - Will return `Err` if the connection could not be shut down
+    pub async fn shutdown(&self) -> Result<(), CommunicationError> {
+        let (tx, rx) = oneshot::channel();
+
+        {
+            let mut state = self.shared().state();
+            if state.closed {
+                return Err(CommunicationError::ConnectionClosed);
+            }
+            state.shutdown = Some(tx);
+        }
+        self.shared().pending.notify_one();
+
+        rx.await
+            .map_err(|_| CommunicationError::ConnectionClosed)??;
+
+        Ok(())
+    }
+
+    /// Wait until the connection was closed, e.g. because writing failed or the queue overflowed
+    pub async fn closed(&self) {
+        let mut closed = self.shared().closed.subscribe();
+        let _ = closed.wait_for(|closed| *closed).await;
+    }
+
+    /// Returns `true` once the connection was closed
+    #[must_use]
+    pub fn is_closed(&self) -> bool {
+        self.shared().state().closed
+    }
+
+    /// Returns the amount of packets waiting to be sent
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.shared().state().len()
+    }
+
+    /// Returns `true` if all packets have been written
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the amount of packets dropped with the `DropOldest` policy
+    #[must_use]
+    pub fn dropped(&self) -> u64 {
+        self.shared().state().dropped
+    }
+
+    fn shared(&self) -> &Shared {
+        &self.handle.shared
+    }
+
+    fn serialize<P: Serialize>(&self, packet: &P) -> Result<Vec<u8>, CommunicationError> {
+        let bytes = self.format().serialize(packet)?;
+
+        if bytes.len() > usize::from(u16::MAX) {
+            return Err(CommunicationError::PacketTooLarge(bytes.len()));
+        }
+
+        Ok(bytes)
+    }
+
+    async fn push(&self, lane: Lane, bytes: Vec<u8>) -> Result<(), CommunicationError> {
+        let item = Item {
+            bytes,
+            #[cfg(feature = "stbchat-compression")]
+            switch: None,
+        };
+
+        self.push_item(lane, item).await
+    }
+
+    async fn push_item(&self, lane: Lane, mut item: Item) -> Result<(), CommunicationError> {
+        let shared = self.shared();
+
+        loop {
+            // Register before checking, so room made in between isn't missed
+            let space = shared.space.notified();
+            tokio::pin!(space);
+            space.as_mut().enable();
+
+            match shared.try_push(lane, item)? {
+                None => return Ok(()),
+                Some(rejected) => {
+                    item = rejected;
+                    space.await;
+                }
+            }
+        }
+    }
+}
+
+/// Work for the writer task
+enum Batch {
+    Items(Vec<Item>),
+    Shutdown(oneshot::Sender<io::Result<()>>),
+    Stop,
+}
+
+/// Take the next packets from the queue, high priority packets first and bulk packets last
+async fn next_batch(shared: &Shared) -> Batch {
+    loop {
+        {
+            let mut state = shared.state();
+
+            if state.closed {
+                return Batch::Stop;
+            }
+
+            let mut items = Vec::new();
+            let mut size = 0;
+            while items.is_empty() || size < shared.config.max_batch_size {
+                let queue = if !state.high.is_empty() {
+                    &mut state.high
+                } else if !state.normal.is_empty() {
+                    &mut state.normal
+                } else {
+                    &mut state.bulk
+                };
+
+                let Some(item) = queue.pop_front() else {
+                    break;
+                };
+
+                // Large frames are written on their own
+                if !items.is_empty() && size + item.bytes.len() > shared.config.max_batch_size {
+                    queue.push_front(item);
+                    break;
+                }
+
+                size += item.bytes.len();
+                items.push(item);
+            }
+
+            if !items.is_empty() {
+                drop(state);
+                shared.space.notify_waiters();
+                return Batch::Items(items);
+            }
+
+            if let Some(shutdown) = state.shutdown.take() {
+                return Batch::Shutdown(shutdown);
+            }
+
+            if state.detached {
+                return Batch::Stop;
+            }
+        }
+
+        shared.pending.notified().await;
+    }
+}
+
+/// Write queued packets until the connection is closed
+async fn write_packets<W: AsyncWrite + Send + Unpin>(
+    shared: Arc<Shared>,
+    mut stream: OutgoingPacketStream<W>,
+) {
+    let mut batch = Vec::new();
+
+    loop {
+        match next_batch(&shared).await {
+            Batch::Items(items) => {
+                for item in items {
+                    // Packets which don't fit into a frame are dropped
+                    let _ = stream.encode_frame(item.bytes, &mut batch);
+
+                    #[cfg(feature = "stbchat-compression")]
+                    if let Some(SwitchCompression(compression)) = item.switch {
+                        stream.set_compression(compression);
+                    }
+                }
+
+                if stream.write_batch(&batch).await.is_err() {
+                    break;
+                }
+                batch.clear();
+            }
+            Batch::Shutdown(done) => {
+                let _ = done.send(stream.inner_mut().shutdown().await);
+                break;
+            }
+            Batch::Stop => {
+                let _ = stream.inner_mut().shutdown().await;
+                break;
+            }
+        }
+    }
+
+    shared.close();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stbchat::net::IncomingPacketStream;
+
+    fn shared(config: QueueConfig) -> Shared {
+        Shared {
+            config,
+            state: Mutex::new(State::default()),
+            pending: Notify::new(),
+            space: Notify::new(),
+            closed: watch::Sender::new(false),
+        }
+    }
+
+    fn item(byte: u8) -> Item {
+        Item {
+            bytes: vec![byte],
+            #[cfg(feature = "stbchat-compression")]
+            switch: None,
+        }
+    }
+
+    fn push(shared: &Shared, lane: Lane, byte: u8) -> Result<Option<Item>, CommunicationError> {
+        shared.try_push(lane, item(byte))
+    }
+
+    /// Take the next batch and return the first byte of each packet
+    async fn batch(shared: &Shared) -> Vec<u8> {
+        match next_batch(shared).await {
+            Batch::Items(items) => items.iter().map(|item| item.bytes[0]).collect(),
+            _ => panic!("expected packets"),
+        }
+    }
+
+    #[tokio::test]
+    async fn high_before_normal_before_bulk() {
+        let shared = shared(QueueConfig::new());
+
+        for (lane, byte) in [
+            (Lane::Bulk, 1),
+            (Lane::Normal, 2),
+            (Lane::High, 3),
+            (Lane::Normal, 4),
+            (Lane::High, 5),
+        ] {
+            assert!(push(&shared, lane, byte).unwrap().is_none());
+        }
+
+        assert_eq!(batch(&shared).await, [3, 5, 2, 4, 1]);
+    }
+
+    #[tokio::test]
+    async fn large_frames_are_written_alone() {
+        let shared = shared(QueueConfig::new().max_batch_size(2));
+
+        for byte in 1..=3 {
+            push(&shared, Lane::Normal, byte).unwrap();
+        }
+
+        assert_eq!(batch(&shared).await, [1, 2]);
+        assert_eq!(batch(&shared).await, [3]);
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_prefers_normal_packets() {
+        let shared = shared(QueueConfig::new().capacity(2));
+
+        push(&shared, Lane::High, 1).unwrap();
+        push(&shared, Lane::Normal, 2).unwrap();
+        push(&shared, Lane::Normal, 3).unwrap();
+        assert_eq!(shared.state().dropped, 1);
+        assert_eq!(batch(&shared).await, [1, 3]);
+
+        push(&shared, Lane::High, 4).unwrap();
+        push(&shared, Lane::High, 5).unwrap();
+        push(&shared, Lane::High, 6).unwrap();
+        assert_eq!(shared.state().dropped, 2);
+        assert_eq!(batch(&shared).await, [5, 6]);
+    }
+
+    #[test]
+    fn disconnect_closes_when_full() {
+        let shared = shared(
+            QueueConfig::new()
+                .capacity(1)
+                .overflow(OverflowPolicy::Disconnect),
+        );
+
+        push(&shared, Lane::Normal, 1).unwrap();
+        assert!(matches!(
+            push(&shared, Lane::Normal, 2),
+            Err(CommunicationError::ConnectionClosed)
+        ));
+        assert!(shared.state().closed);
+        assert_eq!(shared.state().len(), 0);
+        assert!(*shared.closed.borrow());
+    }
+
+    #[test]
+    fn block_returns_packets_waiting_for_room() {
+        let shared = shared(
+            QueueConfig::new()
+                .capacity(1)
+                .overflow(OverflowPolicy::Block),
+        );
+
+        push(&shared, Lane::Normal, 1).unwrap();
+        let waiting = push(&shared, Lane::High, 2).unwrap();
+
+        assert_eq!(waiting.map(|item| item.bytes), Some(vec![2]));
+        assert_eq!(shared.state().len(), 1);
+        assert_eq!(shared.state().dropped, 0);
+    }
+
+    #[tokio::test]
+    async fn bulk_packets_wait_and_never_push_out_others() {
+        let shared = shared(QueueConfig::new().capacity(2));
+
+        push(&shared, Lane::Bulk, 1).unwrap();
+        push(&shared, Lane::Bulk, 2).unwrap();
+        // Bulk packets wait for room instead of dropping
+        assert!(push(&shared, Lane::Bulk, 3).unwrap().is_some());
+        // Other packets don't count bulk packets
+        push(&shared, Lane::Normal, 4).unwrap();
+        push(&shared, Lane::Normal, 5).unwrap();
+
+        assert_eq!(shared.state().dropped, 0);
+        assert_eq!(batch(&shared).await, [4, 5, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn closing_drops_queued_packets() {
+        let shared = shared(QueueConfig::new());
+
+        push(&shared, Lane::Normal, 1).unwrap();
+        shared.close();
+
+        assert!(matches!(
+            push(&shared, Lane::High, 2),
+            Err(CommunicationError::ConnectionClosed)
+        ));
+        assert!(matches!(next_batch(&shared).await, Batch::Stop));
+    }
+
+    #[tokio::test]
+    async fn shutdown_sends_queued_packets() {
+        let (writer, reader) = tokio::io::duplex(1024);
+        let queue = PacketQueue::spawn(OutgoingPacketStream::wrap(writer), QueueConfig::new());
+        let mut reader = IncomingPacketStream::wrap(reader);
+
+        queue.send(ServerPacket::KeepAlive).await.unwrap();
+        queue
+            .send_bulk(ServerPacket::Message {
+                message: "bulk".to_string(),
+                room: "general".to_string(),
+                reply_to: None,
+            })
+            .await
+            .unwrap();
+        queue.shutdown().await.unwrap();
+
+        assert!(matches!(
+            reader.read::<ServerPacket>().await,
+            Ok(ServerPacket::KeepAlive)
+        ));
+        assert!(matches!(
+            reader.read::<ServerPacket>().await,
+            Ok(ServerPacket::Message { message, .. }) if message == "bulk"
+        ));
+        assert!(reader.read::<ServerPacket>().await.is_err());
+        assert!(queue.is_closed());
+        assert!(matches!(
+            queue.send(ServerPacket::KeepAlive).await,
+            Err(CommunicationError::ConnectionClosed)
+        ));
+    }
+}
@@ -0,0 +1,235 @@
+use std::fmt::{self, Display, Formatter};
+
+use crate::colors::{
+    BLACK, BLUE, C_RESET, CYAN, GRAY, GREEN, LIGHT_BLUE, LIGHT_CYAN, LIGHT_GREEN, LIGHT_MAGENTA,
+    LIGHT_RED, LIGHT_WHITE, LIGHT_YELLOW, MAGENTA, RED, RESET, WHITE, YELLOW,
+};
+
+/// Names of the basic and light terminal colors with their escape sequences.
+/// Names are compared after removing `_`, `-` and spaces, and `bright` is treated as `light`
+const NAMED_COLORS: &[(&str, &str)] = &[
+    ("black", BLACK),
+    ("red", RED),
+    ("green", GREEN),
+    ("yellow", YELLOW),
+    ("blue", BLUE),
+    ("magenta", MAGENTA),
+    ("purple", MAGENTA),
+    ("cyan", CYAN),
+    ("white", WHITE),
+    ("gray", GRAY),
+    ("grey", GRAY),
+    ("lightblack", GRAY),
+    ("lightred", LIGHT_RED),
+    ("lightgreen", LIGHT_GREEN),
+    ("lightyellow", LIGHT_YELLOW),
+    ("lightblue", LIGHT_BLUE),
+    ("lightmagenta", LIGHT_MAGENTA),
+    ("lightpurple", LIGHT_MAGENTA),
+    ("lightcyan", LIGHT_CYAN),
+    ("lightwhite", LIGHT_WHITE),
+    ("default", RESET),
+];
+
+/// # Terminal color of a role
+/// `User::role_color` is a free-form string, this is how it is interpreted:
+/// - Color names like `red`, `light_blue` or `bright-cyan`
+/// - Hex colors like `#ff5555` or `#f55`
+/// - Indices of the 256-color palette like `203`
+/// - Raw SGR escape sequences using one of the above, e.g. `\x1b[31m` or `\x1b[38;5;203m`
+///
+/// Anything else is not a color and must not be written to the terminal
+/// ```
+/// use libstrawberry::stbchat::role_color::RoleColor;
+///
+/// assert_eq!(RoleColor::parse("#f55"), Some(RoleColor::Rgb(0xff, 0x55, 0x55)));
+/// assert_eq!(RoleColor::parse("203"), Some(RoleColor::Palette(203)));
+/// assert_eq!(RoleColor::parse("\x1b[2J"), None);
+///
+/// println!("{}", RoleColor::parse("light_red").unwrap().paint("admin"));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RoleColor {
+    /// One of the basic or light terminal colors, holding its escape sequence from `colors`
+    Named(&'static str),
+    /// Index of the 256-color palette
+    Palette(u8),
+    /// 24-bit color
+    Rgb(u8, u8, u8),
+}
+
+impl RoleColor {
+    /// Parse a role color. Returns `None` if the value is no recognized color
+    #[must_use]
+    pub fn parse(role_color: &str) -> Option<Self> {
+        let role_color = role_color.trim();
+
+        if let Some(parameters) = role_color
+            .strip_prefix("\x1b[")
+            .and_then(|sequence| sequence.strip_suffix('m'))
+        {
+            return Self::parse_sgr(parameters);
+        }
+
+        if let Some(hex) = role_color.strip_prefix('#') {
+            return Self::parse_hex(hex);
+        }
+
+        if let Ok(index) = role_color.parse() {
+            return Some(Self::Palette(index));
+        }
+
+        let name: String = role_color
+            .chars()
+            .filter(|c| !matches!(c, '_' | '-' | ' '))
+            .collect::<String>()
+            .to_ascii_lowercase()
+            .replace("bright", "light");
+
+        NAMED_COLORS
+            .iter()
+            .find(|(color, _)| *color == name)
+            .map(|(_, sequence)| Self::Named(sequence))
+    }
+
+    /// `rrggbb` or `rgb`
+    fn parse_hex(hex: &str) -> Option<Self> {
+        if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+
+        let channel = |digits: &str| u8::from_str_radix(digits, 16).ok();
+
+        match hex.len() {
+            6 => Some(Self::Rgb(
+                channel(&hex[0..2])?,
+                channel(&hex[2..4])?,
+                channel(&hex[4..6])?,
+            )),
+            3 => {
+                let short = |i: usize| channel(&hex[i..=i]).map(|value| value * 0x11);
+                Some(Self::Rgb(short(0)?, short(1)?, short(2)?))
+            }
+            _ => None,
+        }
+    }
+
+    /// Parameters of a foreground color SGR sequence, e.g. `31`, `38;5;203` or `38;2;255;85;85`
+    fn parse_sgr(parameters: &str) -> Option<Self> {
+        let parameters: Vec<u8> = parameters
+            .split(';')
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .ok()?;
+
+        match parameters.as_slice() {
+            [38, 5, index] => Some(Self::Palette(*index)),
+            [38, 2, r, g, b] => Some(Self::Rgb(*r, *g, *b)),
+            [code @ (30..=37 | 39 | 90..=97)] => {
+                let sequence = format!("\x1b[{code}m");
+                NAMED_COLORS
+                    .iter()
+                    .find(|(_, named)| **named == sequence)
+                    .map(|(_, named)| Self::Named(named))
+            }
+            _ => None,
+        }
+    }
+
+    /// Escape sequence switching the foreground to this color
+    #[must_use]
+    pub fn ansi(&self) -> String {
+        match self {
+            Self::Named(sequence) => (*sequence).to_string(),
+            Self::Palette(index) => format!("\x1b[38;5;{index}m"),
+            Self::Rgb(r, g, b) => format!("\x1b[38;2;{r};{g};{b}m"),
+        }
+    }
+
+    /// CSS color value, e.g. `#ff5555`.
+    /// Named colors use the palette of common terminals
+    #[must_use]
+    pub fn css(&self) -> String {
+        let (r, g, b) = match *self {
+            Self::Named(sequence) => named_rgb(sequence),
+            Self::Palette(index) => palette_rgb(index),
+            Self::Rgb(r, g, b) => (r, g, b),
+        };
+
+        format!("#{r:02x}{g:02x}{b:02x}")
+    }
+
+    /// Color a text and reset the style afterwards
+    #[must_use]
+    pub fn paint(&self, text: impl Display) -> String {
+        format!("{}{text}{C_RESET}", self.ansi())
+    }
+}
+
+impl Display for RoleColor {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.ansi())
+    }
+}
+
+/// RGB value of a named color, matching the first 16 colors of the 256-color palette
+fn named_rgb(sequence: &str) -> (u8, u8, u8) {
+    match sequence {
+        BLACK => palette_rgb(0),
+        RED => palette_rgb(1),
+        GREEN => palette_rgb(2),
+        YELLOW => palette_rgb(3),
+        BLUE => palette_rgb(4),
+        MAGENTA => palette_rgb(5),
+        CYAN => palette_rgb(6),
+        GRAY => palette_rgb(8),
+        LIGHT_RED => palette_rgb(9),
+        LIGHT_GREEN => palette_rgb(10),
+        LIGHT_YELLOW => palette_rgb(11),
+        LIGHT_BLUE => palette_rgb(12),
+        LIGHT_MAGENTA => palette_rgb(13),
+        LIGHT_CYAN => palette_rgb(14),
+        LIGHT_WHITE => palette_rgb(15),
+        // White and the default foreground
+        _ => palette_rgb(7),
+    }
+}
+
+/// RGB value of an index of the xterm 256-color palette
+const fn palette_rgb(index: u8) -> (u8, u8, u8) {
+    const BASIC: [(u8, u8, u8); 16] = [
+        (0x00, 0x00, 0x00),
+        (0xcd, 0x00, 0x00),
+        (0x00, 0xcd, 0x00),
+        (0xcd, 0xcd, 0x00),
+        (0x00, 0x00, 0xee),
+        (0xcd, 0x00, 0xcd),
+        (0x00, 0xcd, 0xcd),
+        (0xe5, 0xe5, 0xe5),
+        (0x7f, 0x7f, 0x7f),
+        (0xff, 0x00, 0x00),
+        (0x00, 0xff, 0x00),
+        (0xff, 0xff, 0x00),
+        (0x5c, 0x5c, 0xff),
+        (0xff, 0x00, 0xff),
+        (0x00, 0xff, 0xff),
+        (0xff, 0xff, 0xff),
+    ];
+    const CUBE: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+    match index {
+        0..=15 => BASIC[index as usize],
+        16..=231 => {
+            let index = index - 16;
+            (
+                CUBE[(index / 36) as usize],
+                CUBE[(index / 6 % 6) as usize],
+                CUBE[(index % 6) as usize],
+            )
+        }
+        _ => {
+            let level = 8 + (index - 232) * 10;
+            (level, level, level)
+        }
+    }
+}
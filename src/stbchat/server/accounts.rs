@@ -0,0 +1,327 @@
+use std::collections::HashSet;
+use std::sync::{Arc, OnceLock};
+
+use argon2::Argon2;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+
+use crate::stbchat::error::{AccountError, UsernameError};
+use crate::stbchat::object::User;
+use crate::stbchat::server::store::{Account, UserStore};
+
+/// Default minimum length of passwords of new accounts
+pub const DEFAULT_MIN_PASSWORD_LENGTH: usize = 8;
+
+/// Maximum length of a role color in bytes
+const MAX_ROLE_COLOR_LENGTH: usize = 32;
+
+/// Maximum length of a badge in bytes
+const MAX_BADGE_LENGTH: usize = 32;
+
+/// # Rules for the usernames of new accounts
+/// By default usernames have to be 3 to 32 characters long, start with a letter or digit
+/// and may only contain ASCII letters, digits, `_`, `-` and `.`.
+/// The names `system` and `server` are reserved
+#[derive(Debug, Clone)]
+pub struct UsernameRules {
+    min_length: usize,
+    max_length: usize,
+    reserved: HashSet<String>,
+}
+
+impl Default for UsernameRules {
+    fn default() -> Self {
+        Self {
+            min_length: 3,
+            max_length: 32,
+            reserved: HashSet::from(["system".to_string(), "server".to_string()]),
+        }
+    }
+}
+
+impl UsernameRules {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[must_use]
+    pub const fn min_length(mut self, min_length: usize) -> Self {
+        self.min_length = min_length;
+
+        self
+    }
+
+    #[must_use]
+    pub const fn max_length(mut self, max_length: usize) -> Self {
+        self.max_length = max_length;
+
+        self
+    }
+
+    /// Prevent a name from being registered, compared case-insensitively
+    #[must_use]
+    pub fn reserve(mut self, username: impl AsRef<str>) -> Self {
+        self.reserved.insert(username.as_ref().to_lowercase());
+
+        self
+    }
+
+    /// Check whether a username may be registered
+    /// # Errors
+    /// - Will return `Err` describing the first violated rule
+    pub fn validate(&self, username: &str) -> Result<(), UsernameError> {
+        let length = username.chars().count();
+
+        if length < self.min_length {
+            return Err(UsernameError::TooShort(self.min_length));
+        }
+
+        if length > self.max_length {
+            return Err(UsernameError::TooLong(self.max_length));
+        }
+
+        if !username.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+            return Err(UsernameError::InvalidStart);
+        }
+
+        if let Some(invalid) = username
+            .chars()
+            .find(|c| !c.is_ascii_alphanumeric() && !matches!(c, '_' | '-' | '.'))
+        {
+            return Err(UsernameError::InvalidCharacter(invalid));
+        }
+
+        if self.reserved.contains(&username.to_lowercase()) {
+            return Err(UsernameError::Reserved);
+        }
+
+        Ok(())
+    }
+}
+
+/// # Account registry of the Strawberry Chat server
+/// Validates registrations and logins on top of a pluggable user store.
+/// Passwords are hashed with Argon2id and a random salt, hashing runs on the blocking thread pool.
+///
+/// Can be shared with the server to change badges and role colors at runtime.
+/// Changes apply from the next login of the user
+/// ```no_run
+/// use std::sync::Arc;
+///
+/// use libstrawberry::stbchat::server::StbchatServer;
+/// use libstrawberry::stbchat::server::accounts::{AccountRegistry, UsernameRules};
+/// use libstrawberry::stbchat::server::store::FileUserStore;
+///
+/// # async fn example() -> eyre::Result<()> {
+/// let accounts = Arc::new(
+///     AccountRegistry::new(FileUserStore::open("accounts.json")?)
+///         .username_rules(UsernameRules::new().reserve("admin")),
+/// );
+///
+/// tokio::spawn(StbchatServer::new().accounts(accounts.clone()).listen("0.0.0.0:52800"));
+///
+/// accounts.set_badge("alice", "⭐")?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct AccountRegistry {
+    store: Arc<dyn UserStore>,
+    rules: UsernameRules,
+    min_password_length: usize,
+}
+
+impl AccountRegistry {
+    /// Create a registry with the default username and password rules
+    pub fn new(store: impl UserStore + 'static) -> Self {
+        Self {
+            store: Arc::new(store),
+            rules: UsernameRules::default(),
+            min_password_length: DEFAULT_MIN_PASSWORD_LENGTH,
+        }
+    }
+
+    #[must_use]
+    pub fn username_rules(mut self, rules: UsernameRules) -> Self {
+        self.rules = rules;
+
+        self
+    }
+
+    #[must_use]
+    pub const fn min_password_length(mut self, min_password_length: usize) -> Self {
+        self.min_password_length = min_password_length;
+
+        self
+    }
+
+    /// Check a username against the username rules of the registry
+    /// # Errors
+    /// - Will return `Err` describing the first violated rule
+    pub fn validate_username(&self, username: &str) -> Result<(), UsernameError> {
+        self.rules.validate(username)
+    }
+
+    /// Returns the profile of a registered user
+    #[must_use]
+    pub fn get(&self, username: &str) -> Option<User> {
+        self.store.get(username).map(|account| account.user)
+    }
+
+    /// Create a new account
+    /// # Errors
+    /// - Will return `InvalidUsername` if the username violates the username rules
+    /// - Will return `WeakPassword` if the password is too short
+    /// - Will return `InvalidRoleColor` if the role color is not a plain color value
+    /// - Will return `UsernameTaken` if an account with the same name already exists
+    /// - Will return `Err` if the password could not be hashed or the account could not be stored
+    pub async fn register(
+        &self,
+        username: &str,
+        password: &str,
+        role_color: &str,
+    ) -> Result<User, AccountError> {
+        self.rules.validate(username)?;
+
+        if password.chars().count() < self.min_password_length {
+            return Err(AccountError::WeakPassword(self.min_password_length));
+        }
+
+        if !is_valid_role_color(role_color) {
+            return Err(AccountError::InvalidRoleColor(role_color.to_string()));
+        }
+
+        // Avoid the cost of hashing if the name is obviously taken
+        if self.store.get(username).is_some() {
+            return Err(AccountError::UsernameTaken);
+        }
+
+        let password = password.to_string();
+        let password_hash = blocking(move || hash_password(&password)).await??;
+
+        let user = User {
+            username: username.to_string(),
+            nickname: username.to_string(),
+            role_color: role_color.to_string(),
+            ..Default::default()
+        };
+
+        let account = Account {
+            user: user.clone(),
+            password_hash,
+        };
+
+        // Stores may write to disk
+        let store = Arc::clone(&self.store);
+        let created = tokio::task::spawn_blocking(move || store.create(account))
+            .await
+            .map_err(std::io::Error::other)??;
+
+        if !created {
+            return Err(AccountError::UsernameTaken);
+        }
+
+        Ok(user)
+    }
+
+    /// Check the credentials of a user and return the profile of the account
+    /// # Errors
+    /// - Will return `InvalidCredentials` if there is no such user or the password doesn't match
+    pub async fn login(&self, username: &str, password: &str) -> Result<User, AccountError> {
+        let account = self.store.get(username);
+
+        // Unknown users are checked against a dummy hash, so they take as long as wrong passwords
+        let password_hash = account.as_ref().map_or_else(
+            || dummy_hash().to_string(),
+            |account| account.password_hash.clone(),
+        );
+        let password = password.to_string();
+        let valid = blocking(move || verify_password(&password, &password_hash)).await?;
+
+        match account {
+            Some(account) if valid => Ok(account.user),
+            _ => Err(AccountError::InvalidCredentials),
+        }
+    }
+
+    /// Change the badge of a user, an empty badge removes it
+    /// # Errors
+    /// - Will return `InvalidBadge` if the badge is too long or contains control characters
+    /// - Will return `UnknownUser` if there is no such user
+    /// - Will return `Err` if the account could not be stored
+    pub fn set_badge(&self, username: &str, badge: &str) -> Result<User, AccountError> {
+        if badge.len() > MAX_BADGE_LENGTH || badge.chars().any(char::is_control) {
+            return Err(AccountError::InvalidBadge(badge.to_string()));
+        }
+
+        self.modify(username, |user| user.badge = badge.to_string())
+    }
+
+    /// Change the role color of a user, an empty color removes it
+    /// # Errors
+    /// - Will return `InvalidRoleColor` if the role color is not a plain color value
+    /// - Will return `UnknownUser` if there is no such user
+    /// - Will return `Err` if the account could not be stored
+    pub fn set_role_color(&self, username: &str, role_color: &str) -> Result<User, AccountError> {
+        if !is_valid_role_color(role_color) {
+            return Err(AccountError::InvalidRoleColor(role_color.to_string()));
+        }
+
+        self.modify(username, |user| user.role_color = role_color.to_string())
+    }
+
+    fn modify(&self, username: &str, change: impl FnOnce(&mut User)) -> Result<User, AccountError> {
+        let unknown = || AccountError::UnknownUser(username.to_string());
+        let mut account = self.store.get(username).ok_or_else(unknown)?;
+
+        change(&mut account.user);
+        let user = account.user.clone();
+
+        if !self.store.update(account)? {
+            return Err(unknown());
+        }
+
+        Ok(user)
+    }
+}
+
+/// Role colors are plain values like `red`, `#ff5555` or `203`.
+/// Anything else (e.g. raw escape sequences) is rejected
+fn is_valid_role_color(role_color: &str) -> bool {
+    role_color.len() <= MAX_ROLE_COLOR_LENGTH
+        && role_color
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '#' | '_' | '-'))
+}
+
+fn hash_password(password: &str) -> Result<String, AccountError> {
+    let salt = SaltString::generate(&mut OsRng);
+
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|err| AccountError::Hash(err.to_string()))
+}
+
+fn verify_password(password: &str, password_hash: &str) -> bool {
+    PasswordHash::new(password_hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+fn dummy_hash() -> &'static str {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+
+    DUMMY_HASH.get_or_init(|| hash_password("").unwrap_or_default())
+}
+
+/// Run CPU heavy password hashing without blocking the runtime
+async fn blocking<T: Send + 'static>(
+    task: impl FnOnce() -> T + Send + 'static,
+) -> Result<T, AccountError> {
+    tokio::task::spawn_blocking(task)
+        .await
+        .map_err(|err| AccountError::Hash(err.to_string()))
+}
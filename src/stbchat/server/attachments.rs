@@ -0,0 +1,270 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+
+use sha2::{Digest, Sha256};
+
+use crate::stbchat::attachment;
+use crate::stbchat::object::{Attachment, AttachmentId};
+
+/// Total size of all completed attachments kept in memory
+const STORAGE_CAPACITY: usize = 64 * 1024 * 1024;
+
+/// Maximum amount of unfinished uploads per user, their oldest upload is discarded first
+const MAX_PENDING_UPLOADS: usize = 4;
+
+/// An unfinished upload. Kept across reconnects, so the uploader can resume it
+struct Upload {
+    uploader: String,
+    room: String,
+    attachment: Attachment,
+    data: Vec<u8>,
+    /// Hash of the received data, updated with every chunk
+    hasher: Sha256,
+}
+
+/// A completed attachment
+pub struct StoredAttachment {
+    pub room: String,
+    pub data: Arc<Vec<u8>>,
+}
+
+/// Result of writing a chunk to an upload
+pub enum ChunkResult {
+    /// More chunks are expected
+    Pending,
+    /// The upload is complete and the hash matched
+    Complete {
+        room: String,
+        attachment: Attachment,
+    },
+    Failed {
+        hash: String,
+        reason: String,
+    },
+}
+
+/// In-memory storage of attachments and unfinished uploads.
+/// The oldest attachments are dropped once the storage capacity is exceeded
+#[derive(Default)]
+pub struct Attachments {
+    uploads: HashMap<AttachmentId, Upload>,
+    upload_order: VecDeque<AttachmentId>,
+    stored: HashMap<AttachmentId, StoredAttachment>,
+    stored_order: VecDeque<AttachmentId>,
+    stored_bytes: usize,
+    next_id: AttachmentId,
+}
+
+impl Attachments {
+    /// Start a new upload or resume an unfinished upload of the same file by the same user.
+    /// Returns the id of the upload and the offset of the next expected chunk
+    pub fn offer(
+        &mut self,
+        uploader: &str,
+        room: &str,
+        mut attachment: Attachment,
+    ) -> (AttachmentId, u64) {
+        let existing = self.uploads.iter().find(|(_, upload)| {
+            upload.uploader.eq_ignore_ascii_case(uploader)
+                && upload.room == room
+                && upload.attachment.hash == attachment.hash
+                && upload.attachment.size == attachment.size
+        });
+
+        if let Some((id, upload)) = existing {
+            return (*id, upload.data.len() as u64);
+        }
+
+        self.next_id += 1;
+        let id = self.next_id;
+        attachment.id = id;
+
+        self.uploads.insert(
+            id,
+            Upload {
+                uploader: uploader.to_string(),
+                room: room.to_string(),
+                attachment,
+                data: Vec::new(),
+                hasher: Sha256::new(),
+            },
+        );
+        self.upload_order.push_back(id);
+
+        let owned: Vec<AttachmentId> = self
+            .upload_order
+            .iter()
+            .copied()
+            .filter(|id| {
+                self.uploads
+                    .get(id)
+                    .is_some_and(|upload| upload.uploader.eq_ignore_ascii_case(uploader))
+            })
+            .collect();
+
+        for oldest in owned
+            .iter()
+            .take(owned.len().saturating_sub(MAX_PENDING_UPLOADS))
+        {
+            self.remove_upload(*oldest);
+        }
+
+        (id, 0)
+    }
+
+    /// Append a chunk to an upload of the given user.
+    /// Returns `None` if there is no such upload
+    pub fn write_chunk(
+        &mut self,
+        uploader: &str,
+        id: AttachmentId,
+        offset: u64,
+        data: &[u8],
+    ) -> Option<ChunkResult> {
+        let upload = self
+            .uploads
+            .get_mut(&id)
+            .filter(|upload| upload.uploader.eq_ignore_ascii_case(uploader))?;
+
+        let received = upload.data.len() as u64;
+
+        if offset != received {
+            let reason = format!("Expected chunk at offset {received}, got {offset}");
+            return Some(self.fail(id, reason));
+        }
+
+        if received + data.len() as u64 > upload.attachment.size {
+            return Some(self.fail(id, "Received more data than offered".to_string()));
+        }
+
+        upload.data.extend_from_slice(data);
+        upload.hasher.update(data);
+
+        Some(self.finish(id))
+    }
+
+    /// Complete an upload if all data was received.
+    /// The data was hashed while receiving it, so this doesn't block the lock for long
+    pub fn finish(&mut self, id: AttachmentId) -> ChunkResult {
+        let Some(upload) = self.uploads.get(&id) else {
+            return ChunkResult::Pending;
+        };
+
+        if (upload.data.len() as u64) < upload.attachment.size {
+            return ChunkResult::Pending;
+        }
+
+        if attachment::to_hex(&upload.hasher.clone().finalize()) != upload.attachment.hash {
+            return self.fail(id, "Hash mismatch".to_string());
+        }
+
+        let Some(upload) = self.remove_upload(id) else {
+            return ChunkResult::Pending;
+        };
+
+        self.stored_bytes += upload.data.len();
+        self.stored.insert(
+            id,
+            StoredAttachment {
+                room: upload.room.clone(),
+                data: Arc::new(upload.data),
+            },
+        );
+        self.stored_order.push_back(id);
+
+        while self.stored_bytes > STORAGE_CAPACITY {
+            let Some(oldest) = self.stored_order.pop_front() else {
+                break;
+            };
+
+            if let Some(removed) = self.stored.remove(&oldest) {
+                self.stored_bytes -= removed.data.len();
+            }
+        }
+
+        ChunkResult::Complete {
+            room: upload.room,
+            attachment: upload.attachment,
+        }
+    }
+
+    pub fn get(&self, id: AttachmentId) -> Option<&StoredAttachment> {
+        self.stored.get(&id)
+    }
+
+    fn fail(&mut self, id: AttachmentId, reason: String) -> ChunkResult {
+        let hash = self
+            .remove_upload(id)
+            .map(|upload| upload.attachment.hash)
+            .unwrap_or_default();
+
+        ChunkResult::Failed { hash, reason }
+    }
+
+    fn remove_upload(&mut self, id: AttachmentId) -> Option<Upload> {
+        self.upload_order.retain(|other| *other != id);
+        self.uploads.remove(&id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(content: &[u8]) -> Attachment {
+        Attachment {
+            id: 0,
+            name: "file.bin".to_string(),
+            size: content.len() as u64,
+            mime_type: String::new(),
+            hash: attachment::hash(content),
+        }
+    }
+
+    #[test]
+    fn pending_uploads_are_limited_per_user() {
+        let mut attachments = Attachments::default();
+        let (other, _) = attachments.offer("bob", "general", file(b"bob"));
+        let (first, _) = attachments.offer("alice", "general", file(&[0]));
+
+        for i in 1..=MAX_PENDING_UPLOADS {
+            attachments.offer("alice", "general", file(&[u8::try_from(i).unwrap()]));
+        }
+
+        assert!(!attachments.uploads.contains_key(&first));
+        assert!(attachments.uploads.contains_key(&other));
+        assert_eq!(attachments.uploads.len(), MAX_PENDING_UPLOADS + 1);
+    }
+
+    #[test]
+    fn chunked_upload_is_verified() {
+        let content = b"hello attachment";
+        let mut attachments = Attachments::default();
+        let (id, offset) = attachments.offer("alice", "general", file(content));
+        assert_eq!(offset, 0);
+
+        let result = attachments.write_chunk("alice", id, 0, &content[..5]);
+        assert!(matches!(result, Some(ChunkResult::Pending)));
+
+        // Resumed by the same user in another case
+        assert_eq!(
+            attachments.offer("Alice", "general", file(content)),
+            (id, 5)
+        );
+
+        let result = attachments.write_chunk("Alice", id, 5, &content[5..]);
+        assert!(matches!(result, Some(ChunkResult::Complete { .. })));
+        assert_eq!(attachments.get(id).unwrap().data.as_slice(), content);
+    }
+
+    #[test]
+    fn hash_mismatch_fails_upload() {
+        let mut attachments = Attachments::default();
+        let (id, _) = attachments.offer("alice", "general", file(b"expected"));
+
+        let result = attachments.write_chunk("alice", id, 0, b"tampered");
+
+        assert!(matches!(result, Some(ChunkResult::Failed { .. })));
+        assert!(attachments.get(id).is_none());
+    }
+}
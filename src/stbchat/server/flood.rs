@@ -0,0 +1,663 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::stbchat::object::AttachmentId;
+use crate::stbchat::packet::ServerPacket;
+
+/// Maximum amount of accepted uploads tracked per connection, the oldest one is forgotten first
+const MAX_TRANSFERS: usize = 16;
+
+/// A token bucket refilled with `per_second` tokens, holding at most `burst` tokens
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub per_second: u32,
+    pub burst: u32,
+}
+
+/// Action taken against a client once it reached a number of violations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FloodAction {
+    /// Tell the client to slow down using a `SystemMessage`
+    Warn,
+    /// Drop all chat packets of the client for the given time
+    Mute(Duration),
+    /// Close the connection
+    Disconnect,
+}
+
+/// # Flood protection limits
+/// Every packet except keep-alives counts as a message, every frame counts towards the byte limit.
+/// Chunks of an upload the server accepted are exempt as long as they arrive at the expected offset
+/// and don't exceed the offered size, all other chunks count like any other packet.
+/// Packets exceeding a limit are dropped and count as a violation of the client.
+///
+/// By default clients may send 5 messages per second with bursts of 10 messages
+/// and 256 KiB per second, and each IP address may open 16 connections.
+/// Clients are warned on their first violation, muted for 30 seconds on their fifth
+/// and disconnected on their tenth. Violations are forgiven after one minute without flooding
+#[derive(Debug, Clone)]
+pub struct FloodLimits {
+    messages: Option<RateLimit>,
+    bytes: Option<RateLimit>,
+    max_connections_per_ip: Option<usize>,
+    escalation: Vec<(u32, FloodAction)>,
+    forgive_after: Duration,
+}
+
+impl Default for FloodLimits {
+    fn default() -> Self {
+        Self {
+            messages: Some(RateLimit {
+                per_second: 5,
+                burst: 10,
+            }),
+            bytes: Some(RateLimit {
+                per_second: 256 * 1024,
+                burst: 256 * 1024,
+            }),
+            max_connections_per_ip: Some(16),
+            escalation: vec![
+                (1, FloodAction::Warn),
+                (5, FloodAction::Mute(Duration::from_secs(30))),
+                (10, FloodAction::Disconnect),
+            ],
+            forgive_after: Duration::from_mins(1),
+        }
+    }
+}
+
+impl FloodLimits {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limits which never drop a packet or reject a connection
+    #[must_use]
+    pub const fn unlimited() -> Self {
+        Self {
+            messages: None,
+            bytes: None,
+            max_connections_per_ip: None,
+            escalation: Vec::new(),
+            forgive_after: Duration::from_mins(1),
+        }
+    }
+
+    /// Limit the amount of packets per connection, `None` disables the limit
+    #[must_use]
+    pub const fn messages(mut self, messages: Option<RateLimit>) -> Self {
+        self.messages = messages;
+
+        self
+    }
+
+    /// Limit the amount of bytes per connection, `None` disables the limit.
+    /// A frame larger than the burst is only accepted while the bucket is full
+    #[must_use]
+    pub const fn bytes(mut self, bytes: Option<RateLimit>) -> Self {
+        self.bytes = bytes;
+
+        self
+    }
+
+    /// Limit the amount of simultaneous connections per IP address, `None` disables the limit.
+    /// Connections without an address (e.g. Unix domain sockets) are never limited
+    #[must_use]
+    pub const fn max_connections_per_ip(mut self, max_connections: Option<usize>) -> Self {
+        self.max_connections_per_ip = max_connections;
+
+        self
+    }
+
+    /// Take an action once a client reached the given number of violations,
+    /// replacing any action configured for the same number
+    #[must_use]
+    pub fn escalate(mut self, violations: u32, action: FloodAction) -> Self {
+        self.escalation.retain(|(count, _)| *count != violations);
+        self.escalation.push((violations, action));
+        self.escalation.sort_by_key(|(count, _)| *count);
+
+        self
+    }
+
+    /// Remove all escalation steps, packets exceeding a limit are dropped silently
+    #[must_use]
+    pub fn clear_escalation(mut self) -> Self {
+        self.escalation.clear();
+
+        self
+    }
+
+    /// Reset the violations of a client after it stopped flooding for the given time
+    #[must_use]
+    pub const fn forgive_after(mut self, forgive_after: Duration) -> Self {
+        self.forgive_after = forgive_after;
+
+        self
+    }
+
+    fn action(&self, violations: u32) -> Option<FloodAction> {
+        self.escalation
+            .iter()
+            .find(|(count, _)| *count == violations)
+            .map(|(_, action)| *action)
+    }
+}
+
+/// # Flood protection of the Strawberry Chat server
+/// Can be shared with the server to change the limits at runtime.
+/// New limits apply to the next packet of every connection,
+/// connection limits only apply to new connections
+/// ```no_run
+/// use std::sync::Arc;
+///
+/// use libstrawberry::stbchat::server::StbchatServer;
+/// use libstrawberry::stbchat::server::flood::{FloodLimits, FloodProtection, RateLimit};
+///
+/// # async fn example() -> eyre::Result<()> {
+/// let flood = Arc::new(FloodProtection::new(FloodLimits::new()));
+///
+/// tokio::spawn(StbchatServer::new().flood_protection(flood.clone()).listen("0.0.0.0:52800"));
+///
+/// // Slow mode
+/// flood.set_limits(FloodLimits::new().messages(Some(RateLimit {
+///     per_second: 1,
+///     burst: 2,
+/// })));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Default)]
+pub struct FloodProtection {
+    limits: RwLock<Arc<FloodLimits>>,
+    connections: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl FloodProtection {
+    #[must_use]
+    pub fn new(limits: FloodLimits) -> Self {
+        Self {
+            limits: RwLock::new(Arc::new(limits)),
+            connections: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the current limits
+    #[must_use]
+    pub fn limits(&self) -> Arc<FloodLimits> {
+        self.limits
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Replace the limits of all connections
+    pub fn set_limits(&self, limits: FloodLimits) {
+        *self.limits.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(limits);
+    }
+
+    /// Returns the amount of open connections of an IP address
+    #[must_use]
+    pub fn connections(&self, ip: IpAddr) -> usize {
+        self.connections
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&ip)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Count a new connection, returns `None` if its IP address reached the connection limit
+    pub(crate) fn admit(self: &Arc<Self>, addr: Option<SocketAddr>) -> Option<ConnectionPermit> {
+        let Some(ip) = addr.map(|addr| addr.ip()) else {
+            return Some(ConnectionPermit {
+                protection: self.clone(),
+                ip: None,
+            });
+        };
+
+        let max_connections = self.limits().max_connections_per_ip;
+        let mut connections = self
+            .connections
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let count = connections.get(&ip).copied().unwrap_or_default();
+
+        if max_connections.is_some_and(|max| count >= max) {
+            return None;
+        }
+
+        connections.insert(ip, count + 1);
+        drop(connections);
+
+        Some(ConnectionPermit {
+            protection: self.clone(),
+            ip: Some(ip),
+        })
+    }
+
+    /// Create the flood state of a new connection
+    pub(crate) fn guard(self: &Arc<Self>) -> FloodGuard {
+        FloodGuard {
+            protection: self.clone(),
+            messages: Bucket::default(),
+            bytes: Bucket::default(),
+            bytes_read: 0,
+            transfers: Vec::new(),
+            violations: 0,
+            last_violation: None,
+            muted_until: None,
+        }
+    }
+}
+
+/// Counts towards the connection limit of an IP address until dropped
+pub(crate) struct ConnectionPermit {
+    protection: Arc<FloodProtection>,
+    ip: Option<IpAddr>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let Some(ip) = self.ip else {
+            return;
+        };
+
+        let mut connections = self
+            .protection
+            .connections
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        if let Some(count) = connections.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(&ip);
+            }
+        }
+    }
+}
+
+/// Decision about a received packet
+pub(crate) enum Verdict {
+    Accept,
+    /// Drop the packet, optionally telling the client why
+    Reject(Option<String>),
+    Disconnect,
+}
+
+/// Flood state of a single connection
+pub(crate) struct FloodGuard {
+    protection: Arc<FloodProtection>,
+    messages: Bucket,
+    bytes: Bucket,
+    /// Bytes read from the connection at the last check
+    bytes_read: u64,
+    /// Accepted uploads, whose expected chunks don't count towards the limits
+    transfers: Vec<Transfer>,
+    violations: u32,
+    last_violation: Option<Instant>,
+    muted_until: Option<Instant>,
+}
+
+impl FloodGuard {
+    /// Check a received frame against the limits.
+    /// `bytes_read` is the total amount of bytes read from the connection,
+    /// `packet` is `None` for frames which could not be decoded
+    pub(crate) fn check(&mut self, bytes_read: u64, packet: Option<&ServerPacket>) -> Verdict {
+        let limits = self.protection.limits();
+        let now = Instant::now();
+
+        let mut bytes = bytes_read.saturating_sub(self.bytes_read);
+        self.bytes_read = bytes_read;
+
+        let transfer = packet.and_then(|packet| self.expected_chunk(packet));
+        if let Some((_, len)) = transfer {
+            bytes = bytes.saturating_sub(len);
+        }
+
+        if self
+            .last_violation
+            .is_some_and(|last| now.duration_since(last) >= limits.forgive_after)
+        {
+            self.violations = 0;
+            self.last_violation = None;
+        }
+
+        let within_limits = self.bytes.take(now, bytes, limits.bytes)
+            && (transfer.is_some()
+                || !packet.is_some_and(counts_as_message)
+                || self.messages.take(now, 1, limits.messages));
+
+        if !within_limits {
+            return self.violation(now, &limits);
+        }
+
+        if let Some((index, len)) = transfer {
+            self.transfers[index].advance(len);
+            if self.transfers[index].remaining == 0 {
+                self.transfers.remove(index);
+            }
+        }
+
+        match self.muted_until {
+            Some(until) if until > now && packet.is_some_and(is_chat) => {
+                let remaining = until.duration_since(now).as_secs() + 1;
+                Verdict::Reject(Some(format!("You are muted for {remaining} more seconds")))
+            }
+            _ => Verdict::Accept,
+        }
+    }
+
+    /// Exempt the chunks of an accepted upload from the limits, starting at the offset
+    /// the server expects next. Offering the same file again only restarts at that offset,
+    /// so it can't exempt more data than the upload still needs
+    pub(crate) fn allow_transfer(&mut self, id: AttachmentId, offset: u64, remaining: u64) {
+        self.end_transfer(id);
+
+        if remaining == 0 {
+            return;
+        }
+
+        if self.transfers.len() >= MAX_TRANSFERS {
+            self.transfers.remove(0);
+        }
+
+        self.transfers.push(Transfer {
+            id,
+            offset,
+            remaining,
+        });
+    }
+
+    /// Stop exempting the chunks of an upload, e.g. because it failed
+    pub(crate) fn end_transfer(&mut self, id: AttachmentId) {
+        self.transfers.retain(|transfer| transfer.id != id);
+    }
+
+    /// Returns the index of the transfer and the size of the chunk
+    /// if the packet is the next expected chunk of an accepted upload
+    fn expected_chunk(&self, packet: &ServerPacket) -> Option<(usize, u64)> {
+        let ServerPacket::AttachmentChunk { id, offset, data } = packet else {
+            return None;
+        };
+
+        let len = data.len() as u64;
+        let index = self.transfers.iter().position(|transfer| {
+            transfer.id == *id && transfer.offset == *offset && transfer.remaining >= len
+        })?;
+
+        Some((index, len))
+    }
+
+    fn violation(&mut self, now: Instant, limits: &FloodLimits) -> Verdict {
+        self.violations = self.violations.saturating_add(1);
+        self.last_violation = Some(now);
+
+        match limits.action(self.violations) {
+            None => Verdict::Reject(None),
+            Some(FloodAction::Warn) => Verdict::Reject(Some(
+                "You are sending too fast, please slow down".to_string(),
+            )),
+            Some(FloodAction::Mute(duration)) => {
+                self.muted_until = Some(now + duration);
+                Verdict::Reject(Some(format!(
+                    "You were muted for {} seconds for flooding",
+                    duration.as_secs()
+                )))
+            }
+            Some(FloodAction::Disconnect) => Verdict::Disconnect,
+        }
+    }
+}
+
+/// Next expected chunk of an accepted upload
+struct Transfer {
+    id: AttachmentId,
+    offset: u64,
+    remaining: u64,
+}
+
+impl Transfer {
+    const fn advance(&mut self, len: u64) {
+        self.offset += len;
+        self.remaining -= len;
+    }
+}
+
+#[derive(Default)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Option<Instant>,
+}
+
+impl Bucket {
+    /// Refill the bucket and try to take `cost` tokens
+    fn take(&mut self, now: Instant, cost: u64, limit: Option<RateLimit>) -> bool {
+        let Some(limit) = limit else {
+            return true;
+        };
+
+        let burst = f64::from(limit.burst);
+        self.tokens = match self.last_refill {
+            Some(last) => {
+                let refill = now.duration_since(last).as_secs_f64() * f64::from(limit.per_second);
+                (self.tokens + refill).min(burst)
+            }
+            None => burst,
+        };
+        self.last_refill = Some(now);
+
+        // Frames larger than the burst are accepted from a full bucket
+        let cost = f64::from(u32::try_from(cost).unwrap_or(u32::MAX)).min(burst);
+        if self.tokens < cost {
+            return false;
+        }
+
+        self.tokens -= cost;
+        true
+    }
+}
+
+const fn counts_as_message(packet: &ServerPacket) -> bool {
+    !matches!(packet, ServerPacket::KeepAlive)
+}
+
+/// Packets reaching other users, which are dropped while a client is muted
+const fn is_chat(packet: &ServerPacket) -> bool {
+    matches!(
+        packet,
+        ServerPacket::Message { .. }
+            | ServerPacket::DirectMessage { .. }
+            | ServerPacket::EncryptedDirectMessage { .. }
+            | ServerPacket::EditMessage { .. }
+            | ServerPacket::AddReaction { .. }
+            | ServerPacket::RemoveReaction { .. }
+            | ServerPacket::OfferAttachment { .. }
+            | ServerPacket::TypingStart { .. }
+            | ServerPacket::Extension { .. }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn guard(limits: FloodLimits) -> FloodGuard {
+        Arc::new(FloodProtection::new(limits)).guard()
+    }
+
+    fn chunk(id: AttachmentId, offset: u64, len: usize) -> ServerPacket {
+        ServerPacket::AttachmentChunk {
+            id,
+            offset,
+            data: vec![0; len],
+        }
+    }
+
+    /// Check a packet of the given size, counting the bytes read so far
+    fn check(guard: &mut FloodGuard, read: &mut u64, len: u64, packet: &ServerPacket) -> Verdict {
+        *read += len;
+        guard.check(*read, Some(packet))
+    }
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let limit = Some(RateLimit {
+            per_second: 2,
+            burst: 4,
+        });
+        let start = Instant::now();
+        let mut bucket = Bucket::default();
+
+        assert!(bucket.take(start, 4, limit));
+        assert!(!bucket.take(start, 1, limit));
+        assert!(bucket.take(start + SECOND, 2, limit));
+        assert!(!bucket.take(start + SECOND, 1, limit));
+        // Never refilled beyond the burst
+        assert!(bucket.take(start + 10 * SECOND, 4, limit));
+        assert!(!bucket.take(start + 10 * SECOND, 1, limit));
+    }
+
+    #[test]
+    fn bucket_accepts_large_frames_when_full() {
+        let limit = Some(RateLimit {
+            per_second: 1,
+            burst: 10,
+        });
+        let start = Instant::now();
+        let mut bucket = Bucket::default();
+
+        assert!(bucket.take(start, 100, limit));
+        assert!(!bucket.take(start, 100, limit));
+        assert!(bucket.take(start, 100, None));
+    }
+
+    #[test]
+    fn violations_escalate() {
+        let limits = FloodLimits::unlimited()
+            .messages(Some(RateLimit {
+                per_second: 1,
+                burst: 1,
+            }))
+            .escalate(1, FloodAction::Warn)
+            .escalate(2, FloodAction::Mute(Duration::from_secs(30)))
+            .escalate(4, FloodAction::Disconnect);
+        let mut guard = guard(limits);
+        let packet = ServerPacket::ListRooms;
+
+        assert!(matches!(guard.check(0, Some(&packet)), Verdict::Accept));
+        assert!(
+            matches!(guard.check(0, Some(&packet)), Verdict::Reject(Some(message)) if message.contains("slow down"))
+        );
+        assert!(
+            matches!(guard.check(0, Some(&packet)), Verdict::Reject(Some(message)) if message.contains("muted"))
+        );
+        assert!(matches!(
+            guard.check(0, Some(&packet)),
+            Verdict::Reject(None)
+        ));
+        assert!(matches!(guard.check(0, Some(&packet)), Verdict::Disconnect));
+        assert!(guard.muted_until.is_some());
+    }
+
+    #[test]
+    fn keep_alives_are_not_limited() {
+        let limits = FloodLimits::unlimited().messages(Some(RateLimit {
+            per_second: 1,
+            burst: 1,
+        }));
+        let mut guard = guard(limits);
+
+        for _ in 0..10 {
+            assert!(matches!(
+                guard.check(0, Some(&ServerPacket::KeepAlive)),
+                Verdict::Accept
+            ));
+        }
+    }
+
+    fn transfer_limits() -> FloodLimits {
+        FloodLimits::unlimited()
+            .messages(Some(RateLimit {
+                per_second: 1,
+                burst: 1,
+            }))
+            .bytes(Some(RateLimit {
+                per_second: 1,
+                burst: 1024,
+            }))
+    }
+
+    #[test]
+    fn expected_chunks_are_exempt() {
+        let mut guard = guard(transfer_limits());
+        let mut read = 0;
+        guard.allow_transfer(1, 0, 8192);
+
+        for offset in (0..8192).step_by(2048) {
+            let verdict = check(&mut guard, &mut read, 2048, &chunk(1, offset, 2048));
+            assert!(matches!(verdict, Verdict::Accept));
+        }
+
+        // The upload is complete, further chunks count as usual
+        let verdict = check(&mut guard, &mut read, 2048, &chunk(1, 8192, 2048));
+        assert!(matches!(verdict, Verdict::Accept));
+        let verdict = check(&mut guard, &mut read, 2048, &chunk(1, 10240, 2048));
+        assert!(matches!(verdict, Verdict::Reject(_)));
+    }
+
+    #[test]
+    fn unexpected_chunks_are_charged() {
+        let mut guard = guard(transfer_limits());
+        let mut read = 0;
+        guard.allow_transfer(1, 0, 8192);
+
+        // Wrong offset and unknown upload use up the message and byte limits
+        let verdict = check(&mut guard, &mut read, 16, &chunk(1, 4096, 16));
+        assert!(matches!(verdict, Verdict::Accept));
+        let verdict = check(&mut guard, &mut read, 16, &chunk(2, 0, 16));
+        assert!(matches!(verdict, Verdict::Reject(_)));
+
+        // The expected chunk is still exempt
+        let verdict = check(&mut guard, &mut read, 2048, &chunk(1, 0, 2048));
+        assert!(matches!(verdict, Verdict::Accept));
+    }
+
+    #[test]
+    fn offering_again_restarts_at_server_offset() {
+        let mut guard = guard(transfer_limits());
+        let mut read = 0;
+        guard.allow_transfer(1, 0, 4096);
+
+        let verdict = check(&mut guard, &mut read, 4096, &chunk(1, 0, 4096));
+        assert!(matches!(verdict, Verdict::Accept));
+
+        // The server received nothing, offering again only allows the same range
+        guard.allow_transfer(1, 0, 4096);
+        let verdict = check(&mut guard, &mut read, 4096, &chunk(1, 0, 4096));
+        assert!(matches!(verdict, Verdict::Accept));
+        let verdict = check(&mut guard, &mut read, 4096, &chunk(1, 4096, 4096));
+        assert!(matches!(verdict, Verdict::Accept));
+        let verdict = check(&mut guard, &mut read, 4096, &chunk(1, 8192, 4096));
+        assert!(matches!(verdict, Verdict::Reject(_)));
+    }
+
+    #[test]
+    fn ended_transfers_are_charged() {
+        let mut guard = guard(transfer_limits());
+        let mut read = 0;
+        guard.allow_transfer(1, 0, 8192);
+        guard.end_transfer(1);
+
+        let verdict = check(&mut guard, &mut read, 4096, &chunk(1, 0, 4096));
+        assert!(matches!(verdict, Verdict::Accept));
+        let verdict = check(&mut guard, &mut read, 4096, &chunk(1, 4096, 4096));
+        assert!(matches!(verdict, Verdict::Reject(_)));
+    }
+}
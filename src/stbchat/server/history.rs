@@ -0,0 +1,301 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Mutex, PoisonError, RwLock};
+
+use serde::{Deserialize, Serialize};
+
+use crate::stbchat::messages::ChatMessage;
+use crate::stbchat::object::{HistoryQuery, MessageId, MessageUpdate};
+
+/// Amount of messages per room kept by the built-in history stores by default
+pub const DEFAULT_ROOM_CAPACITY: usize = 10_000;
+
+/// A page of the message history of a room
+#[derive(Debug, Default)]
+pub struct HistoryPage {
+    /// Messages of the page, oldest first
+    pub messages: Vec<ChatMessage>,
+    /// Whether more messages are available in the queried direction
+    pub has_more: bool,
+}
+
+/// # Pluggable message history storage for the Strawberry Chat server
+/// Holds the messages of all rooms including their edits and reactions.
+/// Replies, edits and reactions are only accepted for messages that are still stored
+pub trait HistoryStore: Send + Sync {
+    /// Store a new message
+    /// # Errors
+    /// - Will return `Err` if the message could not be persisted
+    fn append(&self, message: &ChatMessage) -> std::io::Result<()>;
+
+    /// Apply an edit, deletion or reaction to a stored message
+    /// # Errors
+    /// - Will return `Err` if the update could not be persisted
+    fn update(&self, update: &MessageUpdate) -> std::io::Result<()>;
+
+    /// Returns a stored message, if existing
+    fn get(&self, id: MessageId) -> Option<ChatMessage>;
+
+    /// Returns up to `limit` messages of a room
+    fn query(&self, room: &str, query: HistoryQuery, limit: usize) -> HistoryPage;
+
+    /// Highest message id ever stored, used to continue numbering after a restart
+    fn last_id(&self) -> MessageId;
+}
+
+#[derive(Default)]
+struct History {
+    rooms: HashMap<String, BTreeMap<MessageId, ChatMessage>>,
+    message_rooms: HashMap<MessageId, String>,
+    last_id: MessageId,
+}
+
+impl History {
+    fn append(&mut self, message: ChatMessage, capacity: usize) {
+        self.last_id = self.last_id.max(message.id);
+        self.message_rooms.insert(message.id, message.room.clone());
+
+        let room = self.rooms.entry(message.room.clone()).or_default();
+        room.insert(message.id, message);
+
+        while room.len() > capacity {
+            if let Some((oldest, _)) = room.pop_first() {
+                self.message_rooms.remove(&oldest);
+            }
+        }
+    }
+
+    fn update(&mut self, update: &MessageUpdate) {
+        let id = update.id();
+        let Some(room) = self.message_rooms.get(&id) else {
+            return;
+        };
+        let Some(messages) = self.rooms.get_mut(room) else {
+            return;
+        };
+
+        if let MessageUpdate::Deleted { .. } = update {
+            messages.remove(&id);
+            self.message_rooms.remove(&id);
+        } else if let Some(message) = messages.get_mut(&id) {
+            message.apply(update);
+        }
+    }
+
+    fn get(&self, id: MessageId) -> Option<ChatMessage> {
+        let room = self.message_rooms.get(&id)?;
+        self.rooms.get(room)?.get(&id).cloned()
+    }
+
+    fn query(&self, room: &str, query: HistoryQuery, limit: usize) -> HistoryPage {
+        let Some(messages) = self.rooms.get(room) else {
+            return HistoryPage::default();
+        };
+
+        // Take one more message than requested to find out whether there are more
+        let mut page: Vec<ChatMessage> = match query {
+            HistoryQuery::Latest => messages.values().rev().take(limit + 1).cloned().collect(),
+            HistoryQuery::Before { id } => messages
+                .range(..id)
+                .rev()
+                .take(limit + 1)
+                .map(|(_, message)| message.clone())
+                .collect(),
+            HistoryQuery::After { id } => messages
+                .range(id.saturating_add(1)..)
+                .take(limit + 1)
+                .map(|(_, message)| message.clone())
+                .collect(),
+        };
+
+        let has_more = page.len() > limit;
+        page.truncate(limit);
+
+        if !matches!(query, HistoryQuery::After { .. }) {
+            page.reverse();
+        }
+
+        HistoryPage {
+            messages: page,
+            has_more,
+        }
+    }
+}
+
+/// In-memory history store, intended for integration tests and local development.
+/// Keeps the most recent messages of every room, all messages are lost once the server stops
+pub struct MemoryHistoryStore {
+    history: RwLock<History>,
+    capacity: usize,
+}
+
+impl Default for MemoryHistoryStore {
+    fn default() -> Self {
+        Self::with_capacity(DEFAULT_ROOM_CAPACITY)
+    }
+}
+
+impl MemoryHistoryStore {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a store that keeps at most `capacity` messages per room
+    #[must_use]
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            history: RwLock::new(History::default()),
+            capacity,
+        }
+    }
+}
+
+impl HistoryStore for MemoryHistoryStore {
+    fn append(&self, message: &ChatMessage) -> std::io::Result<()> {
+        self.history
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .append(message.clone(), self.capacity);
+
+        Ok(())
+    }
+
+    fn update(&self, update: &MessageUpdate) -> std::io::Result<()> {
+        self.history
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .update(update);
+
+        Ok(())
+    }
+
+    fn get(&self, id: MessageId) -> Option<ChatMessage> {
+        self.history
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(id)
+    }
+
+    fn query(&self, room: &str, query: HistoryQuery, limit: usize) -> HistoryPage {
+        self.history
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .query(room, query, limit)
+    }
+
+    fn last_id(&self) -> MessageId {
+        self.history
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .last_id
+    }
+}
+
+/// A line of the history file
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "record")]
+enum Record {
+    #[serde(rename = "message")]
+    Message { message: ChatMessage },
+    #[serde(rename = "update")]
+    Update { update: MessageUpdate },
+}
+
+/// # Append-only file history store
+/// Every message and update is appended to a file as a line of JSON.
+/// The file is replayed when opening the store, so the history survives restarts.
+/// Lines that can't be parsed (e.g. after a crash during a write) are skipped.
+/// The most recent messages of every room are kept in memory for queries, the file itself is never truncated
+/// ```no_run
+/// use libstrawberry::stbchat::server::StbchatServer;
+/// use libstrawberry::stbchat::server::history::FileHistoryStore;
+///
+/// # async fn example() -> eyre::Result<()> {
+/// StbchatServer::new()
+///     .history_store(FileHistoryStore::open("history.jsonl")?)
+///     .listen("127.0.0.1:52800")
+///     .await
+/// # }
+/// ```
+pub struct FileHistoryStore {
+    memory: MemoryHistoryStore,
+    file: Mutex<File>,
+}
+
+impl FileHistoryStore {
+    /// Open or create a history file, keeping the default amount of messages per room in memory
+    /// # Errors
+    /// - Will return `Err` if the file could not be opened or read
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Self::open_with_capacity(path, DEFAULT_ROOM_CAPACITY)
+    }
+
+    /// Open or create a history file, keeping at most `capacity` messages per room in memory
+    /// # Errors
+    /// - Will return `Err` if the file could not be opened or read
+    pub fn open_with_capacity(path: impl AsRef<Path>, capacity: usize) -> std::io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+
+        let mut history = History::default();
+
+        for line in BufReader::new(&mut file).lines() {
+            match serde_json::from_str(&line?) {
+                Ok(Record::Message { message }) => history.append(message, capacity),
+                Ok(Record::Update { update }) => history.update(&update),
+                Err(_) => {}
+            }
+        }
+
+        Ok(Self {
+            memory: MemoryHistoryStore {
+                history: RwLock::new(history),
+                capacity,
+            },
+            file: Mutex::new(file),
+        })
+    }
+
+    fn write(&self, record: &Record) -> std::io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        file.write_all(&line)?;
+        file.flush()
+    }
+}
+
+impl HistoryStore for FileHistoryStore {
+    fn append(&self, message: &ChatMessage) -> std::io::Result<()> {
+        self.write(&Record::Message {
+            message: message.clone(),
+        })?;
+        self.memory.append(message)
+    }
+
+    fn update(&self, update: &MessageUpdate) -> std::io::Result<()> {
+        self.write(&Record::Update {
+            update: update.clone(),
+        })?;
+        self.memory.update(update)
+    }
+
+    fn get(&self, id: MessageId) -> Option<ChatMessage> {
+        self.memory.get(id)
+    }
+
+    fn query(&self, room: &str, query: HistoryQuery, limit: usize) -> HistoryPage {
+        self.memory.query(room, query, limit)
+    }
+
+    fn last_id(&self) -> MessageId {
+        self.memory.last_id()
+    }
+}
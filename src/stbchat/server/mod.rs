@@ -0,0 +1,1724 @@
+#![cfg(not(feature = "stbchat-sync"))]
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, PoisonError};
+
+use tokio::io::{AsyncRead, AsyncWrite, split};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::net::{TcpListener, ToSocketAddrs};
+use tokio::sync::Mutex;
+
+#[cfg(feature = "strawberryid")]
+use crate::id::credentials::StrawberryIdCredentials;
+use crate::stbchat::attachment::{self, CHUNK_SIZE, DEFAULT_MAX_ATTACHMENT_SIZE};
+use crate::stbchat::capture::{CaptureTap, Side};
+#[cfg(feature = "stbchat-compression")]
+use crate::stbchat::compression::{Compression, CompressionAlgorithm};
+use crate::stbchat::error::{AccountError, AttachmentError, CommunicationError};
+use crate::stbchat::extension;
+use crate::stbchat::format::WireFormat;
+use crate::stbchat::messages::ChatMessage;
+use crate::stbchat::metrics::Metrics;
+use crate::stbchat::net::{IncomingPacketStream, OutgoingPacketStream};
+use crate::stbchat::object::{
+    Attachment, AttachmentId, DEFAULT_ROOM, DeliveryStatus, ExtensionTarget, HistoryQuery,
+    MessageId, MessageUpdate, PUBLIC_KEY_LENGTH, Presence, PresenceStatus, Room, StbchatApiRequest,
+    StbchatApiResponse, UploadStatus, User, UserMeta,
+};
+use crate::stbchat::packet::{ClientPacket, ServerPacket};
+use crate::stbchat::queue::{PacketQueue, QueueConfig};
+use crate::stbchat::server::accounts::AccountRegistry;
+use crate::stbchat::server::attachments::{Attachments, ChunkResult};
+use crate::stbchat::server::flood::{
+    ConnectionPermit, FloodGuard, FloodLimits, FloodProtection, Verdict,
+};
+use crate::stbchat::server::history::{HistoryPage, HistoryStore, MemoryHistoryStore};
+use crate::stbchat::server::store::{MemoryUserStore, UserStore};
+#[cfg(feature = "strawberryid")]
+use crate::stbchat::server::token::{StrawberryIdTokenVerifier, TokenVerifier};
+#[cfg(feature = "stbchat-tls")]
+use crate::stbchat::tls::{self, TlsAcceptor};
+use crate::stbchat::transport::{BoxedReader, BoxedWriter, Listener};
+#[cfg(feature = "stbchat-websocket")]
+use crate::stbchat::websocket;
+use crate::string::{contains_whitespace, is_empty_or_whitespace};
+
+pub mod accounts;
+mod attachments;
+pub mod flood;
+pub mod history;
+pub mod store;
+pub mod token;
+
+/// Callback for validating login credentials (username, password).
+/// Overrides the password check of the user store
+pub type AuthCallback = Arc<dyn Fn(&str, &str) -> bool + Send + Sync>;
+
+/// Maximum length of a room name
+const MAX_ROOM_NAME_LENGTH: usize = 32;
+
+/// Maximum length of a reaction emoji in bytes
+const MAX_EMOJI_LENGTH: usize = 32;
+
+/// Maximum amount of messages in a history page
+const MAX_HISTORY_PAGE: usize = 100;
+
+/// Maximum length of an attachment's file name in bytes
+const MAX_ATTACHMENT_NAME_LENGTH: usize = 255;
+
+/// Maximum length of a custom status text in bytes
+const MAX_STATUS_TEXT_LENGTH: usize = 128;
+
+/// A logged in client
+struct Session {
+    user: User,
+    writer: PacketQueue,
+    rooms: HashSet<String>,
+}
+
+struct ServerState {
+    accounts: Arc<AccountRegistry>,
+    auth: Option<AuthCallback>,
+    #[cfg(feature = "strawberryid")]
+    token_verifier: Arc<dyn TokenVerifier>,
+    flood: Arc<FloodProtection>,
+    metrics: Arc<Metrics>,
+    send_queue: QueueConfig,
+    sessions: Mutex<HashMap<u64, Session>>,
+    next_id: AtomicU64,
+    /// Id of the next message, locked while writing to the history
+    next_message_id: Arc<std::sync::Mutex<MessageId>>,
+    history: Arc<dyn HistoryStore>,
+    attachments: Mutex<Attachments>,
+    /// Public keys published for end-to-end encrypted direct messages by lowercase username,
+    /// usernames are case-insensitive like in the account store
+    public_keys: Mutex<HashMap<String, Vec<u8>>>,
+    max_attachment_size: u64,
+    capture_dir: Option<PathBuf>,
+    #[cfg(feature = "stbchat-tls")]
+    tls: Option<TlsAcceptor>,
+    #[cfg(feature = "stbchat-compression")]
+    compression: Option<Compression>,
+}
+
+/// Framing spoken on a listener
+#[derive(Clone, Copy)]
+enum Framing {
+    /// Length prefixed frames directly on the (TLS) connection
+    Raw,
+    /// One binary WebSocket message per frame
+    #[cfg(feature = "stbchat-websocket")]
+    WebSocket,
+}
+
+/// # Reference Strawberry Chat server
+/// Accepts TCP and WebSocket connections, authenticates users and broadcasts their messages to every member of a room.
+/// Every user joins the default room after logging in.
+/// Intended for integration tests, local development and small deployments.
+/// ```no_run
+/// use libstrawberry::stbchat::server::StbchatServer;
+///
+/// # async fn example() -> eyre::Result<()> {
+/// StbchatServer::new()
+///     .auth(|username, password| username == "admin" && password == "admin")
+///     .listen("127.0.0.1:52800")
+///     .await
+/// # }
+/// ```
+pub struct StbchatServer {
+    accounts: Arc<AccountRegistry>,
+    history: Arc<dyn HistoryStore>,
+    max_attachment_size: u64,
+    capture_dir: Option<PathBuf>,
+    auth: Option<AuthCallback>,
+    #[cfg(feature = "strawberryid")]
+    token_verifier: Arc<dyn TokenVerifier>,
+    flood: Arc<FloodProtection>,
+    metrics: Arc<Metrics>,
+    send_queue: QueueConfig,
+    #[cfg(feature = "stbchat-tls")]
+    tls: Option<TlsAcceptor>,
+    #[cfg(feature = "stbchat-compression")]
+    compression: Option<Compression>,
+}
+
+impl Default for StbchatServer {
+    fn default() -> Self {
+        Self {
+            accounts: Arc::new(AccountRegistry::new(MemoryUserStore::new())),
+            history: Arc::new(MemoryHistoryStore::new()),
+            max_attachment_size: DEFAULT_MAX_ATTACHMENT_SIZE,
+            capture_dir: None,
+            auth: None,
+            #[cfg(feature = "strawberryid")]
+            token_verifier: Arc::new(StrawberryIdTokenVerifier::new()),
+            flood: Arc::new(FloodProtection::default()),
+            metrics: Arc::new(Metrics::new()),
+            send_queue: QueueConfig::default(),
+            #[cfg(feature = "stbchat-tls")]
+            tls: None,
+            #[cfg(feature = "stbchat-compression")]
+            compression: None,
+        }
+    }
+}
+
+impl StbchatServer {
+    /// Create a new server using an in-memory user and history store
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use a custom user store with the default account rules
+    #[must_use]
+    pub fn user_store(mut self, user_store: impl UserStore + 'static) -> Self {
+        self.accounts = Arc::new(AccountRegistry::new(user_store));
+
+        self
+    }
+
+    /// Use a configured account registry, which can be kept to change accounts at runtime
+    #[must_use]
+    pub fn accounts(mut self, accounts: Arc<AccountRegistry>) -> Self {
+        self.accounts = accounts;
+
+        self
+    }
+
+    /// Use a custom message history store
+    #[must_use]
+    pub fn history_store(mut self, history: impl HistoryStore + 'static) -> Self {
+        self.history = Arc::new(history);
+
+        self
+    }
+
+    /// Limit the size of uploaded attachments
+    #[must_use]
+    pub const fn max_attachment_size(mut self, max_attachment_size: u64) -> Self {
+        self.max_attachment_size = max_attachment_size;
+
+        self
+    }
+
+    /// Record the packets of every connection into `<dir>/connection-<id>.stbcap`.
+    /// Intended for debugging, captures contain passwords in plain text
+    #[must_use]
+    pub fn capture(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.capture_dir = Some(dir.into());
+
+        self
+    }
+
+    /// Use a custom callback for validating login credentials
+    #[must_use]
+    pub fn auth(mut self, callback: impl Fn(&str, &str) -> bool + Send + Sync + 'static) -> Self {
+        self.auth = Some(Arc::new(callback));
+
+        self
+    }
+
+    /// Verify Strawberry ID token logins with a custom verifier,
+    /// e.g. `StrawberryIdTokenVerifier` with a self-hosted endpoint
+    #[cfg(feature = "strawberryid")]
+    #[must_use]
+    pub fn token_verifier(mut self, verifier: impl TokenVerifier + 'static) -> Self {
+        self.token_verifier = Arc::new(verifier);
+
+        self
+    }
+
+    /// Use custom flood protection limits
+    #[must_use]
+    pub fn flood_limits(mut self, limits: FloodLimits) -> Self {
+        self.flood = Arc::new(FloodProtection::new(limits));
+
+        self
+    }
+
+    /// Use a flood protection, which can be kept to change the limits at runtime
+    #[must_use]
+    pub fn flood_protection(mut self, flood: Arc<FloodProtection>) -> Self {
+        self.flood = flood;
+
+        self
+    }
+
+    /// Count the packets and connections of all clients in the given metrics
+    #[must_use]
+    pub fn metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+
+        self
+    }
+
+    /// Configure the outgoing packet queue of every connection.
+    /// By default the oldest packets of slow clients are dropped
+    #[must_use]
+    pub const fn send_queue(mut self, config: QueueConfig) -> Self {
+        self.send_queue = config;
+
+        self
+    }
+
+    /// Require clients to connect using TLS
+    #[cfg(feature = "stbchat-tls")]
+    #[must_use]
+    pub fn tls(mut self, acceptor: TlsAcceptor) -> Self {
+        self.tls = Some(acceptor);
+
+        self
+    }
+
+    /// Offer per-frame compression to clients. Clients that don't support
+    /// the algorithm keep using uncompressed frames
+    #[cfg(feature = "stbchat-compression")]
+    #[must_use]
+    pub const fn compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+
+        self
+    }
+
+    /// Bind to the given address and serve clients until an error occurs
+    /// # Errors
+    /// - Will return `Err` if the address could not be bound
+    /// - Will return `Err` if accepting a connection fails
+    pub async fn listen(self, addr: impl ToSocketAddrs) -> eyre::Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        self.serve(listener).await
+    }
+
+    /// Serve clients on an already bound listener
+    /// # Errors
+    /// - Will return `Err` if accepting a connection fails
+    pub async fn serve(self, listener: TcpListener) -> eyre::Result<()> {
+        self.serve_listener(listener).await
+    }
+
+    /// Bind to a Unix domain socket and serve local clients until an error occurs
+    /// # Errors
+    /// - Will return `Err` if the socket could not be bound (e.g. because the file already exists)
+    /// - Will return `Err` if accepting a connection fails
+    #[cfg(unix)]
+    pub async fn listen_unix(self, path: impl AsRef<Path>) -> eyre::Result<()> {
+        let listener = UnixListener::bind(path)?;
+        self.serve_listener(listener).await
+    }
+
+    /// Serve clients on any listener, e.g. a Unix domain socket or an in-memory listener
+    /// # Errors
+    /// - Will return `Err` if accepting a connection fails
+    pub async fn serve_listener(self, listener: impl Listener) -> eyre::Result<()> {
+        accept_clients(self.into_state(), listener, Framing::Raw).await
+    }
+
+    /// Bind to the given address and serve WebSocket clients (e.g. browsers) until an error occurs.
+    /// If TLS is enabled, clients have to connect using `wss://`
+    /// # Errors
+    /// - Will return `Err` if the address could not be bound
+    /// - Will return `Err` if accepting a connection fails
+    #[cfg(feature = "stbchat-websocket")]
+    pub async fn listen_websocket(self, addr: impl ToSocketAddrs) -> eyre::Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        self.serve_websocket(listener).await
+    }
+
+    /// Serve WebSocket clients on an already bound listener
+    /// # Errors
+    /// - Will return `Err` if accepting a connection fails
+    #[cfg(feature = "stbchat-websocket")]
+    pub async fn serve_websocket(self, listener: impl Listener) -> eyre::Result<()> {
+        accept_clients(self.into_state(), listener, Framing::WebSocket).await
+    }
+
+    /// Serve raw clients and WebSocket clients at the same time.
+    /// Both kinds of clients share the same rooms and can chat with each other
+    /// ```no_run
+    /// use libstrawberry::stbchat::server::StbchatServer;
+    /// use tokio::net::TcpListener;
+    ///
+    /// # async fn example() -> eyre::Result<()> {
+    /// StbchatServer::new()
+    ///     .serve_with_websocket(
+    ///         TcpListener::bind("0.0.0.0:52800").await?,
+    ///         TcpListener::bind("0.0.0.0:52810").await?,
+    ///     )
+    ///     .await
+    /// # }
+    /// ```
+    /// # Errors
+    /// - Will return `Err` if accepting a connection fails on either listener
+    #[cfg(feature = "stbchat-websocket")]
+    pub async fn serve_with_websocket(
+        self,
+        listener: impl Listener,
+        websocket_listener: impl Listener,
+    ) -> eyre::Result<()> {
+        let state = self.into_state();
+
+        tokio::try_join!(
+            accept_clients(state.clone(), listener, Framing::Raw),
+            accept_clients(state, websocket_listener, Framing::WebSocket),
+        )?;
+
+        Ok(())
+    }
+
+    fn into_state(self) -> Arc<ServerState> {
+        Arc::new(ServerState {
+            accounts: self.accounts,
+            auth: self.auth,
+            #[cfg(feature = "strawberryid")]
+            token_verifier: self.token_verifier,
+            flood: self.flood,
+            metrics: self.metrics,
+            send_queue: self.send_queue,
+            sessions: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            next_message_id: Arc::new(std::sync::Mutex::new(self.history.last_id() + 1)),
+            history: self.history,
+            attachments: Mutex::new(Attachments::default()),
+            public_keys: Mutex::new(HashMap::new()),
+            max_attachment_size: self.max_attachment_size,
+            capture_dir: self.capture_dir,
+            #[cfg(feature = "stbchat-tls")]
+            tls: self.tls,
+            #[cfg(feature = "stbchat-compression")]
+            compression: self.compression,
+        })
+    }
+}
+
+async fn accept_clients<L: Listener>(
+    state: Arc<ServerState>,
+    mut listener: L,
+    framing: Framing,
+) -> eyre::Result<()> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+
+        // Connections exceeding the limit of their IP address are closed right away
+        let Some(permit) = state.flood.admit(addr) else {
+            continue;
+        };
+
+        tokio::spawn(accept_client(state.clone(), stream, framing, permit));
+    }
+}
+
+/// Perform the TLS and WebSocket handshakes of a new connection
+async fn accept_client<S: AsyncRead + AsyncWrite + Send + Unpin + 'static>(
+    state: Arc<ServerState>,
+    stream: S,
+    framing: Framing,
+    _permit: ConnectionPermit,
+) {
+    #[cfg(feature = "stbchat-tls")]
+    if let Some(acceptor) = state.tls.clone() {
+        if let Ok(stream) = tls::accept(&acceptor, stream).await {
+            accept_framing(state, stream, framing).await;
+        }
+        return;
+    }
+
+    accept_framing(state, stream, framing).await;
+}
+
+async fn accept_framing<S: AsyncRead + AsyncWrite + Send + Unpin + 'static>(
+    state: Arc<ServerState>,
+    stream: S,
+    framing: Framing,
+) {
+    match framing {
+        Framing::Raw => handle_connection(state, stream).await,
+        #[cfg(feature = "stbchat-websocket")]
+        Framing::WebSocket => {
+            if let Ok(stream) = websocket::accept(stream).await {
+                handle_connection(state, stream).await;
+            }
+        }
+    }
+}
+
+// Dispatches every packet type of a logged in client
+#[allow(clippy::too_many_lines)]
+async fn handle_connection<S: AsyncRead + AsyncWrite + Send + 'static>(
+    state: Arc<ServerState>,
+    stream: S,
+) {
+    let id = state.next_id.fetch_add(1, Ordering::Relaxed);
+    let _connection = state.metrics.track_connection();
+    let (r_client, w_client) = split(stream);
+
+    let mut r_client = IncomingPacketStream::wrap(Box::new(r_client) as BoxedReader);
+    let mut w_client = OutgoingPacketStream::wrap(Box::new(w_client) as BoxedWriter);
+    r_client.set_metrics(Some(state.metrics.clone()));
+    w_client.set_metrics(Some(state.metrics.clone()));
+
+    if let Some(dir) = &state.capture_dir {
+        let tap =
+            CaptureTap::create(dir.join(format!("connection-{id}.stbcap")), Side::Server).ok();
+        r_client.set_tap(tap.clone());
+        w_client.set_tap(tap);
+    }
+    let writer = PacketQueue::spawn(w_client, state.send_queue);
+    let mut flood = state.flood.guard();
+
+    if let Some(packet) = capabilities(&state)
+        && writer.send(packet).await.is_err()
+    {
+        return;
+    }
+
+    let Some(mut user) = authenticate(&state, &mut r_client, &mut flood, &writer).await else {
+        return;
+    };
+
+    log_in(&state, id, &mut user, &writer).await;
+
+    while let Some(packet) = next_packet(&state, &mut r_client, &mut flood, &writer).await {
+        match packet {
+            ServerPacket::Message {
+                message,
+                room,
+                reply_to,
+            } => {
+                post_message(&state, id, &user, &writer, message, room, reply_to).await;
+            }
+            packet @ (ServerPacket::EditMessage { .. }
+            | ServerPacket::DeleteMessage { .. }
+            | ServerPacket::AddReaction { .. }
+            | ServerPacket::RemoveReaction { .. }) => {
+                if let Some(update) = requested_update(&user, packet) {
+                    update_message(&state, id, &user, &writer, update).await;
+                }
+            }
+            packet @ (ServerPacket::OfferAttachment { .. }
+            | ServerPacket::AttachmentChunk { .. }
+            | ServerPacket::DownloadAttachment { .. }) => {
+                handle_attachment_packet(&state, id, &user, &writer, &mut flood, packet).await;
+            }
+            ServerPacket::History { room, query, limit } => {
+                send_history(&state, id, &writer, room, query, limit).await;
+            }
+            packet @ (ServerPacket::DirectMessage { .. }
+            | ServerPacket::EncryptedDirectMessage { .. }) => {
+                direct_message(&state, &user, &writer, packet).await;
+            }
+            ServerPacket::JoinRoom { room } => {
+                if !is_valid_room_name(&room) {
+                    let _ = system_message(&writer, format!("Invalid room name '{room}'")).await;
+                } else if !is_member(&state, id, &room).await {
+                    join_room(&state, id, &user, &room).await;
+                }
+            }
+            ServerPacket::LeaveRoom { room } => {
+                if is_member(&state, id, &room).await {
+                    leave_room(&state, id, &user, &room).await;
+                }
+            }
+            ServerPacket::ListRooms => {
+                let packet = ClientPacket::RoomList {
+                    rooms: list_rooms(&state).await,
+                };
+                let _ = writer.send(packet).await;
+            }
+            ServerPacket::SetPresence { presence } => {
+                set_presence(&state, &mut user, &writer, presence).await;
+            }
+            ServerPacket::ListPresence => {
+                let packet = ClientPacket::PresenceList {
+                    users: list_presence(&state).await,
+                };
+                let _ = writer.send(packet).await;
+            }
+            ServerPacket::TypingStart { room } => typing(&state, id, &user, room, true).await,
+            ServerPacket::TypingStop { room } => typing(&state, id, &user, room, false).await,
+            ServerPacket::ApiRequest {
+                request_type,
+                request,
+            } => {
+                let packet = api_response(&state, &user, request_type, request).await;
+                let _ = writer.send(packet).await;
+            }
+            ServerPacket::Login { .. }
+            | ServerPacket::TokenLogin { .. }
+            | ServerPacket::Register { .. } => {
+                let _ = system_message(&writer, "You are already logged in").await;
+            }
+            packet @ ServerPacket::Extension { .. } => {
+                relay_extension(&state, id, &user, &writer, packet).await;
+            }
+            ServerPacket::KeepAlive | ServerPacket::SelectCapabilities { .. } => {}
+        }
+    }
+
+    log_out(&state, id, &user).await;
+}
+
+/// Register the session of an authenticated client, announce its presence and join the default room.
+/// Additional connections of a user take over the presence of the existing ones
+async fn log_in(state: &ServerState, id: u64, user: &mut User, writer: &PacketQueue) {
+    // Checked and inserted under one lock, so concurrent logins can't both be the first session
+    let first_session = {
+        let mut sessions = state.sessions.lock().await;
+        let online = sessions
+            .values()
+            .find(|session| session.user.username.eq_ignore_ascii_case(&user.username))
+            .map(|session| session.user.presence.clone());
+        let first_session = online.is_none();
+        user.presence = online.unwrap_or_default();
+
+        sessions.insert(
+            id,
+            Session {
+                user: user.clone(),
+                writer: writer.clone(),
+                rooms: HashSet::new(),
+            },
+        );
+        drop(sessions);
+
+        first_session
+    };
+
+    if first_session {
+        broadcast_presence(state, &user.username, &user.presence).await;
+    }
+
+    join_room(state, id, user, DEFAULT_ROOM).await;
+}
+
+/// Remove the session of a client, leave all of its rooms
+/// and announce the user as offline once their last connection is gone
+async fn log_out(state: &ServerState, id: u64, user: &User) {
+    let Some(session) = state.sessions.lock().await.remove(&id) else {
+        return;
+    };
+
+    for room in session.rooms {
+        broadcast_room(state, &room, &user_left(user, &room)).await;
+    }
+
+    if online_presence(state, &user.username).await.is_none() {
+        let presence = Presence {
+            status: PresenceStatus::Offline,
+            status_text: None,
+        };
+        broadcast_presence(state, &user.username, &presence).await;
+    }
+}
+
+/// Wait for a successful `Login` or `Register` packet and return the authenticated user.
+/// Failed attempts are answered with `AuthFailed`, the client may try again.
+/// Returns `None` once the connection was closed
+async fn authenticate(
+    state: &ServerState,
+    r_client: &mut IncomingPacketStream<BoxedReader>,
+    flood: &mut FloodGuard,
+    writer: &PacketQueue,
+) -> Option<User> {
+    loop {
+        let result = match next_packet(state, r_client, flood, writer).await? {
+            ServerPacket::Login { username, password } => match &state.auth {
+                Some(auth) if auth(&username, &password) => {
+                    Ok(state.accounts.get(&username).unwrap_or_else(|| User {
+                        username: username.clone(),
+                        nickname: username,
+                        ..Default::default()
+                    }))
+                }
+                Some(_) => Err(AccountError::InvalidCredentials),
+                None => state.accounts.login(&username, &password).await,
+            },
+            #[cfg(feature = "strawberryid")]
+            ServerPacket::TokenLogin { username, token } => {
+                token_login(state, username, token).await
+            }
+            #[cfg(not(feature = "strawberryid"))]
+            ServerPacket::TokenLogin { .. } => Err(AccountError::TokenLoginUnsupported),
+            ServerPacket::Register {
+                username,
+                password,
+                role_color,
+            } => {
+                // Users logged in without a local account, e.g. with Strawberry ID, keep their name
+                if online_presence(state, &username).await.is_some() {
+                    Err(AccountError::UsernameTaken)
+                } else {
+                    state
+                        .accounts
+                        .register(&username, &password, &role_color)
+                        .await
+                }
+            }
+            ServerPacket::KeepAlive => continue,
+            _ => {
+                system_message(writer, "Please log in first").await.ok()?;
+                continue;
+            }
+        };
+
+        match result {
+            Ok(user) => {
+                welcome(writer, &user).await.ok()?;
+                return Some(user);
+            }
+            Err(err) => {
+                let packet = ClientPacket::AuthFailed {
+                    reason: err.failure(),
+                    message: err.to_string(),
+                };
+                writer.send(packet).await.ok()?;
+            }
+        }
+    }
+}
+
+/// Verify a Strawberry ID token login.
+/// The accounts are not linked: Strawberry ID names have to follow the username rules
+/// and names of registered local accounts are rejected, so both can't act as the same user
+#[cfg(feature = "strawberryid")]
+async fn token_login(
+    state: &ServerState,
+    username: String,
+    token: String,
+) -> Result<User, AccountError> {
+    let verifier = state.token_verifier.clone();
+    let credentials = StrawberryIdCredentials {
+        username: username.clone(),
+        token,
+    };
+
+    let id = tokio::task::spawn_blocking(move || verifier.verify(&credentials))
+        .await
+        .ok()
+        .and_then(Result::ok)
+        .filter(|id| id.username.eq_ignore_ascii_case(&username))
+        .ok_or(AccountError::InvalidToken)?;
+
+    state.accounts.validate_username(&id.username)?;
+
+    if state.accounts.get(&id.username).is_some() {
+        return Err(AccountError::UsernameTaken);
+    }
+
+    let nickname = if id.full_name.is_empty() {
+        id.username.clone()
+    } else {
+        id.full_name
+    };
+
+    Ok(User {
+        username: id.username,
+        nickname,
+        avatar_url: id.profile_picture,
+        ..Default::default()
+    })
+}
+
+/// Read the next packet of a client, handling flood protection and capability negotiation on the way.
+/// Returns `None` once the connection was closed or the client was disconnected for flooding
+async fn next_packet(
+    state: &ServerState,
+    r_client: &mut IncomingPacketStream<BoxedReader>,
+    flood: &mut FloodGuard,
+    writer: &PacketQueue,
+) -> Option<ServerPacket> {
+    loop {
+        // Stop reading once the queue closed the connection, e.g. because the client is too slow
+        let result = tokio::select! {
+            result = r_client.read::<ServerPacket>() => result,
+            () = writer.closed() => return None,
+        };
+
+        let packet = match result {
+            Ok(packet) => Some(packet),
+            Err(err) if err.is_decode() => None,
+            Err(_) => return None,
+        };
+
+        match flood.check(r_client.bytes_read(), packet.as_ref()) {
+            Verdict::Accept => {}
+            Verdict::Reject(notice) => {
+                if let Some(notice) = notice {
+                    system_message(writer, notice).await.ok()?;
+                }
+                continue;
+            }
+            Verdict::Disconnect => {
+                let _ = system_message(writer, "You were disconnected for flooding").await;
+                let _ = writer.shutdown().await;
+                return None;
+            }
+        }
+
+        let Some(packet) = packet else {
+            continue;
+        };
+
+        if let ServerPacket::SelectCapabilities {
+            compression,
+            format,
+        } = packet
+        {
+            select_capabilities(
+                state,
+                r_client,
+                writer,
+                compression.as_deref(),
+                format.as_deref(),
+            )
+            .await
+            .ok()?;
+            continue;
+        }
+
+        return Some(packet);
+    }
+}
+
+/// Optional protocol features offered to a new connection, `None` if there are none
+#[cfg_attr(not(feature = "stbchat-compression"), allow(unused_variables))]
+fn capabilities(state: &ServerState) -> Option<ClientPacket> {
+    #[cfg(feature = "stbchat-compression")]
+    let compression: Vec<String> = state
+        .compression
+        .iter()
+        .map(|compression| compression.algorithm.name().to_string())
+        .collect();
+    #[cfg(not(feature = "stbchat-compression"))]
+    let compression = Vec::new();
+
+    if compression.is_empty() && WireFormat::ALL.len() == 1 {
+        return None;
+    }
+
+    Some(ClientPacket::Capabilities {
+        compression,
+        formats: WireFormat::ALL
+            .iter()
+            .map(|format| format.name().to_string())
+            .collect(),
+    })
+}
+
+/// Apply the features selected by the client.
+/// The confirmation is sent in the selected format and is the last uncompressed frame sent to the client
+#[cfg_attr(
+    not(feature = "stbchat-compression"),
+    allow(unused_variables, clippy::needless_pass_by_ref_mut)
+)]
+async fn select_capabilities(
+    state: &ServerState,
+    r_client: &mut IncomingPacketStream<BoxedReader>,
+    writer: &PacketQueue,
+    compression: Option<&str>,
+    format: Option<&str>,
+) -> Result<(), CommunicationError> {
+    let format = format.and_then(WireFormat::from_name).unwrap_or_default();
+    writer.set_format(format);
+
+    #[cfg(feature = "stbchat-compression")]
+    {
+        let selected = compression
+            .and_then(CompressionAlgorithm::from_name)
+            .zip(state.compression)
+            .map(|(algorithm, offered)| Compression {
+                algorithm,
+                threshold: offered.threshold,
+            });
+
+        r_client.set_compression(selected);
+
+        let packet = ClientPacket::CapabilitiesSelected {
+            compression: selected.map(|c| c.algorithm.name().to_string()),
+            format: Some(format.name().to_string()),
+        };
+
+        writer.send_switching_compression(packet, selected).await
+    }
+
+    #[cfg(not(feature = "stbchat-compression"))]
+    {
+        let packet = ClientPacket::CapabilitiesSelected {
+            compression: None,
+            format: Some(format.name().to_string()),
+        };
+
+        writer.send(packet).await
+    }
+}
+
+async fn welcome(writer: &PacketQueue, user: &User) -> Result<(), CommunicationError> {
+    writer
+        .send(ClientPacket::Backend {
+            user_meta: UserMeta {
+                username: user.username.clone(),
+            },
+        })
+        .await?;
+
+    system_message(writer, format!("Welcome, {}!", user.nickname)).await
+}
+
+async fn system_message(
+    writer: &PacketQueue,
+    message: impl ToString,
+) -> Result<(), CommunicationError> {
+    writer
+        .send(ClientPacket::SystemMessage {
+            message: message.to_string(),
+        })
+        .await
+}
+
+async fn api_response(
+    state: &ServerState,
+    user: &User,
+    request_type: String,
+    request: Option<StbchatApiRequest>,
+) -> ClientPacket {
+    let response = match (request_type.as_str(), request) {
+        ("user_data", _) => StbchatApiResponse::UserData { data: user.clone() },
+        (_, Some(StbchatApiRequest::PublishKey { public_key })) => {
+            if public_key.len() != PUBLIC_KEY_LENGTH {
+                return ClientPacket::SystemMessage {
+                    message: format!("Invalid public key, expected {PUBLIC_KEY_LENGTH} bytes"),
+                };
+            }
+
+            state
+                .public_keys
+                .lock()
+                .await
+                .insert(user.username.to_lowercase(), public_key.clone());
+
+            StbchatApiResponse::PublicKey {
+                username: user.username.clone(),
+                public_key,
+            }
+        }
+        (_, Some(StbchatApiRequest::PublicKey { username })) => {
+            let public_key = state
+                .public_keys
+                .lock()
+                .await
+                .get(&username.to_lowercase())
+                .cloned();
+
+            match public_key {
+                Some(public_key) => StbchatApiResponse::PublicKey {
+                    username,
+                    public_key,
+                },
+                None => StbchatApiResponse::NoPublicKey { username },
+            }
+        }
+        _ => {
+            return ClientPacket::SystemMessage {
+                message: format!("Unknown api request '{request_type}'"),
+            };
+        }
+    };
+
+    ClientPacket::ApiResponse {
+        response_type: request_type,
+        response,
+    }
+}
+
+/// Assign an id to a message and broadcast it to all members of the room.
+/// Replies are only accepted for known messages of the same room
+async fn post_message(
+    state: &ServerState,
+    id: u64,
+    user: &User,
+    writer: &PacketQueue,
+    message: String,
+    room: String,
+    reply_to: Option<MessageId>,
+) {
+    if !is_member(state, id, &room).await {
+        let _ = system_message(writer, format!("You are not in room '{room}'")).await;
+        return;
+    }
+
+    if let Some(reply_to) = reply_to
+        && state
+            .history
+            .get(reply_to)
+            .is_none_or(|replied| replied.room != room)
+    {
+        let _ = system_message(writer, format!("Unknown message {reply_to}")).await;
+        return;
+    }
+
+    let author = user.clone();
+    let stored = write_history(state, move |history, next_id| {
+        let message = ChatMessage {
+            id: *next_id,
+            author,
+            message,
+            room,
+            reply_to,
+            edited: false,
+            reactions: BTreeMap::new(),
+        };
+
+        history.append(&message)?;
+        *next_id += 1;
+
+        Ok::<_, std::io::Error>(message)
+    })
+    .await;
+
+    let Ok(Ok(message)) = stored else {
+        let _ = system_message(writer, "Couldn't store your message").await;
+        return;
+    };
+
+    broadcast_room(
+        state,
+        &message.room.clone(),
+        &ClientPacket::UserMessage {
+            author: message.author,
+            message: message.message,
+            room: message.room,
+            id: message.id,
+            reply_to: message.reply_to,
+        },
+    )
+    .await;
+}
+
+/// Convert a request for changing a message into the update announced to the room
+fn requested_update(user: &User, packet: ServerPacket) -> Option<MessageUpdate> {
+    match packet {
+        ServerPacket::EditMessage { id, message } => Some(MessageUpdate::Edited { id, message }),
+        ServerPacket::DeleteMessage { id } => Some(MessageUpdate::Deleted { id }),
+        ServerPacket::AddReaction { id, emoji } => Some(MessageUpdate::ReactionAdded {
+            id,
+            emoji,
+            username: user.username.clone(),
+        }),
+        ServerPacket::RemoveReaction { id, emoji } => Some(MessageUpdate::ReactionRemoved {
+            id,
+            emoji,
+            username: user.username.clone(),
+        }),
+        _ => None,
+    }
+}
+
+/// Check whether a client may apply an update to a message and broadcast it to the room of the message.
+/// Only the author may edit or delete a message, every member of the room may react to it
+async fn update_message(
+    state: &ServerState,
+    id: u64,
+    user: &User,
+    writer: &PacketQueue,
+    update: MessageUpdate,
+) {
+    if let MessageUpdate::ReactionAdded { emoji, .. } | MessageUpdate::ReactionRemoved { emoji, .. } =
+        &update
+        && !is_valid_emoji(emoji)
+    {
+        let _ = system_message(writer, format!("Invalid reaction '{emoji}'")).await;
+        return;
+    }
+
+    let message_id = update.id();
+    let Some(message) = state.history.get(message_id) else {
+        let _ = system_message(writer, format!("Unknown message {message_id}")).await;
+        return;
+    };
+
+    if !is_member(state, id, &message.room).await {
+        let _ = system_message(writer, format!("You are not in room '{}'", message.room)).await;
+        return;
+    }
+
+    // Checked again while holding the history lock, the message may have changed in the meantime
+    let username = user.username.clone();
+    let checked_update = update.clone();
+    let result = write_history(state, move |history, _| {
+        let update = checked_update;
+        let Some(message) = history.get(message_id) else {
+            return Err(format!("Unknown message {message_id}"));
+        };
+
+        let changed = match &update {
+            MessageUpdate::Edited { .. } | MessageUpdate::Deleted { .. } => {
+                if !message.author.username.eq_ignore_ascii_case(&username) {
+                    return Err("You can only change your own messages".to_string());
+                }
+                true
+            }
+            MessageUpdate::ReactionAdded {
+                emoji, username, ..
+            } => !message.has_reaction(emoji, username),
+            MessageUpdate::ReactionRemoved {
+                emoji, username, ..
+            } => message.has_reaction(emoji, username),
+        };
+
+        if changed {
+            history
+                .update(&update)
+                .map_err(|_| "Couldn't store your change".to_string())?;
+        }
+
+        Ok(changed)
+    })
+    .await
+    .unwrap_or_else(|_| Err("Couldn't store your change".to_string()));
+
+    match result {
+        Ok(true) => {}
+        Ok(false) => return,
+        Err(reason) => {
+            let _ = system_message(writer, reason).await;
+            return;
+        }
+    }
+
+    broadcast_room(
+        state,
+        &message.room.clone(),
+        &ClientPacket::MessageUpdate {
+            room: message.room,
+            update,
+        },
+    )
+    .await;
+}
+
+/// Send a page of the message history of a room to a member of the room
+async fn send_history(
+    state: &ServerState,
+    id: u64,
+    writer: &PacketQueue,
+    room: String,
+    query: HistoryQuery,
+    limit: usize,
+) {
+    if !is_member(state, id, &room).await {
+        let _ = system_message(writer, format!("You are not in room '{room}'")).await;
+        return;
+    }
+
+    let mut page = state
+        .history
+        .query(&room, query, limit.clamp(1, MAX_HISTORY_PAGE));
+    fit_history_page(writer.format(), &room, query, &mut page);
+
+    let packet = ClientPacket::History {
+        room,
+        messages: page.messages,
+        has_more: page.has_more,
+    };
+
+    if writer.send(packet).await.is_err() {
+        let _ = system_message(writer, "Couldn't send the message history").await;
+    }
+}
+
+/// Drop the messages farthest from the queried position until the page fits into a single frame.
+/// The client can request the dropped messages with the next page
+fn fit_history_page(format: WireFormat, room: &str, query: HistoryQuery, page: &mut HistoryPage) {
+    let empty = ClientPacket::History {
+        room: room.to_string(),
+        messages: Vec::new(),
+        has_more: true,
+    };
+    let Ok(empty) = format.serialize(&empty) else {
+        return;
+    };
+
+    // The list header and separators take up to 5 bytes and one byte per message
+    let mut budget = usize::from(u16::MAX).saturating_sub(empty.len() + 5 + page.messages.len());
+    let sizes: Vec<usize> = page
+        .messages
+        .iter()
+        .map(|message| {
+            format
+                .serialize(message)
+                .map_or(usize::MAX, |bytes| bytes.len())
+        })
+        .collect();
+
+    let fits = |size: &&usize| {
+        let fits = **size <= budget;
+        budget = budget.saturating_sub(**size);
+        fits
+    };
+
+    // Pages are sorted oldest first, only pages after a message start at the queried position
+    if let HistoryQuery::After { .. } = query {
+        let keep = sizes.iter().take_while(fits).count();
+        page.has_more |= keep < page.messages.len();
+        page.messages.truncate(keep);
+    } else {
+        let keep = sizes.iter().rev().take_while(fits).count();
+        page.has_more |= keep < page.messages.len();
+        page.messages.drain(..page.messages.len() - keep);
+    }
+}
+
+/// Write to the history store on the blocking thread pool, stores may write to disk.
+/// Writes hold the lock of the next message id, so messages are stored in the order of their ids
+/// and updates are checked and applied at once
+async fn write_history<T: Send + 'static>(
+    state: &ServerState,
+    write: impl FnOnce(&dyn HistoryStore, &mut MessageId) -> T + Send + 'static,
+) -> std::io::Result<T> {
+    let history = Arc::clone(&state.history);
+    let next_id = Arc::clone(&state.next_message_id);
+
+    tokio::task::spawn_blocking(move || {
+        let mut next_id = next_id.lock().unwrap_or_else(PoisonError::into_inner);
+        write(history.as_ref(), &mut next_id)
+    })
+    .await
+    .map_err(std::io::Error::other)
+}
+
+/// Handle the packets used for uploading and downloading attachments
+async fn handle_attachment_packet(
+    state: &Arc<ServerState>,
+    id: u64,
+    user: &User,
+    writer: &PacketQueue,
+    flood: &mut FloodGuard,
+    packet: ServerPacket,
+) {
+    match packet {
+        ServerPacket::OfferAttachment {
+            room,
+            name,
+            size,
+            mime_type,
+            hash,
+        } => {
+            let attachment = Attachment {
+                id: 0,
+                name,
+                size,
+                mime_type,
+                hash,
+            };
+            if let Some((attachment_id, offset, remaining)) =
+                offer_attachment(state, id, user, writer, room, attachment).await
+            {
+                flood.allow_transfer(attachment_id, offset, remaining);
+            }
+        }
+        ServerPacket::AttachmentChunk {
+            id: attachment_id,
+            offset,
+            data,
+        } => {
+            let result = state.attachments.lock().await.write_chunk(
+                &user.username,
+                attachment_id,
+                offset,
+                &data,
+            );
+
+            if !matches!(
+                result,
+                Some(ChunkResult::Pending | ChunkResult::Complete { .. })
+            ) {
+                flood.end_transfer(attachment_id);
+            }
+
+            if let Some(result) = result {
+                upload_progress(state, user, writer, result).await;
+            }
+        }
+        ServerPacket::DownloadAttachment {
+            id: attachment_id,
+            offset,
+        } => {
+            // Downloads wait for room in the send queue, so they must not stall the connection
+            tokio::spawn(send_attachment(
+                state.clone(),
+                id,
+                writer.clone(),
+                attachment_id,
+                offset,
+            ));
+        }
+        _ => {}
+    }
+}
+
+/// Validate an offered attachment and start or resume its upload.
+/// Returns the id of the upload, the offset of the next chunk and the amount of bytes left
+/// if the upload was accepted
+async fn offer_attachment(
+    state: &ServerState,
+    id: u64,
+    user: &User,
+    writer: &PacketQueue,
+    room: String,
+    attachment: Attachment,
+) -> Option<(AttachmentId, u64, u64)> {
+    let rejection = if !is_member(state, id, &room).await {
+        Some(format!("You are not in room '{room}'"))
+    } else if attachment.size > state.max_attachment_size {
+        Some(
+            AttachmentError::TooLarge {
+                size: attachment.size,
+                max: state.max_attachment_size,
+            }
+            .to_string(),
+        )
+    } else if is_empty_or_whitespace(&attachment.name)
+        || attachment.name.len() > MAX_ATTACHMENT_NAME_LENGTH
+    {
+        Some("Invalid file name".to_string())
+    } else if !attachment::is_valid_hash(&attachment.hash) {
+        Some("Invalid hash".to_string())
+    } else {
+        None
+    };
+
+    if let Some(reason) = rejection {
+        let packet = ClientPacket::AttachmentUpload {
+            hash: attachment.hash,
+            status: UploadStatus::Failed { reason },
+        };
+        let _ = writer.send(packet).await;
+        return None;
+    }
+
+    let hash = attachment.hash.clone();
+    let size = attachment.size;
+    let mut attachments = state.attachments.lock().await;
+    let (attachment_id, offset) = attachments.offer(&user.username, &room, attachment);
+    // Empty files and uploads that were interrupted after the last chunk are complete right away
+    let result = attachments.finish(attachment_id);
+    drop(attachments);
+
+    let packet = ClientPacket::AttachmentUpload {
+        hash,
+        status: UploadStatus::Ready {
+            id: attachment_id,
+            offset,
+        },
+    };
+
+    writer.send(packet).await.ok()?;
+    upload_progress(state, user, writer, result).await;
+
+    Some((attachment_id, offset, size.saturating_sub(offset)))
+}
+
+/// Tell the uploader about a completed or failed upload and share completed attachments with the room
+async fn upload_progress(
+    state: &ServerState,
+    user: &User,
+    writer: &PacketQueue,
+    result: ChunkResult,
+) {
+    match result {
+        ChunkResult::Pending => {}
+        ChunkResult::Complete { room, attachment } => {
+            let packet = ClientPacket::AttachmentUpload {
+                hash: attachment.hash.clone(),
+                status: UploadStatus::Complete { id: attachment.id },
+            };
+            let _ = writer.send(packet).await;
+
+            let packet = ClientPacket::Attachment {
+                author: user.clone(),
+                room: room.clone(),
+                attachment,
+            };
+            broadcast_room(state, &room, &packet).await;
+        }
+        ChunkResult::Failed { hash, reason } => {
+            let packet = ClientPacket::AttachmentUpload {
+                hash,
+                status: UploadStatus::Failed { reason },
+            };
+            let _ = writer.send(packet).await;
+        }
+    }
+}
+
+/// Send the content of an attachment starting at `offset` to a member of its room
+async fn send_attachment(
+    state: Arc<ServerState>,
+    id: u64,
+    writer: PacketQueue,
+    attachment_id: AttachmentId,
+    offset: u64,
+) {
+    let stored = state
+        .attachments
+        .lock()
+        .await
+        .get(attachment_id)
+        .map(|stored| (stored.room.clone(), stored.data.clone()));
+
+    let Some((room, data)) = stored else {
+        let packet = ClientPacket::AttachmentUnavailable { id: attachment_id };
+        let _ = writer.send(packet).await;
+        return;
+    };
+
+    let start = usize::try_from(offset).unwrap_or(usize::MAX);
+
+    if !is_member(&state, id, &room).await || start > data.len() {
+        let packet = ClientPacket::AttachmentUnavailable { id: attachment_id };
+        let _ = writer.send(packet).await;
+        return;
+    }
+
+    let mut offset = offset;
+
+    for chunk in data[start..].chunks(CHUNK_SIZE) {
+        let packet = ClientPacket::AttachmentChunk {
+            id: attachment_id,
+            offset,
+            data: chunk.to_vec(),
+        };
+
+        if writer.send_bulk(packet).await.is_err() {
+            return;
+        }
+
+        offset += chunk.len() as u64;
+    }
+}
+
+fn is_valid_emoji(emoji: &str) -> bool {
+    !is_empty_or_whitespace(emoji) && emoji.len() <= MAX_EMOJI_LENGTH
+}
+
+/// Presence of a user that is logged in on at least one connection
+async fn online_presence(state: &ServerState, username: &str) -> Option<Presence> {
+    state
+        .sessions
+        .lock()
+        .await
+        .values()
+        .find(|session| session.user.username.eq_ignore_ascii_case(username))
+        .map(|session| session.user.presence.clone())
+}
+
+/// Change the presence of a user on all of their connections and announce it to everyone
+async fn set_presence(
+    state: &ServerState,
+    user: &mut User,
+    writer: &PacketQueue,
+    presence: Presence,
+) {
+    if presence
+        .status_text
+        .as_ref()
+        .is_some_and(|text| text.len() > MAX_STATUS_TEXT_LENGTH)
+    {
+        let _ = system_message(writer, "Your status text is too long").await;
+        return;
+    }
+
+    // Compared against the shared presence, another connection of the user may have changed it
+    let changed = {
+        let mut sessions = state.sessions.lock().await;
+        let mut changed = false;
+
+        for session in sessions.values_mut() {
+            if session.user.username.eq_ignore_ascii_case(&user.username)
+                && session.user.presence != presence
+            {
+                session.user.presence = presence.clone();
+                changed = true;
+            }
+        }
+        drop(sessions);
+
+        changed
+    };
+
+    user.presence = presence.clone();
+
+    if changed {
+        broadcast_presence(state, &user.username, &presence).await;
+    }
+}
+
+/// All logged in users including their presence, sorted by username
+async fn list_presence(state: &ServerState) -> Vec<User> {
+    let users: BTreeMap<String, User> = state
+        .sessions
+        .lock()
+        .await
+        .values()
+        .map(|session| (session.user.username.clone(), session.user.clone()))
+        .collect();
+
+    users.into_values().collect()
+}
+
+/// Tell the other members of a room that a user started or stopped typing
+async fn typing(state: &ServerState, id: u64, user: &User, room: String, started: bool) {
+    if !is_member(state, id, &room).await {
+        return;
+    }
+
+    let username = user.username.clone();
+    let packet = if started {
+        ClientPacket::TypingStart {
+            username,
+            room: room.clone(),
+        }
+    } else {
+        ClientPacket::TypingStop {
+            username,
+            room: room.clone(),
+        }
+    };
+
+    broadcast_room_except(state, &room, id, &packet).await;
+}
+
+async fn broadcast_presence(state: &ServerState, username: &str, presence: &Presence) {
+    let packet = ClientPacket::PresenceUpdate {
+        username: username.to_string(),
+        presence: presence.clone(),
+    };
+
+    let writers: Vec<PacketQueue> = state
+        .sessions
+        .lock()
+        .await
+        .values()
+        .map(|session| session.writer.clone())
+        .collect();
+
+    for writer in writers {
+        let _ = writer.send(&packet).await;
+    }
+}
+
+fn is_valid_room_name(room: &str) -> bool {
+    !is_empty_or_whitespace(room)
+        && !contains_whitespace(room)
+        && room.len() <= MAX_ROOM_NAME_LENGTH
+}
+
+async fn is_member(state: &ServerState, id: u64, room: &str) -> bool {
+    state
+        .sessions
+        .lock()
+        .await
+        .get(&id)
+        .is_some_and(|session| session.rooms.contains(room))
+}
+
+/// Add a client to a room and announce it to all members, including the client itself
+async fn join_room(state: &ServerState, id: u64, user: &User, room: &str) {
+    if let Some(session) = state.sessions.lock().await.get_mut(&id) {
+        session.rooms.insert(room.to_string());
+    }
+
+    let packet = ClientPacket::ApiResponse {
+        response_type: "user_joined".to_string(),
+        response: StbchatApiResponse::UserJoined {
+            username: user.username.clone(),
+            nickname: user.nickname.clone(),
+            role_color: user.role_color.clone(),
+            badge: user.badge.clone(),
+            room: room.to_string(),
+        },
+    };
+
+    broadcast_room(state, room, &packet).await;
+}
+
+/// Announce to all members of a room that a client left, including the client itself
+async fn leave_room(state: &ServerState, id: u64, user: &User, room: &str) {
+    broadcast_room(state, room, &user_left(user, room)).await;
+
+    if let Some(session) = state.sessions.lock().await.get_mut(&id) {
+        session.rooms.remove(room);
+    }
+}
+
+fn user_left(user: &User, room: &str) -> ClientPacket {
+    ClientPacket::ApiResponse {
+        response_type: "user_left".to_string(),
+        response: StbchatApiResponse::UserLeft {
+            username: user.username.clone(),
+            room: room.to_string(),
+        },
+    }
+}
+
+/// All rooms with at least one member, sorted by name
+async fn list_rooms(state: &ServerState) -> Vec<Room> {
+    let mut rooms: BTreeMap<String, Vec<String>> = BTreeMap::new();
+
+    for session in state.sessions.lock().await.values() {
+        for room in &session.rooms {
+            rooms
+                .entry(room.clone())
+                .or_default()
+                .push(session.user.username.clone());
+        }
+    }
+
+    rooms
+        .into_iter()
+        .map(|(name, members)| Room { name, members })
+        .collect()
+}
+
+/// Deliver a plain or encrypted direct message and tell the sender whether the recipient is online.
+/// Encrypted payloads are relayed as is
+async fn direct_message(
+    state: &ServerState,
+    user: &User,
+    writer: &PacketQueue,
+    packet: ServerPacket,
+) {
+    let (recipient, packet) = match packet {
+        ServerPacket::DirectMessage { recipient, message } => {
+            let packet = ClientPacket::DirectMessage {
+                author: user.clone(),
+                message,
+            };
+            (recipient, packet)
+        }
+        ServerPacket::EncryptedDirectMessage { recipient, payload } => {
+            let packet = ClientPacket::EncryptedDirectMessage {
+                author: user.clone(),
+                payload,
+            };
+            (recipient, packet)
+        }
+        _ => return,
+    };
+
+    let status = if send_to_user(state, &recipient, &packet).await {
+        DeliveryStatus::Delivered
+    } else {
+        DeliveryStatus::UserOffline
+    };
+
+    let packet = ClientPacket::DirectMessageStatus { recipient, status };
+    let _ = writer.send(packet).await;
+}
+
+/// Relay an extension packet to the members of a room or the connections of a user.
+/// The payload is passed on as is
+async fn relay_extension(
+    state: &ServerState,
+    id: u64,
+    user: &User,
+    writer: &PacketQueue,
+    packet: ServerPacket,
+) {
+    let ServerPacket::Extension {
+        extension_type,
+        target,
+        payload,
+    } = packet
+    else {
+        return;
+    };
+
+    if !extension::is_valid_type(&extension_type) {
+        let _ = system_message(writer, format!("Invalid extension type '{extension_type}'")).await;
+        return;
+    }
+
+    let packet = ClientPacket::Extension {
+        extension_type,
+        sender: user.username.clone(),
+        target: target.clone(),
+        payload,
+    };
+
+    match target {
+        ExtensionTarget::Room { room } => {
+            if is_member(state, id, &room).await {
+                broadcast_room(state, &room, &packet).await;
+            } else {
+                let _ = system_message(writer, format!("You are not in room '{room}'")).await;
+            }
+        }
+        ExtensionTarget::User { username } => {
+            if !send_to_user(state, &username, &packet).await {
+                let _ = system_message(writer, format!("User '{username}' is offline")).await;
+            }
+        }
+    }
+}
+
+/// Send a packet to every connection of a user.
+/// Returns `false` if the packet could not be delivered to any connection
+async fn send_to_user(state: &ServerState, username: &str, packet: &ClientPacket) -> bool {
+    let writers: Vec<PacketQueue> = state
+        .sessions
+        .lock()
+        .await
+        .values()
+        .filter(|session| session.user.username.eq_ignore_ascii_case(username))
+        .map(|session| session.writer.clone())
+        .collect();
+
+    let mut delivered = false;
+
+    for writer in writers {
+        delivered |= writer.send(packet).await.is_ok();
+    }
+
+    delivered
+}
+
+/// Send a packet to every member of a room except the given connection
+async fn broadcast_room_except(
+    state: &ServerState,
+    room: &str,
+    except: u64,
+    packet: &ClientPacket,
+) {
+    let writers: Vec<PacketQueue> = state
+        .sessions
+        .lock()
+        .await
+        .iter()
+        .filter(|(id, session)| **id != except && session.rooms.contains(room))
+        .map(|(_, session)| session.writer.clone())
+        .collect();
+
+    for writer in writers {
+        let _ = writer.send(packet).await;
+    }
+}
+
+/// Send a packet to every member of a room
+async fn broadcast_room(state: &ServerState, room: &str, packet: &ClientPacket) {
+    let writers: Vec<PacketQueue> = state
+        .sessions
+        .lock()
+        .await
+        .values()
+        .filter(|session| session.rooms.contains(room))
+        .map(|session| session.writer.clone())
+        .collect();
+
+    for writer in writers {
+        let _ = writer.send(packet).await;
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError, RwLock};
+
+use serde::{Deserialize, Serialize};
+
+use crate::stbchat::object::User;
+
+/// A registered account as persisted by a user store
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Account {
+    pub user: User,
+    /// Salted Argon2 hash of the password in PHC string format
+    pub password_hash: String,
+}
+
+/// # Pluggable user storage for the Strawberry Chat server
+/// Holds the profiles and password hashes of all registered users.
+/// Usernames are compared case-insensitively, so `Alice` and `alice` are the same account
+pub trait UserStore: Send + Sync {
+    /// Returns the account of a user, if existing
+    fn get(&self, username: &str) -> Option<Account>;
+
+    /// Store a new account. Returns `Ok(false)` if the username is already taken
+    /// # Errors
+    /// - Will return `Err` if the account could not be persisted
+    fn create(&self, account: Account) -> io::Result<bool>;
+
+    /// Replace an existing account, e.g. after changing its badge.
+    /// Returns `Ok(false)` if there is no such account
+    /// # Errors
+    /// - Will return `Err` if the account could not be persisted
+    fn update(&self, account: Account) -> io::Result<bool>;
+}
+
+fn key(username: &str) -> String {
+    username.to_lowercase()
+}
+
+/// In-memory user store, intended for integration tests and local development.
+/// All users are lost once the server stops
+#[derive(Default)]
+pub struct MemoryUserStore {
+    accounts: RwLock<HashMap<String, Account>>,
+}
+
+impl MemoryUserStore {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn remove(&self, username: &str) {
+        let mut accounts = self
+            .accounts
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        accounts.remove(&key(username));
+    }
+
+    fn all(&self) -> Vec<Account> {
+        let accounts = self.accounts.read().unwrap_or_else(PoisonError::into_inner);
+        accounts.values().cloned().collect()
+    }
+}
+
+impl UserStore for MemoryUserStore {
+    fn get(&self, username: &str) -> Option<Account> {
+        let accounts = self.accounts.read().unwrap_or_else(PoisonError::into_inner);
+        accounts.get(&key(username)).cloned()
+    }
+
+    fn create(&self, account: Account) -> io::Result<bool> {
+        let created = match self
+            .accounts
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(key(&account.user.username))
+        {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(account);
+                true
+            }
+        };
+
+        Ok(created)
+    }
+
+    fn update(&self, account: Account) -> io::Result<bool> {
+        let updated = self
+            .accounts
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get_mut(&key(&account.user.username))
+            .map(|stored| *stored = account)
+            .is_some();
+
+        Ok(updated)
+    }
+}
+
+/// # File user store
+/// Keeps all accounts in memory and writes them to a JSON file after every change.
+/// The file is replaced atomically, so a crash during a write never loses existing accounts
+/// ```no_run
+/// use libstrawberry::stbchat::server::StbchatServer;
+/// use libstrawberry::stbchat::server::store::FileUserStore;
+///
+/// # async fn example() -> eyre::Result<()> {
+/// StbchatServer::new()
+///     .user_store(FileUserStore::open("accounts.json")?)
+///     .listen("127.0.0.1:52800")
+///     .await
+/// # }
+/// ```
+pub struct FileUserStore {
+    memory: MemoryUserStore,
+    path: PathBuf,
+    /// Serializes writes, so an older snapshot never replaces a newer one
+    write_lock: Mutex<()>,
+}
+
+impl FileUserStore {
+    /// Open or create an account file
+    /// # Errors
+    /// - Will return `Err` if the file exists but could not be read or parsed
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let memory = MemoryUserStore::new();
+
+        match fs::read(&path) {
+            Ok(content) => {
+                let accounts: Vec<Account> = serde_json::from_slice(&content)?;
+                for account in accounts {
+                    memory.create(account)?;
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+
+        Ok(Self {
+            memory,
+            path,
+            write_lock: Mutex::new(()),
+        })
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let _guard = self
+            .write_lock
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        let mut accounts = self.memory.all();
+        accounts.sort_by(|a, b| a.user.username.cmp(&b.user.username));
+
+        let mut temp = self.path.clone().into_os_string();
+        temp.push(".tmp");
+
+        fs::write(&temp, serde_json::to_vec_pretty(&accounts)?)?;
+        fs::rename(&temp, &self.path)
+    }
+}
+
+impl UserStore for FileUserStore {
+    fn get(&self, username: &str) -> Option<Account> {
+        self.memory.get(username)
+    }
+
+    fn create(&self, account: Account) -> io::Result<bool> {
+        let username = account.user.username.clone();
+
+        if !self.memory.create(account)? {
+            return Ok(false);
+        }
+
+        if let Err(err) = self.save() {
+            self.memory.remove(&username);
+            return Err(err);
+        }
+
+        Ok(true)
+    }
+
+    fn update(&self, account: Account) -> io::Result<bool> {
+        let Some(previous) = self.memory.get(&account.user.username) else {
+            return Ok(false);
+        };
+
+        if !self.memory.update(account)? {
+            return Ok(false);
+        }
+
+        if let Err(err) = self.save() {
+            self.memory.update(previous)?;
+            return Err(err);
+        }
+
+        Ok(true)
+    }
+}
@@ -0,0 +1,77 @@
+#![cfg(feature = "strawberryid")]
+
+use crate::STRAWBERRY_ID_API;
+use crate::id::StrawberryId;
+use crate::id::credentials::StrawberryIdCredentials;
+use crate::id::verifier::StrawberryIdVerifier;
+
+/// # Pluggable verifier for Strawberry ID token logins
+/// Called on the blocking thread pool, so implementations may perform blocking requests.
+/// Implemented for closures, which is useful for integration tests
+/// ```no_run
+/// use libstrawberry::id::StrawberryId;
+/// use libstrawberry::stbchat::server::StbchatServer;
+///
+/// # async fn example() -> eyre::Result<()> {
+/// StbchatServer::new()
+///     .token_verifier(|credentials: &_| {
+///         Ok(StrawberryId {
+///             username: "alice".to_string(),
+///             ..Default::default()
+///         })
+///     })
+///     .listen("127.0.0.1:52800")
+///     .await
+/// # }
+/// ```
+pub trait TokenVerifier: Send + Sync {
+    /// Check the token of a user and return their Strawberry ID
+    /// # Errors
+    /// - Will return `Err` if the credentials are invalid or could not be checked
+    fn verify(&self, credentials: &StrawberryIdCredentials) -> eyre::Result<StrawberryId>;
+}
+
+impl<F> TokenVerifier for F
+where
+    F: Fn(&StrawberryIdCredentials) -> eyre::Result<StrawberryId> + Send + Sync,
+{
+    fn verify(&self, credentials: &StrawberryIdCredentials) -> eyre::Result<StrawberryId> {
+        self(credentials)
+    }
+}
+
+/// Verifies tokens using `StrawberryIdVerifier`, against the official Strawberry ID API by default
+#[derive(Debug, Clone)]
+pub struct StrawberryIdTokenVerifier {
+    endpoint: String,
+}
+
+impl Default for StrawberryIdTokenVerifier {
+    fn default() -> Self {
+        Self {
+            endpoint: STRAWBERRY_ID_API.to_string(),
+        }
+    }
+}
+
+impl StrawberryIdTokenVerifier {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use a custom Strawberry ID API endpoint, e.g. `https://id.example.com/v2/`
+    #[must_use]
+    pub fn endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.endpoint = endpoint.into();
+
+        self
+    }
+}
+
+impl TokenVerifier for StrawberryIdTokenVerifier {
+    fn verify(&self, credentials: &StrawberryIdCredentials) -> eyre::Result<StrawberryId> {
+        StrawberryIdVerifier::verify_with_endpoint(credentials, &self.endpoint)
+            .map(|verifier| verifier.strawberry_id)
+    }
+}
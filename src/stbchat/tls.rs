@@ -0,0 +1,306 @@
+#![cfg(all(feature = "stbchat-tls", not(feature = "stbchat-sync")))]
+
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::Arc;
+
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::rustls::client::WebPkiServerVerifier;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::crypto::{CryptoProvider, ring};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{
+    ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme,
+};
+use tokio_rustls::{client, server};
+
+pub use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::stbchat::error::TlsError;
+
+/// SHA-256 fingerprint of a DER encoded certificate
+pub type Fingerprint = [u8; 32];
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// # Client-side TLS configuration
+/// Trusts the Mozilla root certificates by default. Additional (e.g. self-signed) certificates
+/// can be trusted with `add_ca_pem`, and certificates can be pinned by their SHA-256 fingerprint.
+/// ```no_run
+/// use libstrawberry::stbchat::tls::TlsClientConfig;
+///
+/// # fn example() -> eyre::Result<()> {
+/// let connector = TlsClientConfig::new()
+///     .add_ca_file("certs/ca.pem")?
+///     .pin_fingerprint("3F:1A:...")?
+///     .build()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct TlsClientConfig {
+    roots: RootCertStore,
+    pins: Vec<Fingerprint>,
+}
+
+impl Default for TlsClientConfig {
+    fn default() -> Self {
+        Self {
+            roots: RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            },
+            pins: Vec::new(),
+        }
+    }
+}
+
+impl TlsClientConfig {
+    /// Create a new configuration trusting the Mozilla root certificates
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a new configuration without any trusted certificates
+    #[must_use]
+    pub fn empty() -> Self {
+        Self {
+            roots: RootCertStore::empty(),
+            pins: Vec::new(),
+        }
+    }
+
+    /// Trust all certificates of a PEM encoded bundle
+    /// # Errors
+    /// - Will return `Err` if the bundle contains no valid certificate
+    pub fn add_ca_pem(mut self, pem: &[u8]) -> eyre::Result<Self> {
+        let certs = parse_certificates(pem)?;
+        let (added, _) = self.roots.add_parsable_certificates(certs);
+
+        if added == 0 {
+            return Err(TlsError::MissingCertificate.into());
+        }
+
+        Ok(self)
+    }
+
+    /// Trust all certificates of a PEM encoded file
+    /// # Errors
+    /// - Will return `Err` if the file could not be read
+    /// - Will return `Err` if the file contains no valid certificate
+    pub fn add_ca_file(self, path: impl AsRef<Path>) -> eyre::Result<Self> {
+        let pem = std::fs::read(path)?;
+        self.add_ca_pem(&pem)
+    }
+
+    /// Only accept server certificates with the given SHA-256 fingerprint.
+    /// Pins are checked in addition to the regular certificate validation
+    #[must_use]
+    pub fn pin(mut self, fingerprint: Fingerprint) -> Self {
+        self.pins.push(fingerprint);
+
+        self
+    }
+
+    /// Pin a hex encoded SHA-256 fingerprint, e.g. `3F:1A:...` or `3f1a...`
+    /// # Errors
+    /// - Will return `Err` if the fingerprint is not valid hex or not 32 bytes long
+    pub fn pin_fingerprint(self, fingerprint: &str) -> eyre::Result<Self> {
+        let parsed = parse_fingerprint(fingerprint)
+            .ok_or_else(|| TlsError::InvalidFingerprint(fingerprint.to_string()))?;
+
+        Ok(self.pin(parsed))
+    }
+
+    /// Build a connector from this configuration
+    /// # Errors
+    /// - Will return `Err` if the configuration is invalid
+    pub fn build(self) -> eyre::Result<TlsConnector> {
+        let provider = provider();
+        let verifier =
+            WebPkiServerVerifier::builder_with_provider(Arc::new(self.roots), provider.clone())
+                .build()?;
+
+        let builder =
+            ClientConfig::builder_with_provider(provider).with_safe_default_protocol_versions()?;
+
+        let config = if self.pins.is_empty() {
+            builder.with_webpki_verifier(verifier).with_no_client_auth()
+        } else {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedCertVerifier {
+                    inner: verifier,
+                    pins: self.pins,
+                }))
+                .with_no_client_auth()
+        };
+
+        Ok(TlsConnector::from(Arc::new(config)))
+    }
+}
+
+/// Create a server-side acceptor from a PEM encoded certificate chain and private key
+/// # Errors
+/// - Will return `Err` if no valid certificate or private key was found
+pub fn acceptor_from_pem(cert_chain: &[u8], private_key: &[u8]) -> eyre::Result<TlsAcceptor> {
+    let certs = parse_certificates(cert_chain)?;
+    let key =
+        PrivateKeyDer::from_pem_slice(private_key).map_err(|_| TlsError::MissingPrivateKey)?;
+
+    let config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Create a server-side acceptor from PEM encoded certificate chain and private key files
+/// # Errors
+/// - Will return `Err` if one of the files could not be read
+/// - Will return `Err` if no valid certificate or private key was found
+pub fn acceptor_from_files(
+    cert_chain: impl AsRef<Path>,
+    private_key: impl AsRef<Path>,
+) -> eyre::Result<TlsAcceptor> {
+    acceptor_from_pem(&std::fs::read(cert_chain)?, &std::fs::read(private_key)?)
+}
+
+/// Perform the client-side TLS handshake on an established connection
+/// # Errors
+/// - Will return `Err` if the server name is invalid
+/// - Will return `Err` if the handshake fails
+pub async fn connect<S: AsyncRead + AsyncWrite + Unpin>(
+    connector: &TlsConnector,
+    server_name: &str,
+    stream: S,
+) -> eyre::Result<client::TlsStream<S>> {
+    let server_name = ServerName::try_from(server_name.to_string())
+        .map_err(|_| TlsError::InvalidServerName(server_name.to_string()))?;
+
+    Ok(connector.connect(server_name, stream).await?)
+}
+
+/// Perform the server-side TLS handshake on an accepted connection
+/// # Errors
+/// - Will return `Err` if the handshake fails
+pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+    acceptor: &TlsAcceptor,
+    stream: S,
+) -> eyre::Result<server::TlsStream<S>> {
+    Ok(acceptor.accept(stream).await?)
+}
+
+/// Compute the SHA-256 fingerprint of a DER encoded certificate
+#[must_use]
+pub fn fingerprint(certificate: &[u8]) -> Fingerprint {
+    Sha256::digest(certificate).into()
+}
+
+/// Format a fingerprint as colon separated hex, e.g. `3F:1A:...`
+#[must_use]
+pub fn format_fingerprint(fingerprint: &Fingerprint) -> String {
+    let mut formatted = String::with_capacity(fingerprint.len() * 3);
+
+    for (i, byte) in fingerprint.iter().enumerate() {
+        if i > 0 {
+            formatted.push(':');
+        }
+        let _ = write!(formatted, "{byte:02X}");
+    }
+
+    formatted
+}
+
+fn parse_fingerprint(fingerprint: &str) -> Option<Fingerprint> {
+    let digits: Vec<u8> = fingerprint
+        .bytes()
+        .filter(|c| *c != b':')
+        .map(|c| (c as char).to_digit(16).and_then(|d| u8::try_from(d).ok()))
+        .collect::<Option<_>>()?;
+
+    if digits.len() != 64 {
+        return None;
+    }
+
+    let mut parsed = [0u8; 32];
+    for (byte, pair) in parsed.iter_mut().zip(digits.chunks(2)) {
+        *byte = pair[0] << 4 | pair[1];
+    }
+
+    Some(parsed)
+}
+
+fn parse_certificates(pem: &[u8]) -> eyre::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_slice_iter(pem)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| TlsError::MissingCertificate)?;
+
+    if certs.is_empty() {
+        return Err(TlsError::MissingCertificate.into());
+    }
+
+    Ok(certs)
+}
+
+/// Validates the certificate chain and additionally checks the end-entity certificate against a set of pins
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    pins: Vec<Fingerprint>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+
+        if self.pins.contains(&fingerprint(end_entity)) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(tokio_rustls::rustls::Error::General(
+                "certificate does not match any pinned fingerprint".to_string(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
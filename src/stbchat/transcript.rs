@@ -0,0 +1,639 @@
+use std::io::{self, Read, Write};
+use std::sync::LazyLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use regex::Regex;
+use serde_json::json;
+
+use crate::colors::RESET;
+use crate::stbchat::capture::{CaptureReader, CapturedPacket};
+use crate::stbchat::object::{DeliveryStatus, MessageId, MessageUpdate, StbchatApiResponse, User};
+use crate::stbchat::packet::ClientPacket;
+use crate::stbchat::role_color::RoleColor;
+use crate::string::{ANSI_ESCAPE, escape_ansi, strip_control_characters};
+use crate::time::format_time;
+
+/// Time format of HTML and Markdown transcripts
+pub const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// Time format of JSON Lines transcripts (RFC 3339)
+const JSON_TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%:z";
+
+/// An SGR sequence, capturing its parameters
+static SGR: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^\x1B\[([0-9;]*)m$").unwrap());
+
+const HTML_STYLE: &str = "body{background:#1e1e1e;color:#e5e5e5;font-family:monospace;margin:2em}\
+.entry{margin:.2em 0;white-space:pre-wrap}\
+.time,.room,.id{color:#7f7f7f}\
+.author{font-weight:bold}\
+.system .author{color:#cdcd00}\
+.notification .author{color:#cd00cd}\
+.event .text{color:#7f7f7f;font-style:italic}";
+
+/// Output format of a transcript
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TranscriptFormat {
+    /// Standalone HTML page with inline styles, role colors and colored text are kept
+    Html,
+    /// Markdown, escape sequences are removed
+    Markdown,
+    /// One JSON object per line, escape sequences are removed
+    JsonLines,
+}
+
+impl TranscriptFormat {
+    /// Format by its name or file extension, e.g. `html`, `md` or `jsonl`
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "html" | "htm" => Some(Self::Html),
+            "markdown" | "md" => Some(Self::Markdown),
+            "jsonl" | "json_lines" | "jsonlines" | "ndjson" => Some(Self::JsonLines),
+            _ => None,
+        }
+    }
+
+    /// File extension without the leading dot
+    #[must_use]
+    pub const fn extension(self) -> &'static str {
+        match self {
+            Self::Html => "html",
+            Self::Markdown => "md",
+            Self::JsonLines => "jsonl",
+        }
+    }
+}
+
+/// # Transcript writer
+/// Exports a chat session for archiving or moderation review. Packets can be written while
+/// a client is running, or be read from a capture file.
+/// Only packets that are visible in a chat are exported, e.g. messages, updates, attachments and notifications.
+///
+/// Messages of a `History` packet don't carry a timestamp of their own and are written with the time of the packet
+/// ```no_run
+/// use libstrawberry::stbchat::capture::CaptureReader;
+/// use libstrawberry::stbchat::transcript::{TranscriptFormat, TranscriptWriter};
+///
+/// # fn example() -> std::io::Result<()> {
+/// let output = std::fs::File::create("session.html")?;
+/// let mut transcript = TranscriptWriter::new(output, TranscriptFormat::Html).title("#main");
+///
+/// transcript.write_capture(CaptureReader::open("session.stbcap")?)?;
+/// transcript.finish()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct TranscriptWriter<W: Write> {
+    writer: W,
+    format: TranscriptFormat,
+    title: String,
+    started: bool,
+}
+
+impl<W: Write> TranscriptWriter<W> {
+    #[must_use]
+    pub fn new(writer: W, format: TranscriptFormat) -> Self {
+        Self {
+            writer,
+            format,
+            title: "Strawberry Chat transcript".to_string(),
+            started: false,
+        }
+    }
+
+    /// Title of HTML and Markdown transcripts
+    #[must_use]
+    pub fn title(mut self, title: impl Into<String>) -> Self {
+        self.title = title.into();
+        self
+    }
+
+    #[must_use]
+    pub const fn format(&self) -> TranscriptFormat {
+        self.format
+    }
+
+    /// Write a packet that was just received
+    /// # Errors
+    /// - Will return `Err` if writing fails
+    pub fn write_packet(&mut self, packet: &ClientPacket) -> io::Result<()> {
+        self.write_packet_at(SystemTime::now(), packet)
+    }
+
+    /// Write a packet that was received at a given time
+    /// # Errors
+    /// - Will return `Err` if writing fails
+    pub fn write_packet_at(&mut self, time: SystemTime, packet: &ClientPacket) -> io::Result<()> {
+        for entry in entries(time, packet) {
+            self.write_entry(&entry)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write all packets a client received in a capture, or the server sent.
+    /// Frames that can't be decoded are skipped
+    /// # Errors
+    /// - Will return `Err` if a record could not be read
+    /// - Will return `Err` if writing fails
+    pub fn write_capture<R: Read>(&mut self, capture: CaptureReader<R>) -> io::Result<()> {
+        let side = capture.side();
+
+        for record in capture {
+            let record = record?;
+
+            if let CapturedPacket::Client(packet) = record.decode(side) {
+                self.write_packet_at(record.time().into(), &packet)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Complete the transcript and return the writer
+    /// # Errors
+    /// - Will return `Err` if writing fails
+    pub fn finish(mut self) -> io::Result<W> {
+        self.start()?;
+
+        if self.format == TranscriptFormat::Html {
+            writeln!(self.writer, "</main>\n</body>\n</html>")?;
+        }
+
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    /// Write the header, once before the first entry
+    fn start(&mut self) -> io::Result<()> {
+        if self.started {
+            return Ok(());
+        }
+        self.started = true;
+
+        match self.format {
+            TranscriptFormat::Html => {
+                let title = escape_html(&strip_control_characters(&self.title));
+                writeln!(
+                    self.writer,
+                    "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+                     <title>{title}</title>\n<style>{HTML_STYLE}</style>\n</head>\n<body>\n\
+                     <h1>{title}</h1>\n<main>"
+                )
+            }
+            TranscriptFormat::Markdown => writeln!(
+                self.writer,
+                "# {}\n",
+                escape_markdown(&strip_control_characters(&self.title))
+            ),
+            TranscriptFormat::JsonLines => Ok(()),
+        }
+    }
+
+    fn write_entry(&mut self, entry: &Entry) -> io::Result<()> {
+        self.start()?;
+
+        match self.format {
+            TranscriptFormat::Html => self.write_html(entry),
+            TranscriptFormat::Markdown => self.write_markdown(entry),
+            TranscriptFormat::JsonLines => self.write_json(entry),
+        }
+    }
+
+    fn write_html(&mut self, entry: &Entry) -> io::Result<()> {
+        write!(
+            self.writer,
+            "<div class=\"entry {}\"><span class=\"time\">[{}]</span> ",
+            entry.kind,
+            format_time(entry.time, TIME_FORMAT)
+        )?;
+
+        if let Some(room) = &entry.room {
+            write!(
+                self.writer,
+                "<span class=\"room\">#{}</span> ",
+                escape_html(&strip_control_characters(room))
+            )?;
+        }
+
+        if let Some(id) = entry.id {
+            write!(self.writer, "<span class=\"id\">{id}</span> ")?;
+        }
+
+        write!(
+            self.writer,
+            "<span class=\"author\">{}</span>",
+            html_author(&entry.author)
+        )?;
+
+        if let Some(recipient) = &entry.recipient {
+            write!(
+                self.writer,
+                " -&gt; <span class=\"author\">{}</span>",
+                escape_html(&strip_control_characters(recipient))
+            )?;
+        }
+
+        if let Some(reply_to) = entry.reply_to {
+            write!(
+                self.writer,
+                " <span class=\"id\">(reply to {reply_to})</span>"
+            )?;
+        }
+
+        writeln!(
+            self.writer,
+            ": <span class=\"text\">{}</span></div>",
+            ansi_to_html(&entry.text)
+        )
+    }
+
+    fn write_markdown(&mut self, entry: &Entry) -> io::Result<()> {
+        write!(self.writer, "- `{}`", format_time(entry.time, TIME_FORMAT))?;
+
+        if let Some(room) = &entry.room {
+            write!(
+                self.writer,
+                " #{}",
+                escape_markdown(&strip_control_characters(room))
+            )?;
+        }
+
+        if let Some(id) = entry.id {
+            write!(self.writer, " `{id}`")?;
+        }
+
+        write!(
+            self.writer,
+            " **{}**",
+            escape_markdown(&plain_author(&entry.author))
+        )?;
+
+        if let Some(recipient) = &entry.recipient {
+            write!(
+                self.writer,
+                " → **{}**",
+                escape_markdown(&strip_control_characters(recipient))
+            )?;
+        }
+
+        if let Some(reply_to) = entry.reply_to {
+            write!(self.writer, " (reply to `{reply_to}`)")?;
+        }
+
+        // Continuation lines are indented to stay in the list item
+        let text = escape_markdown(&plain_text(&entry.text)).replace('\n', "  \n  ");
+
+        if entry.kind == "event" {
+            writeln!(self.writer, ": *{text}*")
+        } else {
+            writeln!(self.writer, ": {text}")
+        }
+    }
+
+    fn write_json(&mut self, entry: &Entry) -> io::Result<()> {
+        let author = match &entry.author {
+            Author::User(user) => json!({
+                "username": user.username,
+                "nickname": user.nickname,
+                "badge": user.badge,
+                "role_color": user.role_color,
+            }),
+            Author::Name(name) => json!({ "username": name }),
+            Author::System => json!(null),
+        };
+
+        let line = json!({
+            "time": format_time(entry.time, JSON_TIME_FORMAT),
+            "unix_time": entry
+                .time
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |duration| duration.as_secs()),
+            "type": entry.kind,
+            "room": entry.room,
+            "id": entry.id,
+            "reply_to": entry.reply_to,
+            "author": author,
+            "recipient": entry.recipient,
+            "text": plain_text(&entry.text),
+        });
+
+        serde_json::to_writer(&mut self.writer, &line)?;
+        writeln!(self.writer)
+    }
+}
+
+/// Who an entry is from
+enum Author {
+    User(User),
+    /// Only the username is known
+    Name(String),
+    System,
+}
+
+/// A line of the transcript
+struct Entry {
+    time: SystemTime,
+    /// `message`, `update`, `attachment`, `direct_message`, `system`, `notification` or `event`
+    kind: &'static str,
+    room: Option<String>,
+    id: Option<MessageId>,
+    reply_to: Option<MessageId>,
+    author: Author,
+    recipient: Option<String>,
+    text: String,
+}
+
+impl Entry {
+    const fn new(time: SystemTime, kind: &'static str, author: Author, text: String) -> Self {
+        Self {
+            time,
+            kind,
+            room: None,
+            id: None,
+            reply_to: None,
+            author,
+            recipient: None,
+            text,
+        }
+    }
+
+    fn message(
+        time: SystemTime,
+        author: &User,
+        text: &str,
+        id: MessageId,
+        reply_to: Option<MessageId>,
+    ) -> Self {
+        Self {
+            id: Some(id),
+            reply_to,
+            ..Self::new(
+                time,
+                "message",
+                Author::User(author.clone()),
+                text.to_string(),
+            )
+        }
+    }
+
+    fn room(mut self, room: &str) -> Self {
+        self.room = Some(room.to_string());
+        self
+    }
+}
+
+/// Entries of a packet, empty for packets that are not part of a transcript
+fn entries(time: SystemTime, packet: &ClientPacket) -> Vec<Entry> {
+    let entry = match packet {
+        ClientPacket::SystemMessage { message } => {
+            Entry::new(time, "system", Author::System, message.clone())
+        }
+        ClientPacket::UserMessage {
+            author,
+            message,
+            room,
+            id,
+            reply_to,
+        } => Entry::message(time, author, message, *id, *reply_to).room(room),
+        ClientPacket::History { room, messages, .. } => {
+            return messages
+                .iter()
+                .map(|message| {
+                    Entry::message(
+                        time,
+                        &message.author,
+                        &message.message,
+                        message.id,
+                        message.reply_to,
+                    )
+                    .room(room)
+                })
+                .collect();
+        }
+        ClientPacket::MessageUpdate { room, update } => update_entry(time, update).room(room),
+        ClientPacket::Attachment {
+            author,
+            room,
+            attachment,
+        } => Entry::new(
+            time,
+            "attachment",
+            Author::User(author.clone()),
+            format!(
+                "[{}, {}, {} bytes, sha256 {}]",
+                attachment.name, attachment.mime_type, attachment.size, attachment.hash
+            ),
+        )
+        .room(room),
+        ClientPacket::DirectMessage { author, message } => Entry::new(
+            time,
+            "direct_message",
+            Author::User(author.clone()),
+            message.clone(),
+        ),
+        ClientPacket::EncryptedDirectMessage { author, .. } => Entry::new(
+            time,
+            "direct_message",
+            Author::User(author.clone()),
+            "[encrypted]".to_string(),
+        ),
+        ClientPacket::DirectMessageStatus { recipient, status } => Entry {
+            recipient: Some(recipient.clone()),
+            ..Entry::new(
+                time,
+                "event",
+                Author::System,
+                delivery_text(*status).to_string(),
+            )
+        },
+        ClientPacket::Notification { title, content, .. } => Entry::new(
+            time,
+            "notification",
+            Author::Name(title.clone()),
+            content.clone(),
+        ),
+        ClientPacket::ApiResponse {
+            response: StbchatApiResponse::UserJoined { username, room, .. },
+            ..
+        } => Entry::new(
+            time,
+            "event",
+            Author::Name(username.clone()),
+            "Joined the room".to_string(),
+        )
+        .room(room),
+        ClientPacket::ApiResponse {
+            response: StbchatApiResponse::UserLeft { username, room },
+            ..
+        } => Entry::new(
+            time,
+            "event",
+            Author::Name(username.clone()),
+            "Left the room".to_string(),
+        )
+        .room(room),
+        _ => return Vec::new(),
+    };
+
+    vec![entry]
+}
+
+fn update_entry(time: SystemTime, update: &MessageUpdate) -> Entry {
+    let (author, text) = match update {
+        MessageUpdate::Edited { id, message } => (
+            Author::System,
+            format!("Message {id} was edited: {message}"),
+        ),
+        MessageUpdate::Deleted { id } => (Author::System, format!("Message {id} was deleted")),
+        MessageUpdate::ReactionAdded {
+            id,
+            emoji,
+            username,
+        } => (
+            Author::Name(username.clone()),
+            format!("Reacted with {emoji} to message {id}"),
+        ),
+        MessageUpdate::ReactionRemoved {
+            id,
+            emoji,
+            username,
+        } => (
+            Author::Name(username.clone()),
+            format!("Removed the reaction {emoji} from message {id}"),
+        ),
+    };
+
+    Entry::new(time, "update", author, text)
+}
+
+const fn delivery_text(status: DeliveryStatus) -> &'static str {
+    match status {
+        DeliveryStatus::Delivered => "Direct message delivered",
+        DeliveryStatus::UserOffline => "Direct message not delivered, the user is offline",
+    }
+}
+
+/// Name of the author without any styling, e.g. `nick (@user) [dev]`
+fn plain_author(author: &Author) -> String {
+    match author {
+        Author::User(user) => user.format_name(str::to_string, |username| username),
+        Author::Name(name) => strip_control_characters(name),
+        Author::System => "System".to_string(),
+    }
+}
+
+/// Name of the author with the role color as CSS
+fn html_author(author: &Author) -> String {
+    let Author::User(user) = author else {
+        return escape_html(&plain_author(author));
+    };
+
+    let color = user
+        .color()
+        .filter(|color| *color != RoleColor::Named(RESET));
+
+    user.format_name(escape_html, |username| match color {
+        Some(color) => format!("<span style=\"color:{}\">{username}</span>", color.css()),
+        None => username,
+    })
+}
+
+/// Convert foreground colors of SGR sequences to HTML spans and remove all other escape sequences
+fn ansi_to_html(text: &str) -> String {
+    let mut html = String::with_capacity(text.len());
+    let mut open = 0;
+    let mut last = 0;
+
+    for escape in ANSI_ESCAPE.find_iter(text) {
+        html += &escape_html(&plain_text(&text[last..escape.start()]));
+        last = escape.end();
+
+        let Some(parameters) = SGR.captures(escape.as_str()).map(|sgr| sgr[1].to_string()) else {
+            continue;
+        };
+
+        if matches!(parameters.as_str(), "" | "0") {
+            html += &"</span>".repeat(open);
+            open = 0;
+            continue;
+        }
+
+        match RoleColor::parse(escape.as_str()) {
+            Some(RoleColor::Named(RESET)) => {
+                html += &"</span>".repeat(open);
+                open = 0;
+            }
+            Some(color) => {
+                html.push_str("<span style=\"color:");
+                html.push_str(&color.css());
+                html.push_str("\">");
+                open += 1;
+            }
+            None => {}
+        }
+    }
+
+    html += &escape_html(&plain_text(&text[last..]));
+    html += &"</span>".repeat(open);
+    html
+}
+
+/// Remove escape sequences and control characters except line breaks and tabs
+fn plain_text(text: &str) -> String {
+    escape_ansi(text)
+        .chars()
+        .filter(|c| !c.is_control() || matches!(c, '\n' | '\t'))
+        .collect()
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Escape characters with a meaning in Markdown, so messages are shown as they were sent
+fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        if matches!(
+            c,
+            '\\' | '`'
+                | '*'
+                | '_'
+                | '{'
+                | '}'
+                | '['
+                | ']'
+                | '<'
+                | '>'
+                | '('
+                | ')'
+                | '#'
+                | '+'
+                | '-'
+                | '.'
+                | '!'
+                | '|'
+                | '~'
+        ) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
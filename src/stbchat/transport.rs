@@ -0,0 +1,119 @@
+#![cfg(not(feature = "stbchat-sync"))]
+
+use std::io;
+use std::net::SocketAddr;
+
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, duplex};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc;
+
+/// Size of the in-memory buffer of each direction of a memory connection
+pub const DUPLEX_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Read half of a connection, independent of the underlying transport
+pub type BoxedReader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Write half of a connection, independent of the underlying transport
+pub type BoxedWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// # Source of incoming connections for the Strawberry Chat server
+/// Implemented for TCP listeners, Unix domain socket listeners and in-memory listeners
+pub trait Listener: Send + 'static {
+    type Stream: AsyncRead + AsyncWrite + Send + Unpin + 'static;
+
+    /// Wait for the next connection.
+    /// Returns the stream and the remote address, if the transport has one
+    /// # Errors
+    /// - Will return `Err` if accepting a connection fails
+    fn accept(
+        &mut self,
+    ) -> impl Future<Output = io::Result<(Self::Stream, Option<SocketAddr>)>> + Send;
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    async fn accept(&mut self) -> io::Result<(TcpStream, Option<SocketAddr>)> {
+        let (stream, addr) = Self::accept(self).await?;
+
+        Ok((stream, Some(addr)))
+    }
+}
+
+#[cfg(unix)]
+impl Listener for UnixListener {
+    type Stream = UnixStream;
+
+    async fn accept(&mut self) -> io::Result<(UnixStream, Option<SocketAddr>)> {
+        let (stream, _) = Self::accept(self).await?;
+
+        Ok((stream, None))
+    }
+}
+
+/// # In-memory transport
+/// Connects clients and a server within the same process without any sockets,
+/// intended for integration tests.
+/// ```no_run
+/// use libstrawberry::stbchat::client::StbchatClient;
+/// use libstrawberry::stbchat::server::StbchatServer;
+/// use libstrawberry::stbchat::transport;
+///
+/// # async fn example() -> eyre::Result<()> {
+/// let (connector, listener) = transport::memory();
+/// tokio::spawn(StbchatServer::new().serve_listener(listener));
+///
+/// let client = StbchatClient::from_stream(connector.connect()?);
+/// client.login("alice", "password").await?;
+/// # Ok(())
+/// # }
+/// ```
+#[must_use]
+pub fn memory() -> (MemoryConnector, MemoryListener) {
+    let (connections, incoming) = mpsc::unbounded_channel();
+
+    (MemoryConnector { connections }, MemoryListener { incoming })
+}
+
+/// Client side of the in-memory transport, can be cloned to connect from multiple places
+#[derive(Clone)]
+pub struct MemoryConnector {
+    connections: mpsc::UnboundedSender<DuplexStream>,
+}
+
+impl MemoryConnector {
+    /// Open a new connection to the memory listener
+    /// # Errors
+    /// - Will return `Err` if the listener was dropped
+    pub fn connect(&self) -> io::Result<DuplexStream> {
+        let (client, server) = duplex(DUPLEX_BUFFER_SIZE);
+
+        self.connections
+            .send(server)
+            .map_err(|_| io::Error::from(io::ErrorKind::ConnectionRefused))?;
+
+        Ok(client)
+    }
+}
+
+/// Server side of the in-memory transport
+pub struct MemoryListener {
+    incoming: mpsc::UnboundedReceiver<DuplexStream>,
+}
+
+impl Listener for MemoryListener {
+    type Stream = DuplexStream;
+
+    async fn accept(&mut self) -> io::Result<(DuplexStream, Option<SocketAddr>)> {
+        let stream = self.incoming.recv().await.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "all memory connectors were dropped",
+            )
+        })?;
+
+        Ok((stream, None))
+    }
+}
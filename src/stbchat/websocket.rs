@@ -0,0 +1,212 @@
+#![cfg(all(feature = "stbchat-websocket", not(feature = "stbchat-sync")))]
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+
+use futures_util::{Sink, Stream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::{WebSocketStream, accept_async_with_config, client_async_with_config};
+
+/// Every packet fits into a single frame, larger messages are rejected during reading
+const MAX_MESSAGE_SIZE: usize = u16::MAX as usize;
+
+/// Length of the frame header used by the packet streams
+const HEADER_LENGTH: usize = 2;
+
+fn config() -> WebSocketConfig {
+    WebSocketConfig::default()
+        .max_message_size(Some(MAX_MESSAGE_SIZE))
+        .max_frame_size(Some(MAX_MESSAGE_SIZE))
+}
+
+/// # WebSocket transport
+/// Carries the stbchat protocol over a WebSocket connection, so browsers can connect to a server.
+/// Every packet is sent as a single binary message containing the (possibly compressed)
+/// `MessagePack` body, without the length prefix used on raw TCP connections.
+///
+/// Implements `AsyncRead` and `AsyncWrite`, so it can be wrapped into the regular
+/// `IncomingPacketStream`/`OutgoingPacketStream` (or passed to `StbchatClient::from_stream`).
+/// Text, ping and pong messages are ignored, a close message is read as end of stream
+pub struct WebSocketTransport<S> {
+    socket: WebSocketStream<S>,
+    /// Frame of the last received message that was not fully read yet
+    read_buffer: Vec<u8>,
+    read_position: usize,
+    /// Written bytes that don't form a complete frame yet
+    write_buffer: Vec<u8>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WebSocketTransport<S> {
+    /// Wrap an established WebSocket connection
+    pub const fn wrap(socket: WebSocketStream<S>) -> Self {
+        Self {
+            socket,
+            read_buffer: Vec::new(),
+            read_position: 0,
+            write_buffer: Vec::new(),
+        }
+    }
+
+    /// Returns the wrapped WebSocket connection
+    pub fn unwrap(self) -> WebSocketStream<S> {
+        self.socket
+    }
+
+    /// Send all complete frames of the write buffer as binary messages
+    fn poll_send_frames(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while let Some(len) = complete_frame(&self.write_buffer) {
+            ready!(Pin::new(&mut self.socket).poll_ready(cx)).map_err(into_io)?;
+
+            let frame: Vec<u8> = self.write_buffer.drain(..HEADER_LENGTH + len).collect();
+            Pin::new(&mut self.socket)
+                .start_send(Message::binary(frame[HEADER_LENGTH..].to_vec()))
+                .map_err(into_io)?;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocketTransport<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        while this.read_position >= this.read_buffer.len() {
+            let message = match ready!(Pin::new(&mut this.socket).poll_next(cx)) {
+                Some(Ok(message)) => message,
+                Some(Err(
+                    tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed,
+                ))
+                | None => return Poll::Ready(Ok(())),
+                Some(Err(err)) => return Poll::Ready(Err(into_io(err))),
+            };
+
+            match message {
+                Message::Binary(data) => {
+                    let Ok(len) = u16::try_from(data.len()) else {
+                        return Poll::Ready(Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "WebSocket message exceeds the maximum packet size",
+                        )));
+                    };
+
+                    this.read_buffer.clear();
+                    this.read_buffer.extend_from_slice(&len.to_be_bytes());
+                    this.read_buffer.extend_from_slice(&data);
+                    this.read_position = 0;
+                }
+                Message::Close(_) => return Poll::Ready(Ok(())),
+                _ => {}
+            }
+        }
+
+        let remaining = &this.read_buffer[this.read_position..];
+        let len = remaining.len().min(buf.remaining());
+        buf.put_slice(&remaining[..len]);
+        this.read_position += len;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocketTransport<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        // Apply backpressure while a previous frame is still waiting to be sent
+        ready!(this.poll_send_frames(cx))?;
+        this.write_buffer.extend_from_slice(buf);
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        ready!(this.poll_send_frames(cx))?;
+        Pin::new(&mut this.socket).poll_flush(cx).map_err(into_io)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        ready!(this.poll_send_frames(cx))?;
+        Pin::new(&mut this.socket).poll_close(cx).map_err(into_io)
+    }
+}
+
+/// Perform the client-side WebSocket handshake on an established connection.
+/// The URL is only used for the handshake request, e.g. `ws://chat.example.com:52810/`
+/// # Errors
+/// - Will return `Err` if the URL is invalid
+/// - Will return `Err` if the handshake fails
+pub async fn connect<S: AsyncRead + AsyncWrite + Unpin>(
+    url: &str,
+    stream: S,
+) -> eyre::Result<WebSocketTransport<S>> {
+    let request = url.into_client_request()?;
+    let (socket, _) = client_async_with_config(request, stream, Some(config())).await?;
+
+    Ok(WebSocketTransport::wrap(socket))
+}
+
+/// Perform the server-side WebSocket handshake on an accepted connection
+/// # Errors
+/// - Will return `Err` if the handshake fails
+pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+) -> eyre::Result<WebSocketTransport<S>> {
+    let socket = accept_async_with_config(stream, Some(config())).await?;
+
+    Ok(WebSocketTransport::wrap(socket))
+}
+
+/// Returns the host and port of a `ws://` or `wss://` URL
+/// # Errors
+/// - Will return `Err` if the URL is invalid or has no host
+pub(crate) fn host_and_port(url: &str) -> eyre::Result<(String, u16)> {
+    let request = url.into_client_request()?;
+    let uri = request.uri();
+
+    let host = uri
+        .host()
+        .ok_or_else(|| eyre::eyre!("WebSocket URL '{url}' has no host"))?
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+    let default_port = if uri.scheme_str() == Some("wss") {
+        443
+    } else {
+        80
+    };
+    let port = uri.port_u16().unwrap_or(default_port);
+
+    Ok((host, port))
+}
+
+/// Returns the length of the first frame in the buffer if it is complete
+fn complete_frame(buffer: &[u8]) -> Option<usize> {
+    let header = buffer.get(..HEADER_LENGTH)?;
+    let len = u16::from_be_bytes([header[0], header[1]]) as usize;
+
+    (buffer.len() >= HEADER_LENGTH + len).then_some(len)
+}
+
+fn into_io(err: tungstenite::Error) -> io::Error {
+    match err {
+        tungstenite::Error::Io(err) => err,
+        err => io::Error::other(err),
+    }
+}
@@ -1,12 +1,37 @@
+use std::sync::LazyLock;
+
 use regex::Regex;
 
+/// Any ansi escape sequence
+pub(crate) static ANSI_ESCAPE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?:\x1B[@-_]|[\x80-\x9F])[0-?]*[ -/]*[@-~]").unwrap());
+
 /// Escape ansi characters from a string
 /// # Panics
 /// - Will panic when regex object cannot be created
 #[must_use]
 pub fn escape_ansi(string: &str) -> String {
-    let ansi_escape = Regex::new(r"(?:\x1B[@-_]|[\x80-\x9F])[0-?]*[ -/]*[@-~]").unwrap();
-    ansi_escape.replace_all(string, "").to_string()
+    ANSI_ESCAPE.replace_all(string, "").to_string()
+}
+
+/// Remove ansi escape sequences and other control characters from a string,
+/// e.g. before printing text of other users to the terminal
+#[must_use]
+pub fn strip_control_characters(string: &str) -> String {
+    escape_ansi(string)
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect()
+}
+
+/// Format a badge to be shown after a name, e.g. ` [dev]`. Returns `None` for an empty badge
+#[must_use]
+pub fn badge_handler(badge: &str) -> Option<String> {
+    if badge.is_empty() {
+        None
+    } else {
+        Some(format!(" [{badge}]"))
+    }
 }
 
 /// Check if a variable contains whitespaces and return a bool
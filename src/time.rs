@@ -31,6 +31,13 @@ pub fn current_time(format: &str) -> String {
     local.format(format).to_string()
 }
 
+/// Format a point in time in the local timezone with a given time format
+#[must_use]
+pub fn format_time(time: SystemTime, format: &str) -> String {
+    let local: DateTime<Local> = time.into();
+    local.format(format).to_string()
+}
+
 /// Get current unix epoch time
 /// # Panics
 /// - Will panic when clock may have gone backwards
@@ -0,0 +1,85 @@
+#![cfg(all(feature = "stbchat", not(feature = "stbchat-sync")))]
+
+use std::path::PathBuf;
+
+use libstrawberry::stbchat::attachment;
+use libstrawberry::stbchat::client::StbchatClient;
+use libstrawberry::stbchat::object::{Attachment, DEFAULT_ROOM};
+use libstrawberry::stbchat::packet::ClientPacket;
+use libstrawberry::stbchat::queue::QueueConfig;
+use libstrawberry::stbchat::server::StbchatServer;
+use tokio::net::TcpListener;
+
+/// Start a server with the default limits and log in a new user
+async fn connect(username: &str) -> StbchatClient {
+    connect_to(StbchatServer::new(), username).await
+}
+
+/// Start the given server and log in a new user
+async fn connect_to(server: StbchatServer, username: &str) -> StbchatClient {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(server.serve(listener));
+
+    let mut client = StbchatClient::connect(addr).await.unwrap();
+    client.register(username, "password123", "").await.unwrap();
+
+    while let Some(packet) = client.recv().await {
+        if matches!(packet, ClientPacket::Backend { .. }) {
+            break;
+        }
+    }
+
+    client
+}
+
+/// A file with pseudo-random content in the temp directory, removed on drop
+struct TempFile(PathBuf);
+
+impl TempFile {
+    fn new(name: &str, size: usize) -> Self {
+        let path = std::env::temp_dir().join(format!("{}-{name}", std::process::id()));
+        let content: Vec<u8> = (0..size).map(|i| (i * 31 % 251) as u8).collect();
+        std::fs::write(&path, content).unwrap();
+
+        Self(path)
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[tokio::test]
+async fn upload_larger_than_byte_burst() {
+    let client = connect("uploader").await;
+    // The default byte limit allows bursts of 256 KiB
+    let file = TempFile::new("upload.bin", 1024 * 1024);
+
+    client.send_file(DEFAULT_ROOM, &file.0).await.unwrap();
+}
+
+#[tokio::test]
+async fn download_larger_than_send_queue() {
+    // Far fewer packets fit into the queue than the file has chunks
+    let server = StbchatServer::new().send_queue(QueueConfig::new().capacity(4));
+    let client = connect_to(server, "downloader").await;
+    let file = TempFile::new("download.bin", 4 * 1024 * 1024);
+    let saved = TempFile(file.0.with_extension("saved"));
+
+    let id = client.send_file(DEFAULT_ROOM, &file.0).await.unwrap();
+    let data = std::fs::read(&file.0).unwrap();
+    let attachment = Attachment {
+        id,
+        name: "download.bin".to_string(),
+        size: data.len() as u64,
+        mime_type: String::new(),
+        hash: attachment::hash(&data),
+    };
+
+    client.save_attachment(&attachment, &saved.0).await.unwrap();
+
+    assert_eq!(std::fs::read(&saved.0).unwrap(), data);
+}
@@ -0,0 +1,65 @@
+#![cfg(all(feature = "stbchat", not(feature = "stbchat-sync")))]
+
+use std::net::SocketAddr;
+
+use libstrawberry::stbchat::client::StbchatClient;
+use libstrawberry::stbchat::object::DeliveryStatus;
+use libstrawberry::stbchat::packet::ClientPacket;
+use libstrawberry::stbchat::server::StbchatServer;
+use tokio::net::TcpListener;
+
+async fn start() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(StbchatServer::new().serve(listener));
+
+    addr
+}
+
+/// Register a new user and wait until the login completed
+async fn register(addr: SocketAddr, username: &str) -> StbchatClient {
+    let mut client = StbchatClient::connect(addr).await.unwrap();
+    client.register(username, "password123", "").await.unwrap();
+
+    while let Some(packet) = client.recv().await {
+        if matches!(packet, ClientPacket::Backend { .. }) {
+            break;
+        }
+    }
+
+    client
+}
+
+/// Wait for the first packet `find` returns a value for
+async fn wait_for<T>(client: &mut StbchatClient, find: impl Fn(ClientPacket) -> Option<T>) -> T {
+    while let Some(packet) = client.recv().await {
+        if let Some(value) = find(packet) {
+            return value;
+        }
+    }
+
+    panic!("connection closed");
+}
+
+#[tokio::test]
+async fn recipients_are_case_insensitive() {
+    let addr = start().await;
+    let mut alice = register(addr, "Alice").await;
+    let mut bob = register(addr, "bob").await;
+
+    bob.send_direct_message("alice", "hello").await.unwrap();
+
+    let status = wait_for(&mut bob, |packet| match packet {
+        ClientPacket::DirectMessageStatus { status, .. } => Some(status),
+        _ => None,
+    })
+    .await;
+    assert_eq!(status, DeliveryStatus::Delivered);
+
+    let message = wait_for(&mut alice, |packet| match packet {
+        ClientPacket::DirectMessage { message, .. } => Some(message),
+        _ => None,
+    })
+    .await;
+    assert_eq!(message, "hello");
+}
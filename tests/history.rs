@@ -0,0 +1,136 @@
+#![cfg(all(feature = "stbchat", not(feature = "stbchat-sync")))]
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use libstrawberry::stbchat::client::StbchatClient;
+use libstrawberry::stbchat::object::MessageUpdate;
+use libstrawberry::stbchat::packet::ClientPacket;
+use libstrawberry::stbchat::server::StbchatServer;
+use libstrawberry::stbchat::server::flood::FloodLimits;
+use libstrawberry::stbchat::server::history::FileHistoryStore;
+use tokio::net::TcpListener;
+
+async fn start(server: StbchatServer) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(server.serve(listener));
+
+    addr
+}
+
+/// Register a new user and wait until the login completed
+async fn register(addr: SocketAddr, username: &str) -> StbchatClient {
+    let mut client = StbchatClient::connect(addr).await.unwrap();
+    client.register(username, "password123", "").await.unwrap();
+
+    while let Some(packet) = client.recv().await {
+        if matches!(packet, ClientPacket::Backend { .. }) {
+            break;
+        }
+    }
+
+    client
+}
+
+/// Wait for the first packet `find` returns a value for
+async fn wait_for<T>(client: &mut StbchatClient, find: impl Fn(ClientPacket) -> Option<T>) -> T {
+    while let Some(packet) = client.recv().await {
+        if let Some(value) = find(packet) {
+            return value;
+        }
+    }
+
+    panic!("connection closed");
+}
+
+#[tokio::test]
+async fn only_authors_change_messages() {
+    let addr = start(StbchatServer::new()).await;
+    let mut alice = register(addr, "alice").await;
+    let mut bob = register(addr, "bob").await;
+
+    alice.send_message("hello").await.unwrap();
+    let id = wait_for(&mut alice, |packet| match packet {
+        ClientPacket::UserMessage { id, .. } => Some(id),
+        _ => None,
+    })
+    .await;
+
+    bob.edit_message(id, "hijacked").await.unwrap();
+    let message = wait_for(&mut bob, |packet| match packet {
+        ClientPacket::SystemMessage { message } if message.contains("change") => Some(message),
+        _ => None,
+    })
+    .await;
+    assert_eq!(message, "You can only change your own messages");
+
+    alice.edit_message(id, "edited").await.unwrap();
+    let update = wait_for(&mut alice, |packet| match packet {
+        ClientPacket::MessageUpdate { update, .. } => Some(update),
+        _ => None,
+    })
+    .await;
+    assert_eq!(
+        update,
+        MessageUpdate::Edited {
+            id,
+            message: "edited".to_string()
+        }
+    );
+}
+
+/// A path in the temp directory, removed on drop
+struct TempPath(PathBuf);
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[tokio::test]
+async fn messages_are_stored_in_id_order() {
+    const MESSAGES: usize = 50;
+
+    let path = TempPath(std::env::temp_dir().join(format!("{}-history.jsonl", std::process::id())));
+    let server = StbchatServer::new()
+        .flood_limits(FloodLimits::unlimited())
+        .history_store(FileHistoryStore::open(&path.0).unwrap());
+    let addr = start(server).await;
+    let mut alice = register(addr, "alice").await;
+    let bob = register(addr, "bob").await;
+
+    let send = |client: StbchatClient| {
+        tokio::spawn(async move {
+            for i in 0..MESSAGES {
+                client.send_message(i).await.unwrap();
+            }
+            client
+        })
+    };
+    let bob = send(bob);
+    let carol = send(register(addr, "carol").await);
+
+    let mut received = 0;
+    while received < 2 * MESSAGES {
+        wait_for(&mut alice, |packet| {
+            matches!(packet, ClientPacket::UserMessage { .. }).then_some(())
+        })
+        .await;
+        received += 1;
+    }
+    drop((bob.await.unwrap(), carol.await.unwrap()));
+
+    let ids: Vec<u64> = std::fs::read_to_string(&path.0)
+        .unwrap()
+        .lines()
+        .map(|line| {
+            let record: serde_json::Value = serde_json::from_str(line).unwrap();
+            record["message"]["id"].as_u64().unwrap()
+        })
+        .collect();
+
+    assert_eq!(ids.len(), 2 * MESSAGES);
+    assert!(ids.is_sorted());
+}
@@ -0,0 +1,98 @@
+#![cfg(all(
+    feature = "stbchat",
+    feature = "strawberryid",
+    not(feature = "stbchat-sync")
+))]
+
+use std::net::SocketAddr;
+
+use libstrawberry::id::StrawberryId;
+use libstrawberry::id::credentials::StrawberryIdCredentials;
+use libstrawberry::stbchat::client::StbchatClient;
+use libstrawberry::stbchat::object::AuthFailure;
+use libstrawberry::stbchat::packet::ClientPacket;
+use libstrawberry::stbchat::server::StbchatServer;
+use tokio::net::TcpListener;
+
+/// Start a server which accepts every token for the name it was sent with
+async fn start() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = StbchatServer::new().token_verifier(|credentials: &StrawberryIdCredentials| {
+        Ok(StrawberryId {
+            username: credentials.username.clone(),
+            ..Default::default()
+        })
+    });
+    tokio::spawn(server.serve(listener));
+
+    addr
+}
+
+/// Wait for the result of a login, `None` if it succeeded
+async fn auth_result(client: &mut StbchatClient) -> Option<AuthFailure> {
+    while let Some(packet) = client.recv().await {
+        match packet {
+            ClientPacket::Backend { .. } => return None,
+            ClientPacket::AuthFailed { reason, .. } => return Some(reason),
+            _ => {}
+        }
+    }
+
+    panic!("connection closed before logging in");
+}
+
+#[tokio::test]
+async fn token_login_with_unused_name() {
+    let addr = start().await;
+    let mut client = StbchatClient::connect(addr).await.unwrap();
+
+    client.login_token("bob", "token").await.unwrap();
+
+    assert_eq!(auth_result(&mut client).await, None);
+}
+
+#[tokio::test]
+async fn token_login_rejects_local_account_names() {
+    let addr = start().await;
+    let mut local = StbchatClient::connect(addr).await.unwrap();
+    local.register("alice", "password123", "").await.unwrap();
+    assert_eq!(auth_result(&mut local).await, None);
+
+    let mut client = StbchatClient::connect(addr).await.unwrap();
+    client.login_token("Alice", "token").await.unwrap();
+
+    assert_eq!(
+        auth_result(&mut client).await,
+        Some(AuthFailure::UsernameTaken)
+    );
+}
+
+#[tokio::test]
+async fn token_login_checks_username_rules() {
+    let addr = start().await;
+    let mut client = StbchatClient::connect(addr).await.unwrap();
+
+    client.login_token("server", "token").await.unwrap();
+
+    assert_eq!(
+        auth_result(&mut client).await,
+        Some(AuthFailure::InvalidUsername)
+    );
+}
+
+#[tokio::test]
+async fn register_rejects_names_of_online_users() {
+    let addr = start().await;
+    let mut id_user = StbchatClient::connect(addr).await.unwrap();
+    id_user.login_token("carol", "token").await.unwrap();
+    assert_eq!(auth_result(&mut id_user).await, None);
+
+    let mut client = StbchatClient::connect(addr).await.unwrap();
+    client.register("carol", "password123", "").await.unwrap();
+
+    assert_eq!(
+        auth_result(&mut client).await,
+        Some(AuthFailure::UsernameTaken)
+    );
+}